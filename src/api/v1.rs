@@ -1,10 +1,19 @@
 use std::collections::HashMap;
 
-use axum::{routing::post, Router};
-use serde::Deserialize;
+use axum::{
+    routing::{get, post},
+    Router,
+};
+use serde::{Deserialize, Serialize};
 use tracing::info;
 
-use crate::{http::AuthToken, AppState};
+use crate::{
+    handler::Takedown,
+    http::AuthToken,
+    report::MailReport,
+    store::{AttachmentReference, MailRecord},
+    AppState,
+};
 
 #[derive(Debug, Clone, Deserialize)]
 #[allow(dead_code)]
@@ -35,19 +44,101 @@ pub struct MailIngestionRequest {
     pub started_at: String, // FIXME: this should be deserialized to a time
 }
 
+#[derive(Debug, Clone, Serialize)]
+pub struct IngestionResult {
+    /// The reports of the ingested mails, in the order they were submitted.
+    pub mails: Vec<MailReport>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AttachmentQuery {
+    /// The Message-ID of the mail the attachments were uploaded from.
+    pub message_id: Option<String>,
+    /// The sender of the mail the attachments were uploaded from.
+    pub sender: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AttachmentList {
+    /// The keys of the matching attachments.
+    pub keys: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TakedownList {
+    /// The results of the attachment takedowns.
+    pub takedowns: Vec<Takedown>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReferenceList {
+    /// The mails that included the attachment.
+    pub references: Vec<AttachmentReference>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MailQuery {
+    /// Only include mails from this sender.
+    pub sender: Option<String>,
+    /// Only include mails dated at or after this RFC 3339 timestamp.
+    pub since: Option<String>,
+    /// Only include mails dated before this RFC 3339 timestamp.
+    pub until: Option<String>,
+    /// Only include mails with an attachment of this MIME type.
+    pub mime_type: Option<String>,
+    /// Only include mails whose subject contains this string.
+    pub subject: Option<String>,
+    /// The page to return, starting at 1.
+    pub page: Option<u32>,
+    /// The number of mails per page.
+    pub per_page: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MailList {
+    /// The matching mails on the requested page.
+    pub mails: Vec<MailRecord>,
+    /// The requested page.
+    pub page: u32,
+    /// The number of mails per page.
+    pub per_page: u32,
+    /// The total number of matching mails.
+    pub total: u64,
+}
+
+/// The default number of mails per page.
+const DEFAULT_PER_PAGE: u32 = 50;
+/// The maximum number of mails per page.
+const MAX_PER_PAGE: u32 = 200;
+
 pub fn router() -> Router<AppState> {
-    Router::new().route("/ingestion", post(handlers::ingest))
+    Router::new()
+        .route("/ingestion", post(handlers::ingest))
+        .route("/mails", get(handlers::find_mails))
+        .route(
+            "/attachments",
+            get(handlers::find_attachments).delete(handlers::delete_attachments),
+        )
+        .route(
+            "/attachments/{*key}",
+            get(handlers::attachment_references).delete(handlers::delete_attachment),
+        )
 }
 
 mod handlers {
-    use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+    use axum::{
+        extract::{Path, Query, State},
+        http::StatusCode,
+        response::IntoResponse,
+        Json,
+    };
     use base64::prelude::{Engine, BASE64_STANDARD};
     use mail_parser::MessageParser;
     use tracing::{debug, error};
 
     use super::*;
 
-    use crate::AppState;
+    use crate::{store::MailFilter, AppState, Error};
 
     #[tracing::instrument(skip_all)]
     pub(super) async fn ingest(
@@ -70,23 +161,263 @@ mod handlers {
             .with_address_headers()
             .with_message_ids();
 
+        let mut reports = vec![];
+
         for mail in &payload.mails {
             let Ok(decoded) = BASE64_STANDARD.decode(&mail.raw) else {
+                reports.push(MailReport::failed("could not decode email"));
+
                 continue;
             };
 
             match mail_parser.parse(&decoded[..]) {
                 Some(parsed) => {
                     let from = mail.metadata.from.as_deref();
+                    let to = mail.metadata.to.as_deref();
                     debug!(?from, ?parsed, "parsed mail");
-                    let _ = mail_handler.lock().await.handle(parsed, from).await;
+
+                    match mail_handler.lock().await.handle(parsed, from, to).await {
+                        Ok(report) => reports.push(report),
+                        Err(err) => {
+                            error!(%err, "could not handle email");
+
+                            reports.push(MailReport::failed(err.to_string()));
+                        }
+                    }
                 }
                 None => {
                     error!("could not parse email");
+
+                    reports.push(MailReport::failed("could not parse email"));
+                }
+            }
+        }
+
+        Json(IngestionResult { mails: reports }).into_response()
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub(super) async fn find_attachments(
+        AuthToken(token): AuthToken,
+        State(AppState {
+            api_token,
+            mail_handler,
+        }): State<AppState>,
+        Query(query): Query<AttachmentQuery>,
+    ) -> impl IntoResponse {
+        if token != api_token {
+            return (StatusCode::UNAUTHORIZED, "invalid api token").into_response();
+        }
+
+        if query.message_id.is_none() && query.sender.is_none() {
+            return (StatusCode::BAD_REQUEST, "message_id or sender is required").into_response();
+        }
+
+        match mail_handler
+            .lock()
+            .await
+            .find_attachments(query.message_id.as_deref(), query.sender.as_deref())
+        {
+            Ok(keys) => Json(AttachmentList { keys }).into_response(),
+            Err(err) => {
+                error!(%err, "could not look up attachments");
+
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "could not look up attachments",
+                )
+                    .into_response()
+            }
+        }
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub(super) async fn delete_attachments(
+        AuthToken(token): AuthToken,
+        State(AppState {
+            api_token,
+            mail_handler,
+        }): State<AppState>,
+        Query(query): Query<AttachmentQuery>,
+    ) -> impl IntoResponse {
+        if token != api_token {
+            return (StatusCode::UNAUTHORIZED, "invalid api token").into_response();
+        }
+
+        if query.message_id.is_none() && query.sender.is_none() {
+            return (StatusCode::BAD_REQUEST, "message_id or sender is required").into_response();
+        }
+
+        let mut mail_handler = mail_handler.lock().await;
+        let keys = match mail_handler
+            .find_attachments(query.message_id.as_deref(), query.sender.as_deref())
+        {
+            Ok(keys) => keys,
+            Err(err) => {
+                error!(%err, "could not look up attachments");
+
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "could not look up attachments",
+                )
+                    .into_response();
+            }
+        };
+
+        let mut takedowns = vec![];
+
+        // Only drop the references of the matching mails so attachments that were also sent in
+        // other mails stay available.
+        for key in &keys {
+            match mail_handler
+                .delete_attachment(key, query.message_id.as_deref(), query.sender.as_deref())
+                .await
+            {
+                Ok(takedown) => takedowns.push(takedown),
+                Err(err) => {
+                    error!(%err, %key, "could not delete attachment");
+
+                    return (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "could not delete attachment",
+                    )
+                        .into_response();
                 }
             }
         }
 
-        ().into_response()
+        Json(TakedownList { takedowns }).into_response()
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub(super) async fn delete_attachment(
+        AuthToken(token): AuthToken,
+        State(AppState {
+            api_token,
+            mail_handler,
+        }): State<AppState>,
+        Path(key): Path<String>,
+        Query(query): Query<AttachmentQuery>,
+    ) -> impl IntoResponse {
+        if token != api_token {
+            return (StatusCode::UNAUTHORIZED, "invalid api token").into_response();
+        }
+
+        match mail_handler
+            .lock()
+            .await
+            .delete_attachment(&key, query.message_id.as_deref(), query.sender.as_deref())
+            .await
+        {
+            Ok(takedown) => Json(takedown).into_response(),
+            Err(Error::AttachmentNotFound(_)) => {
+                (StatusCode::NOT_FOUND, "attachment not found").into_response()
+            }
+            Err(Error::InvalidAttachmentKey(_)) => {
+                (StatusCode::BAD_REQUEST, "not an attachment key").into_response()
+            }
+            Err(err) => {
+                error!(%err, %key, "could not delete attachment");
+
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "could not delete attachment",
+                )
+                    .into_response()
+            }
+        }
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub(super) async fn attachment_references(
+        AuthToken(token): AuthToken,
+        State(AppState {
+            api_token,
+            mail_handler,
+        }): State<AppState>,
+        Path(key): Path<String>,
+    ) -> impl IntoResponse {
+        if token != api_token {
+            return (StatusCode::UNAUTHORIZED, "invalid api token").into_response();
+        }
+
+        match mail_handler.lock().await.store.references(&key) {
+            Ok(references) if references.is_empty() => {
+                (StatusCode::NOT_FOUND, "attachment not found").into_response()
+            }
+            Ok(references) => Json(ReferenceList { references }).into_response(),
+            Err(err) => {
+                error!(%err, %key, "could not look up attachment references");
+
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "could not look up attachment references",
+                )
+                    .into_response()
+            }
+        }
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub(super) async fn find_mails(
+        AuthToken(token): AuthToken,
+        State(AppState {
+            api_token,
+            mail_handler,
+        }): State<AppState>,
+        Query(query): Query<MailQuery>,
+    ) -> impl IntoResponse {
+        if token != api_token {
+            return (StatusCode::UNAUTHORIZED, "invalid api token").into_response();
+        }
+
+        let parse_date = |value: Option<&str>| match value {
+            Some(value) => mail_parser::DateTime::parse_rfc3339(value)
+                .map(|x| Some(x.to_timestamp()))
+                .ok_or(()),
+            None => Ok(None),
+        };
+
+        let (Ok(since), Ok(until)) = (
+            parse_date(query.since.as_deref()),
+            parse_date(query.until.as_deref()),
+        ) else {
+            return (StatusCode::BAD_REQUEST, "invalid date, expected RFC 3339").into_response();
+        };
+
+        let filter = MailFilter {
+            sender: query.sender,
+            since,
+            until,
+            mime_type: query.mime_type,
+            subject: query.subject,
+            ..MailFilter::default()
+        };
+        let page = query.page.unwrap_or(1).max(1);
+        let per_page = query
+            .per_page
+            .unwrap_or(DEFAULT_PER_PAGE)
+            .clamp(1, MAX_PER_PAGE);
+        let offset = (page - 1).saturating_mul(per_page);
+
+        match mail_handler
+            .lock()
+            .await
+            .store
+            .find_mails(&filter, per_page, offset)
+        {
+            Ok((mails, total)) => Json(MailList {
+                mails,
+                page,
+                per_page,
+                total,
+            })
+            .into_response(),
+            Err(err) => {
+                error!(%err, "could not look up mails");
+
+                (StatusCode::INTERNAL_SERVER_ERROR, "could not look up mails").into_response()
+            }
+        }
     }
 }
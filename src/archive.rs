@@ -0,0 +1,416 @@
+use std::{
+    io::{Cursor, Read},
+    path::{Component, Path},
+};
+
+use flate2::read::GzDecoder;
+use zip::ZipArchive;
+
+use crate::{config::ArchiveConfig, Error};
+
+/// A file extracted from an archive.
+#[derive(Debug)]
+pub struct Member {
+    /// The path of the file within the archive, with nested archives as directories.
+    pub path: String,
+    /// The contents of the file.
+    pub contents: Vec<u8>,
+}
+
+/// Returns whether the file with the given `contents` and `mime_type` is an archive that can be
+/// expanded.
+///
+/// Office documents are ZIP packages as well, but they are documents rather than archives.
+pub fn expandable(mime_type: &str, contents: &[u8]) -> bool {
+    match mime_type {
+        "application/zip" => ZipArchive::new(Cursor::new(contents)).is_ok_and(|x| {
+            x.index_for_name("[Content_Types].xml").is_none()
+                && x.index_for_name("mimetype").is_none()
+        }),
+        "application/x-tar"
+        | "application/gzip"
+        | "application/x-gzip"
+        | "application/x-compressed-tar" => true,
+        _ => false,
+    }
+}
+
+/// Expands the archive `name` with the given `contents` and `mime_type` into its files.
+///
+/// Fails if the archive is malformed or exceeds any of the limits of `config`.
+pub fn expand(
+    name: &str,
+    contents: &[u8],
+    mime_type: &str,
+    config: &ArchiveConfig,
+) -> Result<Vec<Member>, Error> {
+    let ratio_limit = (contents.len() as u64).saturating_mul(config.max_ratio);
+    let mut expander = Expander {
+        config,
+        entries: 0,
+        size: 0,
+        max_size: config.max_size.min(ratio_limit),
+        members: vec![],
+    };
+
+    expander.expand(name, contents, mime_type, "", 0)?;
+
+    Ok(expander.members)
+}
+
+/// Returns the error for an archive that can't be expanded.
+fn rejected(reason: impl Into<String>) -> Error {
+    Error::ArchiveRejected(reason.into())
+}
+
+/// Returns the normalized form of the member `path`, or `None` if it is absolute or escapes the
+/// archive.
+fn safe_path(path: &Path) -> Option<String> {
+    let mut parts = vec![];
+
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_str()?),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+
+    (!parts.is_empty()).then(|| parts.join("/"))
+}
+
+/// Reads all of `reader`, failing if it yields more than `limit` bytes.
+fn read_limited(reader: impl Read, limit: u64) -> Result<Vec<u8>, Error> {
+    let mut buf = vec![];
+
+    reader
+        .take(limit.saturating_add(1))
+        .read_to_end(&mut buf)
+        .map_err(|e| rejected(format!("could not read archive: {e}")))?;
+
+    if buf.len() as u64 > limit {
+        return Err(rejected(format!(
+            "archive expands to more than {limit} bytes"
+        )));
+    }
+
+    Ok(buf)
+}
+
+/// Expands archives while keeping track of the limits across nested archives.
+struct Expander<'a> {
+    /// The configured limits.
+    config: &'a ArchiveConfig,
+    /// The number of entries seen so far.
+    entries: usize,
+    /// The number of bytes extracted so far.
+    size: u64,
+    /// The maximum number of bytes to extract.
+    max_size: u64,
+    /// The files extracted so far.
+    members: Vec<Member>,
+}
+
+impl Expander<'_> {
+    /// Expands the archive `name` at the given nesting `depth`, prefixing its members with
+    /// `prefix`.
+    fn expand(
+        &mut self,
+        name: &str,
+        contents: &[u8],
+        mime_type: &str,
+        prefix: &str,
+        depth: usize,
+    ) -> Result<(), Error> {
+        let files = match mime_type {
+            "application/zip" => self.zip(contents)?,
+            "application/x-tar" => self.tar(contents)?,
+            _ => self.gzip(name, contents)?,
+        };
+
+        for (path, contents) in files {
+            let path = format!("{prefix}{path}");
+            let mime_type = tree_magic_mini::from_u8(&contents);
+
+            // Archives nested too deeply are kept as they are.
+            if depth + 1 < self.config.max_depth && expandable(mime_type, &contents) {
+                self.expand(&path, &contents, mime_type, &format!("{path}/"), depth + 1)?;
+            } else {
+                self.members.push(Member { path, contents });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Counts an entry of an archive against the limit.
+    fn entry(&mut self) -> Result<(), Error> {
+        self.entries += 1;
+
+        if self.entries > self.config.max_entries {
+            return Err(rejected(format!(
+                "archive has more than {} entries",
+                self.config.max_entries
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Reads the contents of an entry, counting them against the limit.
+    fn read(&mut self, reader: impl Read) -> Result<Vec<u8>, Error> {
+        let contents = read_limited(reader, self.max_size - self.size)?;
+        self.size += contents.len() as u64;
+
+        Ok(contents)
+    }
+
+    fn zip(&mut self, contents: &[u8]) -> Result<Vec<(String, Vec<u8>)>, Error> {
+        let invalid = |e| rejected(format!("invalid ZIP archive: {e}"));
+        let mut archive = ZipArchive::new(Cursor::new(contents)).map_err(invalid)?;
+        let mut files = vec![];
+
+        for index in 0..archive.len() {
+            self.entry()?;
+
+            let entry = archive.by_index(index).map_err(invalid)?;
+            let Some(path) = safe_path(Path::new(entry.name())) else {
+                return Err(rejected(format!(
+                    "member `{}' escapes the archive",
+                    entry.name()
+                )));
+            };
+
+            if !entry.is_file() {
+                continue;
+            }
+
+            files.push((path, self.read(entry)?));
+        }
+
+        Ok(files)
+    }
+
+    fn tar(&mut self, contents: &[u8]) -> Result<Vec<(String, Vec<u8>)>, Error> {
+        let invalid = |e| rejected(format!("invalid tar archive: {e}"));
+        let mut archive = tar::Archive::new(contents);
+        let mut files = vec![];
+
+        for entry in archive.entries().map_err(invalid)? {
+            self.entry()?;
+
+            let entry = entry.map_err(invalid)?;
+            let entry_path = entry.path().map_err(invalid)?.into_owned();
+            let Some(path) = safe_path(&entry_path) else {
+                return Err(rejected(format!(
+                    "member `{}' escapes the archive",
+                    entry_path.display()
+                )));
+            };
+
+            // Links and special files are skipped, as they have no contents of their own.
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+
+            files.push((path, self.read(entry)?));
+        }
+
+        Ok(files)
+    }
+
+    /// Decompresses a gzip file, which is either a tar archive or a single compressed file.
+    fn gzip(&mut self, name: &str, contents: &[u8]) -> Result<Vec<(String, Vec<u8>)>, Error> {
+        let decoder = GzDecoder::new(contents);
+        let inner_name = decoder
+            .header()
+            .and_then(|x| x.filename())
+            .and_then(|x| std::str::from_utf8(x).ok())
+            .and_then(|x| safe_path(Path::new(x)));
+        let decompressed = read_limited(decoder, self.max_size - self.size)?;
+
+        if tree_magic_mini::match_u8("application/x-tar", &decompressed) {
+            return self.tar(&decompressed);
+        }
+
+        self.entry()?;
+
+        let name = inner_name.unwrap_or_else(|| {
+            let name = name.rsplit('/').next().unwrap_or(name);
+
+            name.strip_suffix(".gz").unwrap_or(name).to_string()
+        });
+        self.size += decompressed.len() as u64;
+
+        Ok(vec![(name, decompressed)])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+    use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+    use super::*;
+
+    fn config() -> ArchiveConfig {
+        ArchiveConfig {
+            enabled: true,
+            ..ArchiveConfig::default()
+        }
+    }
+
+    /// Returns a ZIP archive with the given entries, compressed with deflate.
+    fn zip(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(vec![]));
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+        for (name, contents) in entries {
+            writer.start_file(*name, options).unwrap();
+            writer.write_all(contents).unwrap();
+        }
+
+        writer.finish().unwrap().into_inner()
+    }
+
+    /// Returns a tar archive with the given entries.
+    fn tar(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(vec![]);
+
+        for (name, contents) in entries {
+            let mut header = tar::Header::new_gnu();
+
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, *contents).unwrap();
+        }
+
+        builder.into_inner().unwrap()
+    }
+
+    fn gzip(contents: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+
+        encoder.write_all(contents).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn paths(members: &[Member]) -> Vec<&str> {
+        members.iter().map(|x| x.path.as_str()).collect()
+    }
+
+    #[test]
+    fn normalizes_safe_paths() {
+        assert_eq!(safe_path(Path::new("a/b.txt")).as_deref(), Some("a/b.txt"));
+        assert_eq!(
+            safe_path(Path::new("./a/./b.txt")).as_deref(),
+            Some("a/b.txt")
+        );
+        assert_eq!(safe_path(Path::new("a/")).as_deref(), Some("a"));
+        assert_eq!(safe_path(Path::new("../b.txt")), None);
+        assert_eq!(safe_path(Path::new("a/../../b.txt")), None);
+        assert_eq!(safe_path(Path::new("/etc/passwd")), None);
+        assert_eq!(safe_path(Path::new(".")), None);
+        assert_eq!(safe_path(Path::new("")), None);
+    }
+
+    #[test]
+    fn expands_zip_archives() {
+        let contents = zip(&[("a.txt", b"hello"), ("dir/b.txt", b"world")]);
+        let members = expand("x.zip", &contents, "application/zip", &config()).unwrap();
+
+        assert_eq!(paths(&members), ["a.txt", "dir/b.txt"]);
+        assert_eq!(members[1].contents, b"world");
+    }
+
+    #[test]
+    fn expands_tar_archives() {
+        let contents = gzip(&tar(&[("a.txt", b"hello")]));
+        let members = expand("x.tar.gz", &contents, "application/gzip", &config()).unwrap();
+
+        assert_eq!(paths(&members), ["a.txt"]);
+    }
+
+    #[test]
+    fn decompresses_single_files() {
+        let contents = gzip(b"hello");
+        let members = expand("dir/a.txt.gz", &contents, "application/gzip", &config()).unwrap();
+
+        assert_eq!(paths(&members), ["a.txt"]);
+        assert_eq!(members[0].contents, b"hello");
+    }
+
+    #[test]
+    fn expands_nested_archives_up_to_max_depth() {
+        let inner = zip(&[("b.txt", b"world")]);
+        let contents = zip(&[("a.txt", b"hello"), ("inner.zip", &inner)]);
+        let members = expand("x.zip", &contents, "application/zip", &config()).unwrap();
+
+        assert_eq!(paths(&members), ["a.txt", "inner.zip/b.txt"]);
+
+        let config = ArchiveConfig {
+            max_depth: 1,
+            ..config()
+        };
+        let members = expand("x.zip", &contents, "application/zip", &config).unwrap();
+
+        assert_eq!(paths(&members), ["a.txt", "inner.zip"]);
+    }
+
+    #[test]
+    fn rejects_escaping_members() {
+        // The builder refuses to write such paths, so the header is written by hand.
+        let mut header = tar::Header::new_gnu();
+        let mut builder = tar::Builder::new(vec![]);
+
+        header.as_mut_bytes()[..10].copy_from_slice(b"../a.txt\0\0");
+        header.set_size(5);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append(&header, &b"hello"[..]).unwrap();
+
+        let contents = builder.into_inner().unwrap();
+        let result = expand("x.tar", &contents, "application/x-tar", &config());
+
+        assert!(matches!(result, Err(Error::ArchiveRejected(x)) if x.contains("escapes")));
+    }
+
+    #[test]
+    fn rejects_too_many_entries() {
+        let contents = zip(&[("a.txt", b"a"), ("b.txt", b"b"), ("c.txt", b"c")]);
+        let config = ArchiveConfig {
+            max_entries: 2,
+            ..config()
+        };
+
+        assert!(matches!(
+            expand("x.zip", &contents, "application/zip", &config),
+            Err(Error::ArchiveRejected(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_archive_bombs() {
+        let contents = zip(&[("zeros", &[0; 1024 * 1024])]);
+
+        assert!(matches!(
+            expand("x.zip", &contents, "application/zip", &config()),
+            Err(Error::ArchiveRejected(_))
+        ));
+    }
+
+    #[test]
+    fn office_documents_are_not_expandable() {
+        assert!(expandable("application/zip", &zip(&[("a.txt", b"a")])));
+        assert!(!expandable(
+            "application/zip",
+            &zip(&[("[Content_Types].xml", b"<Types/>")])
+        ));
+        assert!(!expandable("application/zip", b"not a zip"));
+        assert!(expandable("application/x-tar", &tar(&[])));
+        assert!(!expandable("image/png", b""));
+    }
+}
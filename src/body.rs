@@ -0,0 +1,129 @@
+use mail_parser::Message;
+
+/// The body of a mail, prepared for storage.
+#[derive(Debug, Clone)]
+pub struct Body {
+    /// The contents of the stored object.
+    pub contents: String,
+    /// The MIME type of the stored object.
+    pub mime_type: &'static str,
+    /// The filename of the stored object.
+    pub filename: &'static str,
+    /// The beginning of the body as plain text, for notifications.
+    pub excerpt: String,
+}
+
+/// Returns the body of `mail` with an excerpt of at most `excerpt_length` characters, or `None`
+/// if the mail has no body.
+///
+/// HTML bodies are sanitized and stripped of their images, and plain text is used when the mail
+/// has no HTML body.
+pub fn extract(mail: &Message<'_>, excerpt_length: usize) -> Option<Body> {
+    let text = mail.body_text(0);
+    let excerpt = excerpt(text.as_deref().unwrap_or_default(), excerpt_length);
+
+    // `body_html` converts plain text bodies to HTML, which there's no point in storing.
+    if mail.html_part(0).is_some_and(|x| x.is_text_html()) {
+        let html = mail.body_html(0)?;
+        let contents = format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n</head>\n<body>\n{}\n</body>\n</html>\n",
+            sanitize(&html)
+        );
+
+        return Some(Body {
+            contents,
+            mime_type: "text/html",
+            filename: "body.html",
+            excerpt,
+        });
+    }
+
+    let text = text?;
+
+    if text.trim().is_empty() {
+        return None;
+    }
+
+    Some(Body {
+        contents: text.into_owned(),
+        mime_type: "text/plain; charset=utf-8",
+        filename: "body.txt",
+        excerpt,
+    })
+}
+
+/// Returns the sanitized form of the HTML body `html`, without the sources of images.
+///
+/// Remote images would tell the sender when and from where the stored body is viewed, so only
+/// their alternative text is kept.
+fn sanitize(html: &str) -> String {
+    ammonia::Builder::default()
+        .attribute_filter(|element, attribute, value| match (element, attribute) {
+            ("img", "src" | "srcset") => None,
+            _ => Some(value.into()),
+        })
+        .clean(html)
+        .to_string()
+}
+
+/// Returns the beginning of `text` with collapsed whitespace, truncated to `length` characters.
+fn excerpt(text: &str, length: usize) -> String {
+    let collapsed = text.split_whitespace().collect::<Vec<_>>().join(" ");
+
+    match collapsed.char_indices().nth(length) {
+        Some((idx, _)) => format!("{}…", collapsed[..idx].trim_end()),
+        None => collapsed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mail_parser::MessageParser;
+
+    use super::*;
+
+    fn parse(raw: &str) -> Message<'_> {
+        MessageParser::default().parse(raw.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn excerpts_collapse_whitespace() {
+        assert_eq!(excerpt("  hello\n\n  world\t ", 20), "hello world");
+        assert_eq!(excerpt("hello world", 5), "hello…");
+        assert_eq!(excerpt("hello world", 6), "hello…");
+        assert_eq!(excerpt("héllo wörld", 4), "héll…");
+        assert_eq!(excerpt("", 5), "");
+    }
+
+    #[test]
+    fn sanitizes_html_bodies() {
+        let mail = parse(
+            "Content-Type: text/html\r\n\r\n\
+             <p onclick=\"x()\">Hi <script>alert(1)</script>\
+             <img src=\"https://tracker.example.com/pixel.gif\" alt=\"logo\">\
+             <a href=\"https://example.com/\">link</a></p>",
+        );
+        let body = extract(&mail, 100).unwrap();
+
+        assert_eq!(body.mime_type, "text/html");
+        assert!(body.contents.contains("<img alt=\"logo\">"));
+        assert!(body.contents.contains("href=\"https://example.com/\""));
+        assert!(!body.contents.contains("tracker"));
+        assert!(!body.contents.contains("script"));
+        assert!(!body.contents.contains("onclick"));
+    }
+
+    #[test]
+    fn stores_plain_text_bodies_as_is() {
+        let body = extract(&parse("Subject: x\r\n\r\nhello <b>world</b>\r\n"), 100).unwrap();
+
+        assert_eq!(body.mime_type, "text/plain; charset=utf-8");
+        assert_eq!(body.contents.trim_end(), "hello <b>world</b>");
+        assert_eq!(body.excerpt, "hello <b>world</b>");
+    }
+
+    #[test]
+    fn skips_empty_bodies() {
+        assert!(extract(&parse("Subject: x\r\n\r\n \r\n"), 100).is_none());
+    }
+}
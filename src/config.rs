@@ -1,3 +1,5 @@
+use std::{collections::HashMap, path::PathBuf};
+
 use serde::{Deserialize, Serialize};
 use url::Url;
 
@@ -11,12 +13,60 @@ pub struct Config {
     pub aws: AwsConfig,
     /// Meta webhook configuration
     pub meta_webhook: MetaWebhookConfig,
+    /// Store configuration
+    #[serde(default)]
+    pub store: StoreConfig,
+    /// Gallery configuration
+    #[serde(default)]
+    pub gallery: GalleryConfig,
+    /// Post-processing pipeline, in order. The default pipeline is used when empty.
+    #[serde(default)]
+    pub postprocess: Vec<PostProcessConfig>,
+    /// Antivirus scanning configuration
+    #[serde(default)]
+    pub scan: ScanConfig,
+    /// Limits on the attachments that are accepted
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    /// Archive expansion configuration
+    #[serde(default)]
+    pub archives: ArchiveConfig,
+    /// Configuration of which parts of a mail are processed as attachments
+    #[serde(default)]
+    pub parts: PartsConfig,
+    /// Mail body storage configuration
+    #[serde(default)]
+    pub bodies: BodiesConfig,
+    /// Link extraction configuration
+    #[serde(default)]
+    pub links: LinksConfig,
+    /// Chat notification configuration
+    #[serde(default)]
+    pub notifications: NotificationsConfig,
+    /// Outbound webhooks that receive an event for every ingested attachment
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+    /// Chat services notifications are sent to, besides the meta webhook
+    #[serde(default)]
+    pub notifiers: Vec<NotifierConfig>,
+    /// Configuration of the client requests to other services are sent with
+    #[serde(default)]
+    pub http: HttpConfig,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
 pub struct MetaWebhookConfig {
+    /// Send notifications through the meta webhook
+    #[serde(default = "default_true")]
+    pub enabled: bool,
     /// The bearer token.
     pub token: String,
+    /// The format of the notifications sent through the webhook
+    #[serde(default)]
+    pub format: NotificationFormat,
+    /// Templates overriding the default ones of `format`, by kind of notification
+    #[serde(default)]
+    pub templates: HashMap<String, String>,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
@@ -33,10 +83,24 @@ pub struct AwsS3Config {
     pub public_url: Option<Url>,
 }
 
+impl AwsS3Config {
+    /// Returns the public URL of the object with the given `key`, if a public URL is configured.
+    pub fn object_url(&self, key: &str) -> Option<Url> {
+        self.public_url.as_ref().and_then(|x| x.join(key).ok())
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct IngestionConfig {
     /// The API token for e-mail ingestion
     pub api_token: String,
+    /// Reject attachments of the `sanitized_mime_types` that no metadata-sanitizing
+    /// post-processor ran on
+    #[serde(default = "default_true")]
+    pub reject_unsanitized: bool,
+    /// Glob patterns of the MIME types that carry metadata and have to be sanitized
+    #[serde(default = "default_sanitized_mime_types")]
+    pub sanitized_mime_types: Vec<String>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -44,3 +108,533 @@ pub struct TracingConfig {
     /// Enable tracing
     pub enabled: bool,
 }
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct StoreConfig {
+    /// The path to the embedded database file
+    pub path: PathBuf,
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        StoreConfig {
+            path: PathBuf::from("meta-mail-ingress.db"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HttpConfig {
+    /// The maximum duration of establishing a connection, in seconds
+    pub connect_timeout: u64,
+    /// The maximum duration of a request, from connecting to reading the response, in seconds
+    pub timeout: u64,
+    /// The user agent requests are sent with
+    pub user_agent: String,
+    /// The proxy requests are sent through; the `HTTP_PROXY` and `HTTPS_PROXY` environment
+    /// variables are used when unset
+    pub proxy: Option<Url>,
+    /// The number of times requests are retried on server errors and connection failures, and
+    /// idempotent ones on timeouts
+    pub max_retries: u32,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            connect_timeout: 10,
+            timeout: 30,
+            user_agent: concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")).to_string(),
+            proxy: None,
+            max_retries: 3,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct GalleryConfig {
+    /// Serve the web gallery of recent uploads
+    pub enabled: bool,
+    /// The path to the gallery's static assets
+    pub assets_path: PathBuf,
+    /// The number of mails to show per page
+    pub per_page: u32,
+}
+
+impl Default for GalleryConfig {
+    fn default() -> Self {
+        GalleryConfig {
+            enabled: false,
+            assets_path: PathBuf::from("assets"),
+            per_page: 25,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PostProcessConfig {
+    /// The name of the post-processor, either a built-in one or a name for `command`
+    pub name: String,
+    /// Run the post-processor
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Glob patterns of the MIME types to run on, overriding the post-processor's own
+    pub mime_types: Option<Vec<String>>,
+    /// The external command to run, making this a generic command post-processor
+    pub command: Option<String>,
+    /// The arguments of `command`, where `{input}` and `{output}` are replaced by file paths
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// The maximum duration of a single run, in seconds
+    pub timeout: Option<u64>,
+    /// What to do when the post-processor fails, overriding the post-processor's own
+    pub policy: Option<Policy>,
+    /// The format `transcode` converts images to
+    pub format: Option<ImageFormat>,
+    /// The JPEG quality `transcode` encodes images with; WebP is always lossless
+    pub quality: Option<u8>,
+    /// The maximum width and height of images converted by `transcode`, in pixels
+    pub max_dimension: Option<u32>,
+    /// The minimum size of PNGs that `transcode` converts, in bytes; PNGs are left alone if unset
+    pub min_png_size: Option<u64>,
+    /// Whether `transcode` keeps the unconverted original, without its metadata, as a derived
+    /// object
+    #[serde(default)]
+    pub keep_original: bool,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /// Glob patterns of the MIME types of attachments to accept; all are accepted when empty
+    pub allowed_mime_types: Vec<String>,
+    /// Glob patterns of the MIME types of attachments to skip, taking precedence over the
+    /// allowed ones
+    pub denied_mime_types: Vec<String>,
+    /// The maximum size of a single attachment, in bytes
+    pub max_attachment_size: Option<u64>,
+    /// The maximum number of attachments processed per mail
+    pub max_attachments: Option<usize>,
+    /// The maximum total size of the attachments processed per mail, in bytes
+    pub max_total_size: Option<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ScanConfig {
+    /// Scan attachments with clamd before uploading them
+    pub enabled: bool,
+    /// The address of clamd, either `tcp://host:port` or `unix:/path/to/clamd.sock`
+    pub address: String,
+    /// What to do with attachments malware was detected in
+    pub action: ScanAction,
+    /// What to do with attachments that could not be scanned
+    pub policy: Policy,
+    /// The private bucket infected attachments are quarantined in, required by the `quarantine`
+    /// action
+    pub quarantine_bucket: Option<String>,
+    /// The key prefix infected attachments are quarantined under
+    pub quarantine_prefix: String,
+    /// The maximum duration of a single scan, in seconds
+    pub timeout: u64,
+}
+
+impl Default for ScanConfig {
+    fn default() -> Self {
+        ScanConfig {
+            enabled: false,
+            address: "tcp://127.0.0.1:3310".to_string(),
+            action: ScanAction::Reject,
+            policy: Policy::Required,
+            quarantine_bucket: None,
+            quarantine_prefix: "~meta/quarantine/".to_string(),
+            timeout: 30,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PartsConfig {
+    /// Process inline parts, such as images referenced by HTML bodies, as attachments
+    pub inline: bool,
+    /// Extract the attachments of forwarded messages instead of storing the messages themselves
+    pub forwarded: bool,
+    /// The maximum nesting depth of forwarded messages whose attachments are extracted
+    pub max_depth: usize,
+    /// The minimum size of inline images, in bytes; smaller ones, like signature logos, are skipped
+    pub min_inline_size: Option<u64>,
+    /// The minimum width and height of inline images, in pixels; smaller ones are skipped
+    pub min_inline_dimension: Option<u32>,
+}
+
+impl Default for PartsConfig {
+    fn default() -> Self {
+        PartsConfig {
+            inline: true,
+            forwarded: false,
+            max_depth: 3,
+            min_inline_size: None,
+            min_inline_dimension: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct BodiesConfig {
+    /// Store the bodies of mails as objects, with sanitized HTML or plain text
+    pub enabled: bool,
+    /// The recipient addresses to store the bodies of mails for; all when empty
+    pub routes: Vec<String>,
+    /// The maximum length of the excerpt of a body in notifications, in characters
+    pub excerpt_length: usize,
+}
+
+impl BodiesConfig {
+    /// Returns whether the bodies of mails sent to `recipient` are stored.
+    pub fn applies_to(&self, recipient: Option<&str>) -> bool {
+        self.enabled
+            && (self.routes.is_empty()
+                || recipient.is_some_and(|x| self.routes.iter().any(|y| y.eq_ignore_ascii_case(x))))
+    }
+}
+
+impl Default for BodiesConfig {
+    fn default() -> Self {
+        BodiesConfig {
+            enabled: false,
+            routes: vec![],
+            excerpt_length: 200,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LinksConfig {
+    /// Extract the URLs from the bodies of mails and send notifications about them
+    pub enabled: bool,
+    /// Domains whose URLs are ignored, including their subdomains
+    pub denied_domains: Vec<String>,
+    /// The maximum number of URLs notified about per mail
+    pub max_links: usize,
+}
+
+impl Default for LinksConfig {
+    fn default() -> Self {
+        LinksConfig {
+            enabled: false,
+            denied_domains: vec![],
+            max_links: 10,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct NotificationsConfig {
+    /// The number of stored attachments from which a mail is notified about with a single
+    /// summary instead of a message per attachment; 0 never summarizes
+    pub batch_threshold: usize,
+    /// The maximum number of attachment links in a summary
+    pub max_links: usize,
+    /// The URL linked in summaries for the attachments that aren't listed, such as the gallery
+    pub summary_url: Option<Url>,
+    /// The maximum length of a chat message line, in bytes; longer messages are split
+    pub max_line_length: usize,
+}
+
+impl Default for NotificationsConfig {
+    fn default() -> Self {
+        NotificationsConfig {
+            batch_threshold: 3,
+            max_links: 5,
+            summary_url: None,
+            max_line_length: 400,
+        }
+    }
+}
+
+/// A chat service notifications are sent to.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NotifierConfig {
+    /// A Matrix room, through the client-server API.
+    Matrix(MatrixConfig),
+    /// A Slack channel, through an incoming webhook.
+    Slack(SlackConfig),
+    /// A Discord channel, through a webhook.
+    Discord(DiscordConfig),
+    /// IRC channels, through a persistent connection to the server.
+    Irc(IrcConfig),
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MatrixConfig {
+    /// The base URL of the homeserver
+    pub homeserver: Url,
+    /// The access token of the account notifications are sent from
+    pub access_token: String,
+    /// The id of the room notifications are sent to, e.g. `!abc:example.org`
+    pub room_id: String,
+    /// Upload the thumbnails of images to the media repository and send them along
+    #[serde(default)]
+    pub upload_thumbnails: bool,
+    /// The format of the notifications, plain text by default
+    pub format: Option<NotificationFormat>,
+    /// Templates overriding the default ones of `format`, by kind of notification
+    #[serde(default)]
+    pub templates: HashMap<String, String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SlackConfig {
+    /// The URL of the incoming webhook
+    pub webhook_url: Url,
+    /// The format of the notifications, plain text by default
+    pub format: Option<NotificationFormat>,
+    /// Templates overriding the default ones of `format`, by kind of notification
+    #[serde(default)]
+    pub templates: HashMap<String, String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DiscordConfig {
+    /// The URL of the webhook
+    pub webhook_url: Url,
+    /// The name notifications are posted under, overriding the one of the webhook
+    pub username: Option<String>,
+    /// The format of the notifications, Markdown by default
+    pub format: Option<NotificationFormat>,
+    /// Templates overriding the default ones of `format`, by kind of notification
+    #[serde(default)]
+    pub templates: HashMap<String, String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct IrcConfig {
+    /// The hostname of the server
+    pub server: String,
+    /// The port of the server
+    #[serde(default = "default_irc_port")]
+    pub port: u16,
+    /// Connect with TLS
+    #[serde(default = "default_true")]
+    pub tls: bool,
+    /// The nickname to use
+    pub nick: String,
+    /// The username, the nickname by default
+    pub username: Option<String>,
+    /// The real name, the nickname by default
+    pub realname: Option<String>,
+    /// The password of the server, if it requires one
+    pub password: Option<String>,
+    /// The account to authenticate as with SASL, the nickname by default
+    pub sasl_username: Option<String>,
+    /// The password to authenticate with using SASL PLAIN; SASL is only used when set
+    pub sasl_password: Option<String>,
+    /// The channels to join and send notifications to
+    pub channels: Vec<String>,
+    /// The number of messages sent at once before flood control delays them
+    #[serde(default = "default_irc_burst")]
+    pub burst: u32,
+    /// The delay between messages once the burst is used up, in milliseconds
+    #[serde(default = "default_irc_message_interval")]
+    pub message_interval: u64,
+    /// The format of the notifications, IRC by default
+    pub format: Option<NotificationFormat>,
+    /// Templates overriding the default ones of `format`, by kind of notification
+    #[serde(default)]
+    pub templates: HashMap<String, String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct WebhookConfig {
+    /// The URL events are posted to
+    pub url: Url,
+    /// The secret the payloads are signed with, using HMAC-SHA256
+    pub secret: String,
+    /// The maximum number of attempts to deliver an event
+    #[serde(default = "default_webhook_attempts")]
+    pub max_attempts: u32,
+    /// The maximum duration of a single attempt, in seconds
+    #[serde(default = "default_webhook_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ArchiveConfig {
+    /// Expand ZIP and tar archives into their files, which are processed as attachments
+    pub enabled: bool,
+    /// The maximum number of entries in an archive, including those of nested archives
+    pub max_entries: usize,
+    /// The maximum ratio between the expanded and the compressed size of an archive
+    pub max_ratio: u64,
+    /// The maximum expanded size of an archive, in bytes
+    pub max_size: u64,
+    /// The maximum number of levels of archives that are expanded, counting the attachment
+    /// itself; archives nested any deeper are kept as they are
+    pub max_depth: usize,
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        ArchiveConfig {
+            enabled: false,
+            max_entries: 1000,
+            max_ratio: 100,
+            max_size: 256 * 1024 * 1024,
+            max_depth: 2,
+        }
+    }
+}
+
+/// What to do with an attachment malware was detected in.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScanAction {
+    /// Drop the attachment.
+    Reject,
+    /// Store the attachment in the private quarantine bucket.
+    Quarantine,
+    /// Upload the attachment as usual, with the detected signature in its metadata.
+    Tag,
+}
+
+/// The format of notification messages, which selects the default templates.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationFormat {
+    /// Plain text with IRC colour codes.
+    #[default]
+    Irc,
+    /// Plain text.
+    Plain,
+    /// Markdown.
+    Markdown,
+}
+
+/// The format images are converted to.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageFormat {
+    /// Lossy JPEG.
+    #[default]
+    Jpeg,
+    /// Lossless WebP.
+    Webp,
+}
+
+/// What to do with an attachment when a post-processor fails on it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Policy {
+    /// Reject the attachment.
+    Required,
+    /// Continue with the output of the previous stage.
+    BestEffort,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// The MIME types the default post-processing pipeline sanitizes, given that `exiftool` is
+/// installed for the videos and HEIC images.
+fn default_sanitized_mime_types() -> Vec<String> {
+    [
+        "image/jpeg",
+        "image/png",
+        "image/webp",
+        "image/heic",
+        "video/mp4",
+        "video/heic",
+        "video/mpeg",
+        "video/quicktime",
+        "video/3gpp",
+        "video/x-msvideo",
+        "video/x-ms-wmv",
+        "application/pdf",
+        "application/vnd.openxmlformats-officedocument.*",
+        "application/vnd.oasis.opendocument.*",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+fn default_irc_port() -> u16 {
+    6697
+}
+
+fn default_irc_burst() -> u32 {
+    4
+}
+
+fn default_irc_message_interval() -> u64 {
+    2000
+}
+
+fn default_webhook_attempts() -> u32 {
+    5
+}
+
+fn default_webhook_timeout() -> u64 {
+    10
+}
+
+#[cfg(test)]
+mod tests {
+    use figment::{
+        providers::{Format, Toml},
+        Figment,
+    };
+
+    use super::*;
+
+    #[test]
+    fn parses_example_config() {
+        let config: Config = Figment::new()
+            .merge(Toml::file(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/config.toml"
+            )))
+            .extract()
+            .unwrap();
+
+        assert!(config.ingestion.reject_unsanitized);
+    }
+
+    #[test]
+    fn rejects_unsanitized_media_by_default() {
+        let config: IngestionConfig = Figment::new()
+            .merge(Toml::string(r#"api_token = "secret""#))
+            .extract()
+            .unwrap();
+
+        assert!(config.reject_unsanitized);
+        assert!(crate::mime::matches_any(
+            &config.sanitized_mime_types,
+            "image/jpeg"
+        ));
+        assert!(crate::mime::matches_any(
+            &config.sanitized_mime_types,
+            "application/vnd.openxmlformats-officedocument.wordprocessingml.document"
+        ));
+        assert!(!crate::mime::matches_any(
+            &config.sanitized_mime_types,
+            "text/plain"
+        ));
+        assert!(!crate::mime::matches_any(
+            &config.sanitized_mime_types,
+            "image/gif"
+        ));
+        assert!(!crate::mime::matches_any(
+            &config.sanitized_mime_types,
+            "video/webm"
+        ));
+    }
+}
@@ -1,4 +1,4 @@
-use std::io;
+use std::{io, time::Duration};
 
 use miette::Diagnostic;
 use thiserror::Error;
@@ -11,6 +11,8 @@ pub enum Error {
     CreateTempFile(#[source] io::Error),
     #[error("post-processing failed: {0}")]
     PostProcessFailed(String),
+    #[error("post-processor `{0}' timed out after {1:?}")]
+    PostProcessTimeout(String, Duration),
     #[error("aws sdk error")]
     AwsS3Error(#[source] Box<aws_sdk_s3::Error>),
     #[error("s3 error")]
@@ -19,6 +21,40 @@ pub enum Error {
     ByteStream(#[source] Box<aws_sdk_s3::primitives::ByteStreamError>),
     #[error("reqwest error")]
     Reqwest(#[from] reqwest::Error),
+    #[error("store error")]
+    Store(#[from] rusqlite::Error),
+    #[error("attachment `{0}' does not exist")]
+    AttachmentNotFound(String),
+    #[error("`{0}' is not the key of an attachment")]
+    InvalidAttachmentKey(String),
+    #[error("unknown post-processor `{0}', expected a built-in one or a `command'")]
+    UnknownPostProcessor(String),
     #[error("the tool `{0}' failed healthcheck, is it installed?")]
     ToolCheckFailed(String),
+    #[error("`transcode' encodes WebP losslessly, so `quality' only applies to JPEG")]
+    UnsupportedQuality,
+    #[error("virus scan failed: {0}")]
+    ScanFailed(String),
+    #[error("invalid clamd address `{0}', expected `tcp://host:port' or `unix:/path'")]
+    InvalidScanAddress(String),
+    #[error("quarantining requires a `quarantine_bucket' other than the public bucket")]
+    InvalidQuarantineBucket,
+    #[error("archive rejected: {0}")]
+    ArchiveRejected(String),
+    #[error("unknown notification template `{0}'")]
+    UnknownTemplate(String),
+    #[error("invalid notification template `{0}'")]
+    InvalidTemplate(String, #[source] minijinja::Error),
+    #[error("could not render notification template `{0}'")]
+    RenderTemplate(String, #[source] minijinja::Error),
+    #[error("request to `{0}' failed: {1}")]
+    HttpRequestFailed(String, String),
+    #[error("response from `{0}' exceeds {1} bytes")]
+    ResponseTooLarge(String, usize),
+    #[error("irc error: {0}")]
+    Irc(String),
+    #[error("could not set up tls")]
+    TlsSetup(#[source] native_tls::Error),
+    #[error("tls handshake with `{0}' failed")]
+    TlsHandshake(String, #[source] native_tls::Error),
 }
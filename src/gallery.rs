@@ -0,0 +1,342 @@
+use std::fmt::Write;
+
+use axum::{routing::get, Extension, Router};
+use mail_parser::DateTime;
+use serde::Deserialize;
+use tower_http::services::ServeDir;
+
+use crate::{config::GalleryConfig, http::BasicAuthPassword, store::MailRecord, AppState};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GalleryQuery {
+    /// The page to show, starting at 1.
+    pub page: Option<u32>,
+}
+
+/// Returns the routes of the gallery, which is served at `/gallery`.
+///
+/// The routes are absolute rather than nested, as a nested `/` route doesn't match `/gallery/`.
+pub fn router(config: &GalleryConfig) -> Router<AppState> {
+    Router::new()
+        .route("/gallery", get(handlers::index))
+        .route("/gallery/", get(handlers::index))
+        .nest_service("/gallery/assets", ServeDir::new(&config.assets_path))
+        .layer(Extension(config.clone()))
+}
+
+mod handlers {
+    use axum::{
+        extract::{Query, State},
+        http::StatusCode,
+        response::{Html, IntoResponse},
+    };
+    use tracing::error;
+
+    use super::*;
+
+    use crate::{http::unauthorized, store::MailFilter};
+
+    #[tracing::instrument(skip_all)]
+    pub(super) async fn index(
+        BasicAuthPassword(password): BasicAuthPassword,
+        State(AppState {
+            api_token,
+            mail_handler,
+        }): State<AppState>,
+        Extension(config): Extension<GalleryConfig>,
+        Query(query): Query<GalleryQuery>,
+    ) -> impl IntoResponse {
+        if password != api_token {
+            return unauthorized();
+        }
+
+        let page = query.page.unwrap_or(1).max(1);
+        let per_page = config.per_page.max(1);
+        let offset = (page - 1).saturating_mul(per_page);
+        let mail_handler = mail_handler.lock().await;
+
+        let filter = MailFilter {
+            stored: true,
+            ..MailFilter::default()
+        };
+
+        match mail_handler.store.find_mails(&filter, per_page, offset) {
+            Ok((mails, total)) => {
+                let has_next = u64::from(page.saturating_mul(per_page)) < total;
+                let object_url = |key: &str| {
+                    mail_handler
+                        .s3_config
+                        .object_url(key)
+                        .map(|x| x.to_string())
+                };
+
+                Html(render(&mails, page, has_next, object_url)).into_response()
+            }
+            Err(err) => {
+                error!(%err, "could not look up mails");
+
+                (StatusCode::INTERNAL_SERVER_ERROR, "could not look up mails").into_response()
+            }
+        }
+    }
+}
+
+/// Renders the gallery page for the given `mails`.
+fn render(
+    mails: &[MailRecord],
+    page: u32,
+    has_next: bool,
+    object_url: impl Fn(&str) -> Option<String>,
+) -> String {
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+         <title>Recent uploads</title>\n\
+         <link rel=\"stylesheet\" href=\"/gallery/assets/gallery.css\">\n</head>\n<body>\n\
+         <h1>Recent uploads</h1>\n",
+    );
+
+    for mail in mails {
+        let subject = mail.subject.as_deref().unwrap_or("(no subject)");
+        let sender = mail.sender.as_deref().unwrap_or("unknown");
+        let date = DateTime::from_timestamp(mail.date.unwrap_or(mail.received_at)).to_rfc3339();
+
+        let _ = write!(
+            html,
+            "<section class=\"mail\">\n<h2>{}</h2>\n<p class=\"meta\">from {} &middot; \
+             <time datetime=\"{date}\">{date}</time></p>\n<ul class=\"attachments\">\n",
+            escape(subject),
+            escape(sender),
+        );
+
+        // Attachments that were never stored or were taken down have nothing to show.
+        for attachment in mail.attachments.iter().filter(|x| x.key.is_some()) {
+            let filename = attachment
+                .filename
+                .as_deref()
+                .or(attachment.key.as_deref())
+                .unwrap_or("(unnamed)");
+            let url = attachment.key.as_deref().and_then(&object_url);
+            // Originals can be huge, so attachments without a thumbnail get a placeholder.
+            let thumbnail_url = attachment.thumbnail_key.as_deref().and_then(&object_url);
+
+            html.push_str("<li>");
+
+            match (url, thumbnail_url) {
+                (Some(url), Some(thumbnail_url)) => {
+                    let _ = write!(
+                        html,
+                        "<a href=\"{}\"><img src=\"{}\" alt=\"{}\" loading=\"lazy\"></a>",
+                        escape(&url),
+                        escape(&thumbnail_url),
+                        escape(filename),
+                    );
+                }
+                (Some(url), None) => {
+                    let _ = write!(
+                        html,
+                        "<a class=\"file\" href=\"{}\">{}</a>",
+                        escape(&url),
+                        escape(&attachment.mime_type)
+                    );
+                }
+                (None, _) => {
+                    let _ = write!(html, "<span class=\"file\">{}</span>", attachment.outcome);
+                }
+            }
+
+            let _ = writeln!(
+                html,
+                "<span class=\"filename\">{}</span></li>",
+                escape(filename)
+            );
+        }
+
+        html.push_str("</ul>\n</section>\n");
+    }
+
+    html.push_str("<nav>");
+
+    if page > 1 {
+        let _ = write!(html, "<a href=\"?page={}\">&larr; newer</a> ", page - 1);
+    }
+
+    if has_next {
+        let _ = write!(html, "<a href=\"?page={}\">older &rarr;</a>", page + 1);
+    }
+
+    html.push_str("</nav>\n</body>\n</html>\n");
+    html
+}
+
+/// Escapes the given `value` for use in HTML text and attribute values.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use aws_sdk_s3::config::BehaviorVersion;
+    use tokio::{net::TcpListener, sync::Mutex};
+
+    use super::*;
+    use crate::{
+        config::{
+            ArchiveConfig, AwsS3Config, BodiesConfig, HttpConfig, LimitsConfig, LinksConfig,
+            NotificationsConfig, PartsConfig,
+        },
+        handler::MailHandler,
+        http_client::HttpClient,
+        notifier::Notifiers,
+        store::{AttachmentRecord, Outcome, Store},
+        webhook::Webhooks,
+    };
+
+    fn mail(attachments: Vec<AttachmentRecord>) -> MailRecord {
+        MailRecord {
+            id: 1,
+            message_id: None,
+            date: Some(0),
+            received_at: 0,
+            sender: Some("<script>".to_string()),
+            recipients: vec![],
+            subject: None,
+            outcome: Outcome::Processed,
+            attachments,
+        }
+    }
+
+    fn attachment(key: &str, mime_type: &str, thumbnail_key: Option<&str>) -> AttachmentRecord {
+        AttachmentRecord {
+            key: Some(key.to_string()),
+            filename: Some("a.jpg".to_string()),
+            mime_type: mime_type.to_string(),
+            size: 1,
+            outcome: Outcome::Uploaded,
+            thumbnail_key: thumbnail_key.map(String::from),
+        }
+    }
+
+    fn object_url(key: &str) -> Option<String> {
+        Some(format!("https://example.com/{key}"))
+    }
+
+    #[test]
+    fn embeds_thumbnails() {
+        let html = render(
+            &[mail(vec![attachment("a", "image/jpeg", Some("a.thumb"))])],
+            1,
+            false,
+            object_url,
+        );
+
+        assert!(html.contains(
+            "<a href=\"https://example.com/a\"><img src=\"https://example.com/a.thumb\""
+        ));
+    }
+
+    #[test]
+    fn never_embeds_originals() {
+        let html = render(
+            &[mail(vec![attachment("a", "image/jpeg", None)])],
+            1,
+            false,
+            object_url,
+        );
+
+        assert!(!html.contains("<img"));
+        assert!(html.contains("<a class=\"file\" href=\"https://example.com/a\">image/jpeg</a>"));
+    }
+
+    #[test]
+    fn skips_attachments_that_are_not_stored() {
+        let taken_down = AttachmentRecord {
+            key: None,
+            filename: Some("gone.jpg".to_string()),
+            outcome: Outcome::TakenDown,
+            ..attachment("a", "image/jpeg", None)
+        };
+        let html = render(&[mail(vec![taken_down])], 1, false, object_url);
+
+        assert!(!html.contains("gone.jpg"));
+        assert!(!html.contains("taken_down"));
+    }
+
+    #[tokio::test]
+    async fn serves_the_index_with_and_without_a_trailing_slash() {
+        let dir = tempfile::tempdir().unwrap();
+        let store_path = dir.path().join("store.db");
+        let s3_client = aws_sdk_s3::Client::from_conf(
+            aws_sdk_s3::Config::builder()
+                .behavior_version(BehaviorVersion::latest())
+                .build(),
+        );
+        let s3_config = AwsS3Config {
+            bucket_name: "bucket".to_string(),
+            public_url: None,
+        };
+        let http = HttpClient::new(&HttpConfig::default()).unwrap();
+        let mail_handler = MailHandler::new(
+            s3_client,
+            s3_config,
+            Notifiers::start(vec![]),
+            vec![],
+            Store::open(&store_path).unwrap(),
+            None,
+            LimitsConfig::default(),
+            ArchiveConfig::default(),
+            PartsConfig::default(),
+            BodiesConfig::default(),
+            LinksConfig::default(),
+            NotificationsConfig::default(),
+            Webhooks::start(&[], &store_path, &http).unwrap(),
+            false,
+            vec![],
+        );
+        let state = AppState {
+            api_token: "token".to_string(),
+            mail_handler: Arc::new(Mutex::new(mail_handler)),
+        };
+        let app = router(&GalleryConfig::default()).with_state(state);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let client = reqwest::Client::new();
+
+        for path in ["/gallery", "/gallery/"] {
+            let response = client
+                .get(format!("http://{addr}{path}"))
+                .basic_auth("gallery", Some("token"))
+                .send()
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), reqwest::StatusCode::OK, "{path}");
+        }
+    }
+
+    #[test]
+    fn escapes_mail_contents() {
+        let html = render(&[mail(vec![])], 1, false, object_url);
+
+        assert!(html.contains("from &lt;script&gt;"));
+        assert!(!html.contains("<script>"));
+    }
+}
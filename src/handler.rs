@@ -1,17 +1,66 @@
 use std::{
     fs,
     io::{self, Write},
+    path::Path,
 };
 
-use aws_sdk_s3::{primitives::ByteStream, types::ObjectCannedAcl, Error as AwsS3Error};
+use aws_sdk_s3::{
+    operation::put_object::builders::PutObjectFluentBuilder, primitives::ByteStream,
+    types::ObjectCannedAcl, Error as AwsS3Error,
+};
 use base64::prelude::{Engine, BASE64_URL_SAFE_NO_PAD};
-use mail_parser::Message;
-use serde_json::json;
+use mail_parser::{Message, MimeHeaders};
+use serde::Serialize;
 use sha2::{Digest, Sha256};
 use tempfile::{NamedTempFile, TempPath};
-use tracing::{debug, error, info, instrument};
+use tracing::{debug, error, info, instrument, warn};
+
+use crate::{
+    archive,
+    body::{self, Body},
+    config::{
+        ArchiveConfig, AwsS3Config, BodiesConfig, LimitsConfig, LinksConfig, NotificationsConfig,
+        PartsConfig, Policy, ScanAction,
+    },
+    links,
+    mime::{self, Detection},
+    notifier::Notifiers,
+    notify::Notification,
+    parts,
+    postprocess::{self, Derived, PostProcessor},
+    report::{AttachmentReport, MailReport, ScanReport, ScanStatus},
+    scan::{Scanner, Verdict},
+    store::{Outcome, Store},
+    webhook::{Event, Webhooks},
+    Error,
+};
 
-use crate::{config::AwsS3Config, postprocess::PostProcessor, Error};
+/// The prefix under which attachments are stored in the S3 bucket.
+pub const ATTACHMENT_KEY_PREFIX: &str = "~meta/mails/v2/";
+
+/// Information about the mail an attachment was included in.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MailInfo<'a> {
+    /// The Message-ID of the mail, if any.
+    pub message_id: Option<&'a str>,
+    /// The sender of the mail, if known.
+    pub sender: Option<&'a str>,
+    /// The intended recipient of the mail, if known.
+    pub recipient: Option<&'a str>,
+    /// The subject of the mail, if any.
+    pub subject: Option<&'a str>,
+}
+
+/// The result of an attachment takedown.
+#[derive(Debug, Clone, Serialize)]
+pub struct Takedown {
+    /// The key of the attachment.
+    pub key: String,
+    /// Whether the object was deleted from the S3 bucket.
+    pub deleted: bool,
+    /// The number of mails that still reference the attachment.
+    pub remaining_references: u64,
+}
 
 /// The result of an attachment upload.
 pub struct AttachmentUpload {
@@ -23,6 +72,8 @@ pub struct AttachmentUpload {
     pub sender: Option<String>,
     /// Whether the attachment already existed in the remote bucket.
     pub cached: bool,
+    /// The keys of the artifacts derived from the attachment.
+    pub derived_keys: Vec<String>,
 }
 
 #[derive(Debug)]
@@ -39,17 +90,52 @@ pub struct MailHandler {
     pub s3_client: aws_sdk_s3::Client,
     /// AWS S3 configuration.
     pub s3_config: AwsS3Config,
-    /// Meta webhook bearer token.
-    pub meta_webhook_token: String,
+    /// The chat services notifications are sent to.
+    pub notifiers: Notifiers,
+    /// Index of the attachments referenced by ingested mails.
+    pub store: Store,
+    /// Malware scanner attachments are checked with before they are uploaded, if enabled.
+    pub scanner: Option<Scanner>,
+    /// Limits on the attachments that are accepted.
+    pub limits: LimitsConfig,
+    /// Limits on the archives that are expanded, if enabled.
+    pub archives: ArchiveConfig,
+    /// Which parts of a mail are processed as attachments.
+    pub parts: PartsConfig,
+    /// Which mail bodies are stored.
+    pub bodies: BodiesConfig,
+    /// Which URLs in mail bodies are notified about, if enabled.
+    pub links: LinksConfig,
+    /// How chat notifications are grouped and split.
+    pub notifications: NotificationsConfig,
+    /// Outbound webhooks that receive an event for every ingested attachment.
+    pub webhooks: Webhooks,
+    /// Reject attachments of the `sanitized_mime_types` that no metadata-sanitizing
+    /// post-processor ran on.
+    pub reject_unsanitized: bool,
+    /// Glob patterns of the MIME types that have to be sanitized.
+    pub sanitized_mime_types: Vec<String>,
 }
 
 impl MailHandler {
     #[must_use]
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         s3_client: aws_sdk_s3::Client,
         s3_config: AwsS3Config,
-        meta_webhook_token: String,
+        notifiers: Notifiers,
         postprocessors: Vec<Box<dyn PostProcessor>>,
+        store: Store,
+        scanner: Option<Scanner>,
+        limits: LimitsConfig,
+        archives: ArchiveConfig,
+        parts: PartsConfig,
+        bodies: BodiesConfig,
+        links: LinksConfig,
+        notifications: NotificationsConfig,
+        webhooks: Webhooks,
+        reject_unsanitized: bool,
+        sanitized_mime_types: Vec<String>,
     ) -> Self {
         MailHandler {
             num_attachments_bytes_processed: 0,
@@ -58,112 +144,665 @@ impl MailHandler {
             processors: postprocessors,
             s3_client,
             s3_config,
-            meta_webhook_token,
+            notifiers,
+            store,
+            scanner,
+            limits,
+            archives,
+            parts,
+            bodies,
+            links,
+            notifications,
+            webhooks,
+            reject_unsanitized,
+            sanitized_mime_types,
         }
     }
 
     #[instrument(skip_all)]
-    pub async fn handle(&mut self, mail: Message<'_>, from: Option<&str>) -> Result<(), Error> {
-        if mail.attachment_count() == 0 {
+    pub async fn handle(
+        &mut self,
+        mail: Message<'_>,
+        from: Option<&str>,
+        to: Option<&str>,
+    ) -> Result<MailReport, Error> {
+        let subject = mail.subject();
+        let info = MailInfo {
+            message_id: mail.message_id(),
+            sender: from,
+            recipient: to,
+            subject,
+        };
+        let mut report = MailReport {
+            message_id: info.message_id.map(String::from),
+            subject: subject.map(String::from),
+            outcome: Outcome::Skipped,
+            attachments: vec![],
+            body: None,
+            links: vec![],
+            error: None,
+        };
+
+        let parts = parts::collect(&mail, &self.parts);
+        let body = if self.bodies.applies_to(to) {
+            body::extract(&mail, self.bodies.excerpt_length)
+        } else {
+            None
+        };
+
+        let urls = if self.links.enabled {
+            links::extract(&mail, &self.links)
+        } else {
+            vec![]
+        };
+
+        if parts.is_empty() && body.is_none() && urls.is_empty() {
             info!(from = ?mail.from(), "skipping email as it doesn't contain any attachments");
 
-            return Ok(());
+            self.record_mail(&mail, &info, &report);
+
+            return Ok(report);
         }
 
-        let subject = mail.subject();
+        let mut accepted = Accepted::default();
+        let mut notifications = vec![];
 
-        for attachment in mail.attachments() {
-            debug!("processing attachment");
+        if let Some(body) = body {
+            let body_report = self.store_body(&body, &info).await?;
 
-            let attachment_size = attachment.len();
-            let mime_type = tree_magic_mini::from_u8(attachment.contents());
-            let mut file = NamedTempFile::new().expect("could not create temp file");
+            notifications.extend(Notification::body(
+                &body_report,
+                &body.excerpt,
+                &info,
+                &self.s3_config,
+            ));
+            report.body = Some(body_report);
+        }
 
-            debug!(
-                path = %file.path().display(),
-                "writing {attachment_size} bytes attachment of type {mime_type} to disk"
-            );
+        for url in urls {
+            notifications.push(Notification::link(url.as_str(), &info));
+            report.links.push(url.into());
+        }
 
-            file.write_all(attachment.contents())?;
+        let mut attachment_notifications = vec![];
 
-            // Run post-processing pipeline on the temporary file.
-            let mut path = Some(file.into_temp_path());
-            let processors = self.processors.iter().filter(|x| x.applicable(mime_type));
+        for part in parts {
+            let contents = part.part.contents();
+            let filename = part.part.attachment_name();
+            let declared = part.part.content_type().map(|x| match x.subtype() {
+                Some(subtype) => format!("{}/{subtype}", x.ctype()),
+                None => x.ctype().to_string(),
+            });
+            let detection = mime::detect(contents, declared.as_deref(), filename);
+            let mime_type = detection.mime_type;
+            let size = contents.len() as u64;
 
-            for processor in processors {
-                if let Some(inner_path) = path {
-                    path = match processor.apply(inner_path) {
-                        Ok(temp_path) => Some(temp_path),
-                        Err(_) => None,
-                    }
+            if detection.mismatch {
+                warn!(
+                    sniffed = detection.sniffed,
+                    declared = ?detection.declared,
+                    ?filename,
+                    "attachment contents do not match its declared type"
+                );
+            }
+
+            let skipped = part
+                .inline
+                .then(|| parts::too_small(&self.parts, mime_type, contents))
+                .flatten()
+                .or_else(|| check_limits(&self.limits, &accepted, mime_type, size));
+
+            let attachment_reports = if let Some(reason) = skipped {
+                info!(%mime_type, %size, %reason, "skipping attachment");
+
+                let mut attachment_report = AttachmentReport::new(filename, &detection, size);
+                attachment_report.record.outcome = Outcome::Skipped;
+                attachment_report.reason = Some(reason);
+
+                vec![attachment_report]
+            } else if self.archives.enabled && archive::expandable(mime_type, contents) {
+                // Boxed, as the nested futures would otherwise exceed the compiler's query
+                // depth limit.
+                let members = Box::pin(self.ingest_archive(
+                    contents,
+                    &detection,
+                    filename,
+                    &mut accepted,
+                    &info,
+                ))
+                .await?;
+                let name = filename.unwrap_or("archive");
+
+                attachment_notifications.extend(Notification::archive(
+                    &members,
+                    name,
+                    &info,
+                    &self.s3_config,
+                ));
+
+                members
+            } else {
+                accepted.add(size);
+
+                let attachment_report = self
+                    .process_attachment(contents, &detection, filename, &info)
+                    .await?;
+
+                attachment_notifications.extend(Notification::attachment(
+                    &attachment_report,
+                    &info,
+                    &self.s3_config,
+                ));
+
+                vec![attachment_report]
+            };
+
+            for mut attachment_report in attachment_reports {
+                attachment_report.inline = part.inline;
+                attachment_report.forwarded = part.forwarded;
+                report.attachments.push(attachment_report);
+            }
+        }
+
+        let stored = report
+            .attachments
+            .iter()
+            .filter(|x| x.record.key.is_some())
+            .count();
+        let threshold = self.notifications.batch_threshold;
+
+        // Mails with many attachments are summarized, so they don't flood the channel.
+        if threshold > 0 && stored >= threshold {
+            attachment_notifications = vec![Notification::summary(
+                &report.attachments,
+                &info,
+                &self.notifications,
+                &self.s3_config,
+            )];
+        }
+
+        notifications.extend(attachment_notifications);
+
+        for attachment_report in &report.attachments {
+            self.webhooks
+                .send(Event::attachment(attachment_report, &info, &self.s3_config));
+        }
+
+        for notification in notifications {
+            self.notifiers.send(notification);
+        }
+
+        report.outcome = if report.attachments.iter().chain(&report.body).any(|x| {
+            matches!(
+                x.record.outcome,
+                Outcome::Failed | Outcome::Rejected | Outcome::Quarantined
+            )
+        }) {
+            Outcome::Failed
+        } else {
+            Outcome::Processed
+        };
+
+        self.record_mail(&mail, &info, &report);
+        self.num_mails_processed += 1;
+
+        Ok(report)
+    }
+
+    /// Uploads the `body` of a mail.
+    #[instrument(skip_all)]
+    async fn store_body(
+        &mut self,
+        body: &Body,
+        info: &MailInfo<'_>,
+    ) -> Result<AttachmentReport, Error> {
+        let detection = Detection {
+            mime_type: body.mime_type,
+            sniffed: body.mime_type,
+            declared: None,
+            mismatch: false,
+        };
+        let mut report =
+            AttachmentReport::new(Some(body.filename), &detection, body.contents.len() as u64);
+        let mut file = NamedTempFile::new().map_err(Error::CreateTempFile)?;
+
+        file.write_all(body.contents.as_bytes())?;
+
+        match self
+            .upload_attachment(file.into_temp_path(), body.mime_type, vec![], &[], info)
+            .await
+        {
+            Ok(upload) => {
+                report.record.outcome = if upload.cached {
+                    Outcome::Cached
+                } else {
+                    Outcome::Uploaded
+                };
+                report.record.key = Some(upload.key);
+            }
+            Err(err) => {
+                error!(%err, subject = ?info.subject, sender = ?info.sender, "could not upload mail body");
+
+                report.reason = Some(err.to_string());
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Expands the archive with the given `contents` and processes each of its files as an
+    /// attachment.
+    ///
+    /// `accepted` holds the attachments of the mail accepted so far, and is updated with the
+    /// files that are accepted. If the archive can't be expanded, the returned reports
+    /// only include the rejected archive itself.
+    #[instrument(skip(self, contents, accepted, info))]
+    async fn ingest_archive(
+        &mut self,
+        contents: &[u8],
+        detection: &Detection,
+        filename: Option<&str>,
+        accepted: &mut Accepted,
+        info: &MailInfo<'_>,
+    ) -> Result<Vec<AttachmentReport>, Error> {
+        let name = filename.unwrap_or("archive").to_string();
+        let mime_type = detection.mime_type;
+        let config = self.archives.clone();
+        let owned = contents.to_vec();
+        let result =
+            tokio::task::spawn_blocking(move || archive::expand(&name, &owned, mime_type, &config))
+                .await
+                .map_err(|e| Error::ArchiveRejected(format!("expansion panicked: {e}")))
+                .and_then(|x| x);
+
+        let members = match result {
+            Ok(members) => members,
+            Err(err) => {
+                warn!(%err, ?filename, "rejecting archive");
+
+                let mut report = AttachmentReport::new(filename, detection, contents.len() as u64);
+                report.record.outcome = Outcome::Rejected;
+                report.reason = Some(err.to_string());
+
+                return Ok(vec![report]);
+            }
+        };
+
+        info!(?filename, members = members.len(), "expanded archive");
+
+        let mut reports = vec![];
+
+        for member in members {
+            let detection = mime::detect(&member.contents, None, Some(&member.path));
+            let mime_type = detection.mime_type;
+            let size = member.contents.len() as u64;
+            let mut report = match check_limits(&self.limits, accepted, mime_type, size) {
+                Some(reason) => {
+                    info!(path = %member.path, %mime_type, %size, %reason, "skipping archive member");
+
+                    let mut report = AttachmentReport::new(Some(&member.path), &detection, size);
+                    report.record.outcome = Outcome::Skipped;
+                    report.reason = Some(reason);
+                    report
+                }
+                None => {
+                    accepted.add(size);
+
+                    self.process_attachment(&member.contents, &detection, Some(&member.path), info)
+                        .await?
                 }
+            };
+
+            report.archive = filename.map(String::from);
+            reports.push(report);
+        }
+
+        Ok(reports)
+    }
+
+    /// Runs the post-processing pipeline on an attachment with the given `contents` and uploads
+    /// the result.
+    #[instrument(skip(self, contents, info))]
+    async fn process_attachment(
+        &mut self,
+        contents: &[u8],
+        detection: &Detection,
+        filename: Option<&str>,
+        info: &MailInfo<'_>,
+    ) -> Result<AttachmentReport, Error> {
+        debug!("processing attachment");
+
+        let attachment_size = contents.len();
+        let mime_type = detection.mime_type;
+        let mut file = NamedTempFile::new().map_err(Error::CreateTempFile)?;
+
+        debug!(
+            path = %file.path().display(),
+            "writing {attachment_size} bytes attachment of type {mime_type} to disk"
+        );
+
+        file.write_all(contents)?;
+
+        self.num_attachments_processed += 1;
+        self.num_attachments_bytes_processed += attachment_size as u64;
+
+        let mut report = AttachmentReport::new(filename, detection, attachment_size as u64);
+
+        // Run post-processing pipeline on the temporary file.
+        let pipeline = postprocess::run(&self.processors, file.into_temp_path(), mime_type).await;
+
+        report.processors = pipeline.reports;
+        report.record.mime_type = pipeline.mime_type.to_string();
+
+        if let Some(extension) = pipeline.extension {
+            report.record.filename = report
+                .record
+                .filename
+                .map(|x| with_extension(&x, extension));
+        }
+
+        let mime_type = pipeline.mime_type;
+
+        let Some(path) = pipeline.path else {
+            report.record.outcome = Outcome::Rejected;
+            report.reason = Some("a required post-processor failed".to_string());
+
+            return Ok(report);
+        };
+
+        if self.reject_unsanitized
+            && is_unsanitized(&self.sanitized_mime_types, mime_type, pipeline.sanitized)
+        {
+            info!(%mime_type, "rejecting attachment as its metadata could not be sanitized");
+
+            report.record.outcome = Outcome::Rejected;
+            report.reason = Some(format!("no post-processor sanitizes {mime_type}"));
+
+            return Ok(report);
+        }
+
+        if !self
+            .scan_attachment(&path, mime_type, info, &mut report)
+            .await
+        {
+            return Ok(report);
+        }
+
+        // Record both the declared and the sniffed type, and for tagged attachments the name of
+        // the detected malware.
+        let signature = report.scan.as_ref().and_then(|x| x.signature.clone());
+        let mut metadata = vec![("sniffed-type", detection.sniffed)];
+
+        if let Some(ref declared) = detection.declared {
+            metadata.push(("declared-type", declared));
+        }
+
+        if let Some(ref signature) = signature {
+            metadata.push(("malware", signature));
+        }
+
+        match self
+            .upload_attachment(path, mime_type, pipeline.derived, &metadata, info)
+            .await
+        {
+            Ok(upload) => {
+                report.record.outcome = if upload.cached {
+                    Outcome::Cached
+                } else {
+                    Outcome::Uploaded
+                };
+                report.record.key = Some(upload.key);
+                report.record.thumbnail_key = upload
+                    .derived_keys
+                    .into_iter()
+                    .find(|x| x.ends_with(".thumbnail.jpg"));
+            }
+            Err(err) => {
+                error!(%err, %mime_type, subject = ?info.subject, sender = ?info.sender, "could not upload attachment");
+
+                report.reason = Some(err.to_string());
             }
+        }
 
-            if let Some(inner_path) = path {
-                let sender = from;
+        Ok(report)
+    }
+
+    /// Scans the attachment at `path` for malware if scanning is enabled, and acts on the result.
+    ///
+    /// Returns whether the attachment may be uploaded. Otherwise `report` describes why not.
+    async fn scan_attachment(
+        &mut self,
+        path: &Path,
+        mime_type: &str,
+        info: &MailInfo<'_>,
+        report: &mut AttachmentReport,
+    ) -> bool {
+        let Some(ref scanner) = self.scanner else {
+            return true;
+        };
+
+        let (action, policy) = (scanner.action, scanner.policy);
+        let quarantine_bucket = scanner.quarantine_bucket.clone();
+        let quarantine_prefix = scanner.quarantine_prefix.clone();
+        let result = scanner.scan(path).await;
+        let scan = report.scan.insert(ScanReport {
+            status: ScanStatus::Clean,
+            signature: None,
+            action: None,
+            quarantine_key: None,
+            error: None,
+        });
+
+        let signature = match result {
+            Ok(Verdict::Clean) => return true,
+            Ok(Verdict::Infected(signature)) => signature,
+            Err(err) => {
+                error!(%err, "could not scan attachment");
+
+                scan.status = ScanStatus::Failed;
+                scan.error = Some(err.to_string());
+
+                if policy == Policy::BestEffort {
+                    return true;
+                }
+
+                report.record.outcome = Outcome::Rejected;
+                report.reason = Some("the attachment could not be scanned for malware".to_string());
+
+                return false;
+            }
+        };
+
+        warn!(%signature, ?action, "malware detected in attachment");
+
+        scan.status = ScanStatus::Infected;
+        scan.signature = Some(signature.clone());
+        scan.action = Some(action);
+
+        match action {
+            ScanAction::Reject => {
+                report.record.outcome = Outcome::Rejected;
+                report.reason = Some(format!("malware detected: {signature}"));
+
+                false
+            }
+            ScanAction::Quarantine => {
+                let bucket = quarantine_bucket.expect("quarantining scanners have a bucket");
 
                 match self
-                    .upload_attachment(inner_path, mime_type, subject, sender)
+                    .quarantine_attachment(
+                        path,
+                        &bucket,
+                        &quarantine_prefix,
+                        mime_type,
+                        &signature,
+                        info,
+                    )
                     .await
                 {
-                    Ok(upload) => {
-                        let key = upload.key;
-                        let sender = upload.sender.unwrap_or("unknown".to_string());
-                        let message = match subject {
-                            Some(subject) => {
-                                format!("\x0310> “\x0f{subject}\x0310” from\x0f {sender}\x0310: https://pub.rwx.im/{key}")
-                            }
-                            None => {
-                                format!("\x0310> Mail received from\x0f {sender}\x0310 https://pub.rwx.im/{key}")
-                            }
-                        };
-
-                        if let Err(err) = self.send_chat_message(message).await {
-                            error!(%err, "could not send notification message");
+                    Ok(key) => {
+                        report.record.outcome = Outcome::Quarantined;
+
+                        if let Some(ref mut scan) = report.scan {
+                            scan.quarantine_key = Some(key);
                         }
                     }
                     Err(err) => {
-                        error!(%err, %mime_type, ?subject, ?sender, "could not upload attachment");
+                        error!(%err, "could not quarantine attachment");
+
+                        report.reason = Some(err.to_string());
                     }
                 }
-            }
 
-            self.num_attachments_processed += 1;
-            self.num_attachments_bytes_processed += attachment_size as u64;
+                false
+            }
+            ScanAction::Tag => true,
         }
+    }
 
-        self.num_mails_processed += 1;
+    /// Uploads the attachment at `path` under the quarantine `prefix` of the private `bucket` and
+    /// returns its key.
+    #[instrument(skip(self, path, info))]
+    async fn quarantine_attachment(
+        &mut self,
+        path: &Path,
+        bucket: &str,
+        prefix: &str,
+        mime_type: &str,
+        signature: &str,
+        info: &MailInfo<'_>,
+    ) -> Result<String, Error> {
+        let key = format!("{prefix}{}", content_hash(path)?);
+        let body = ByteStream::from_path(path)
+            .await
+            .map_err(|e| Error::ByteStream(Box::new(e)))?;
+
+        debug!(%key, "quarantining object");
+
+        let put_object = self
+            .s3_client
+            .put_object()
+            .bucket(bucket)
+            .key(&key)
+            .content_type(mime_type)
+            .content_disposition("attachment")
+            .metadata("malware", signature);
 
-        Ok(())
+        with_origin(put_object, info)
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| Error::S3PutObjectFailed(Box::new(e.into())))?;
+
+        Ok(key)
     }
 
-    #[instrument(skip_all)]
-    async fn send_chat_message(&mut self, msg: String) -> Result<(), Error> {
-        let payload = json!({
-            "method": "message",
-            "params": {
-                "network": "irc.rwx.im:6697",
-                "channel": "#uplink",
-                "message": msg
+    /// Records the ingested `mail` in the store.
+    fn record_mail(&mut self, mail: &Message<'_>, info: &MailInfo<'_>, report: &MailReport) {
+        let mut recipients: Vec<String> = [mail.to(), mail.cc()]
+            .into_iter()
+            .flatten()
+            .flat_map(|x| x.iter())
+            .filter_map(|x| x.address())
+            .map(String::from)
+            .collect();
+
+        if let Some(recipient) = info.recipient {
+            if !recipients.iter().any(|x| x == recipient) {
+                recipients.insert(0, recipient.to_string());
             }
-        });
+        }
 
-        let client = reqwest::Client::new();
-        let res = client
-            .post("https://meta-webhook.infra.rwx.im/trigger")
-            .header(
-                "Authorization",
-                format!("Bearer {}", self.meta_webhook_token),
-            )
-            .json(&payload)
+        if let Err(err) = self.store.add_mail(
+            info.message_id,
+            mail.date().map(mail_parser::DateTime::to_timestamp),
+            info.sender,
+            &recipients,
+            info.subject,
+            report.outcome,
+            report
+                .attachments
+                .iter()
+                .chain(&report.body)
+                .map(|x| &x.record),
+        ) {
+            error!(%err, "could not record mail");
+        }
+    }
+
+    /// Returns the keys of the attachments that were included in the mail with the given
+    /// `message_id` and/or sent by the given `sender`.
+    pub fn find_attachments(
+        &self,
+        message_id: Option<&str>,
+        sender: Option<&str>,
+    ) -> Result<Vec<String>, Error> {
+        self.store.find_keys(message_id, sender)
+    }
+
+    /// Takes down the attachment with the given `key` and announces the takedown.
+    ///
+    /// If `message_id` and/or `sender` are given, only the references from the matching mails
+    /// are dropped and the object is kept for as long as other mails still reference it.
+    #[instrument(skip(self))]
+    pub async fn delete_attachment(
+        &mut self,
+        key: &str,
+        message_id: Option<&str>,
+        sender: Option<&str>,
+    ) -> Result<Takedown, Error> {
+        // Only attachments may be taken down, not arbitrary objects in the bucket.
+        if !is_attachment_key(key) {
+            return Err(Error::InvalidAttachmentKey(key.to_string()));
+        }
+
+        if !self.store.is_referenced(key)? && !self.object_exists(key).await? {
+            return Err(Error::AttachmentNotFound(key.to_string()));
+        }
+
+        let remaining_references = self.store.remove_references(key, message_id, sender)?;
+
+        if remaining_references > 0 {
+            info!(%key, %remaining_references, "keeping attachment as it is still referenced");
+
+            return Ok(Takedown {
+                key: key.to_string(),
+                deleted: false,
+                remaining_references,
+            });
+        }
+
+        for derived_key in self.store.derived_keys(key)? {
+            self.s3_client
+                .delete_object()
+                .bucket(&self.s3_config.bucket_name)
+                .key(&derived_key)
+                .send()
+                .await
+                .map_err(|e| Error::AwsS3Error(Box::new(e.into())))?;
+
+            debug!(%derived_key, "deleted derived object");
+        }
+
+        self.s3_client
+            .delete_object()
+            .bucket(&self.s3_config.bucket_name)
+            .key(key)
             .send()
-            .await?
-            .text()
-            .await?;
+            .await
+            .map_err(|e| Error::AwsS3Error(Box::new(e.into())))?;
+        self.store.remove_attachment(key)?;
+
+        info!(%key, "deleted attachment");
 
-        debug!(%res, "sent chat message");
+        self.notifiers
+            .send(Notification::takedown(key, &self.s3_config));
 
-        Ok(())
+        Ok(Takedown {
+            key: key.to_string(),
+            deleted: true,
+            remaining_references,
+        })
     }
 
     /// Returns whether the object with the given `key` already exists in the S3 bucket.
@@ -188,35 +827,48 @@ impl MailHandler {
         &mut self,
         path: TempPath,
         mime_type: &str,
-        subject: Option<&str>,
-        sender: Option<&str>,
+        derived: Vec<Derived>,
+        metadata: &[(&str, &str)],
+        info: &MailInfo<'_>,
     ) -> Result<AttachmentUpload, Error> {
-        let sha256_bytes = {
-            let mut hasher = Sha256::new();
-            let mut file = fs::File::open(&path)?;
-            let _ = io::copy(&mut file, &mut hasher)?;
+        let key = format!("{ATTACHMENT_KEY_PREFIX}{}", content_hash(&path)?);
 
-            hasher.finalize()
+        // Check if the file has been seen before, either by another mail or in the bucket.
+        let cached = match self.store.is_referenced(&key) {
+            Ok(true) => true,
+            _ => matches!(self.object_exists(&key).await, Ok(true)),
         };
 
-        let encoded_hash = BASE64_URL_SAFE_NO_PAD.encode(sha256_bytes);
-        let key = format!("~meta/mails/v2/{encoded_hash}");
-
-        // Check if the file already exists.
-        if let Ok(true) = self.object_exists(&key).await {
+        if cached {
             debug!(%key, "skipping upload of object as it already exists in the bucket");
 
+            self.store.add_reference(
+                &key,
+                info.message_id,
+                info.sender,
+                info.recipient,
+                info.subject,
+            )?;
+
+            // Objects stored before their artifacts were derived still get them.
+            let mut derived_keys = self.store.derived_keys(&key)?;
+
+            if derived_keys.is_empty() {
+                derived_keys = self.upload_derived(&key, derived).await;
+            }
+
             return Ok(AttachmentUpload {
                 key,
-                sender: sender.map(String::from),
-                subject: subject.map(String::from),
+                sender: info.sender.map(String::from),
+                subject: info.subject.map(String::from),
                 cached: true,
+                derived_keys,
             });
         }
 
         debug!(%key, "uploading object");
 
-        let put_object = self
+        let mut put_object = self
             .s3_client
             .put_object()
             .bucket(&self.s3_config.bucket_name)
@@ -225,6 +877,12 @@ impl MailHandler {
             .content_type(mime_type)
             .content_disposition(content_type_disposition(mime_type));
 
+        for (name, value) in metadata.iter().filter(|(_, x)| x.is_ascii()) {
+            put_object = put_object.metadata(*name, *value);
+        }
+
+        let put_object = with_origin(put_object, info);
+
         match ByteStream::from_path(&path).await {
             Ok(body) => {
                 let _ = put_object
@@ -233,11 +891,22 @@ impl MailHandler {
                     .await
                     .map_err(|e| Error::S3PutObjectFailed(Box::new(e.into())))?;
 
+                self.store.add_reference(
+                    &key,
+                    info.message_id,
+                    info.sender,
+                    info.recipient,
+                    info.subject,
+                )?;
+
+                let derived_keys = self.upload_derived(&key, derived).await;
+
                 return Ok(AttachmentUpload {
                     key,
-                    sender: sender.map(String::from),
-                    subject: subject.map(String::from),
-                    cached: true,
+                    sender: info.sender.map(String::from),
+                    subject: info.subject.map(String::from),
+                    cached: false,
+                    derived_keys,
                 });
             }
             Err(err) => {
@@ -245,6 +914,161 @@ impl MailHandler {
             }
         }
     }
+
+    /// Uploads the artifacts `derived` from the object with the given `key` and returns their
+    /// keys.
+    ///
+    /// Failures are logged rather than returned, as the original object has been stored already.
+    #[instrument(skip(self, derived))]
+    async fn upload_derived(&mut self, key: &str, derived: Vec<Derived>) -> Vec<String> {
+        let mut derived_keys = vec![];
+
+        for artifact in derived {
+            let derived_key = artifact.key(key);
+
+            debug!(%derived_key, "uploading derived object");
+
+            let body = match ByteStream::from_path(&artifact.path).await {
+                Ok(body) => body,
+                Err(err) => {
+                    error!(%err, %derived_key, "could not read derived object");
+
+                    continue;
+                }
+            };
+
+            let result = self
+                .s3_client
+                .put_object()
+                .bucket(&self.s3_config.bucket_name)
+                .acl(ObjectCannedAcl::PublicRead)
+                .key(&derived_key)
+                .content_type(artifact.mime_type)
+                .content_disposition(content_type_disposition(artifact.mime_type))
+                .body(body)
+                .send()
+                .await;
+
+            if let Err(err) = result {
+                error!(err = %aws_sdk_s3::Error::from(err), %derived_key, "could not upload derived object");
+
+                continue;
+            }
+
+            if let Err(err) = self.store.add_derived(key, &derived_key) {
+                error!(%err, %derived_key, "could not record derived object");
+            }
+
+            derived_keys.push(derived_key);
+        }
+
+        derived_keys
+    }
+}
+
+/// Returns the URL-safe base64 encoded SHA-256 hash of the file at `path`.
+fn content_hash(path: &Path) -> Result<String, Error> {
+    let mut hasher = Sha256::new();
+    let mut file = fs::File::open(path)?;
+    let _ = io::copy(&mut file, &mut hasher)?;
+
+    Ok(BASE64_URL_SAFE_NO_PAD.encode(hasher.finalize()))
+}
+
+/// The attachments of a mail accepted so far, which count towards the per-mail limits.
+#[derive(Debug, Default)]
+struct Accepted {
+    /// The number of attachments.
+    count: usize,
+    /// The total size of the attachments, in bytes.
+    size: u64,
+}
+
+impl Accepted {
+    /// Counts an accepted attachment of the given `size`.
+    fn add(&mut self, size: u64) {
+        self.count += 1;
+        self.size += size;
+    }
+}
+
+/// Returns whether an attachment of the given `mime_type` is one of the `sanitized_mime_types`
+/// but no post-processor `sanitized` its metadata.
+fn is_unsanitized(sanitized_mime_types: &[String], mime_type: &str, sanitized: bool) -> bool {
+    !sanitized && mime::matches_any(sanitized_mime_types, mime_type)
+}
+
+/// Returns the reason an attachment must be skipped according to the `limits`, given the
+/// attachments of the mail `accepted` so far, if any.
+fn check_limits(
+    limits: &LimitsConfig,
+    accepted: &Accepted,
+    mime_type: &str,
+    size: u64,
+) -> Option<String> {
+    if mime::matches_any(&limits.denied_mime_types, mime_type) {
+        return Some(format!("attachments of type {mime_type} are denied"));
+    }
+
+    if !limits.allowed_mime_types.is_empty()
+        && !mime::matches_any(&limits.allowed_mime_types, mime_type)
+    {
+        return Some(format!("attachments of type {mime_type} are not allowed"));
+    }
+
+    if let Some(max) = limits.max_attachments.filter(|x| accepted.count >= *x) {
+        return Some(format!("the mail has more than {max} attachments"));
+    }
+
+    if let Some(max) = limits.max_attachment_size.filter(|x| size > *x) {
+        return Some(format!(
+            "the attachment is {size} bytes, more than the limit of {max} bytes"
+        ));
+    }
+
+    if let Some(max) = limits.max_total_size.filter(|x| accepted.size + size > *x) {
+        return Some(format!(
+            "the attachments of the mail exceed the limit of {max} bytes in total"
+        ));
+    }
+
+    None
+}
+
+/// Returns whether `key` is the key of an attachment, rather than that of a derived object or
+/// anything else in the bucket.
+fn is_attachment_key(key: &str) -> bool {
+    key.strip_prefix(ATTACHMENT_KEY_PREFIX).is_some_and(|hash| {
+        !hash.is_empty()
+            && hash
+                .bytes()
+                .all(|x| x.is_ascii_alphanumeric() || x == b'-' || x == b'_')
+    })
+}
+
+/// Records where an attachment came from in the metadata of the object, so it can be found
+/// again for takedowns.
+fn with_origin(
+    mut put_object: PutObjectFluentBuilder,
+    info: &MailInfo<'_>,
+) -> PutObjectFluentBuilder {
+    if let Some(message_id) = info.message_id.filter(|x| x.is_ascii()) {
+        put_object = put_object.metadata("message-id", message_id);
+    }
+
+    if let Some(sender) = info.sender.filter(|x| x.is_ascii()) {
+        put_object = put_object.metadata("sender", sender);
+    }
+
+    put_object
+}
+
+/// Returns the given `filename` with its extension replaced by `extension`.
+fn with_extension(filename: &str, extension: &str) -> String {
+    Path::new(filename)
+        .with_extension(extension)
+        .to_string_lossy()
+        .into_owned()
 }
 
 /// Returns the content disposition based on the given `content_type`.
@@ -256,3 +1080,91 @@ fn content_type_disposition(content_type: &str) -> &'static str {
         _ => "attachment",
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use figment::{
+        providers::{Format, Toml},
+        Figment,
+    };
+
+    use super::*;
+    use crate::config::IngestionConfig;
+
+    #[test]
+    fn limits_count_accepted_attachments_only() {
+        let limits = LimitsConfig {
+            denied_mime_types: vec!["application/x-msdownload".to_string()],
+            max_attachments: Some(2),
+            max_total_size: Some(100),
+            ..LimitsConfig::default()
+        };
+        let mut accepted = Accepted::default();
+
+        // Denied attachments don't count towards the maximum.
+        for _ in 0..3 {
+            assert!(check_limits(&limits, &accepted, "application/x-msdownload", 1).is_some());
+        }
+
+        assert_eq!(check_limits(&limits, &accepted, "image/png", 10), None);
+        accepted.add(10);
+        assert_eq!(check_limits(&limits, &accepted, "image/png", 90), None);
+        assert!(check_limits(&limits, &accepted, "image/png", 91).is_some());
+        accepted.add(10);
+        assert!(check_limits(&limits, &accepted, "image/png", 1).is_some());
+    }
+
+    #[test]
+    fn max_attachments_of_zero_skips_all() {
+        let limits = LimitsConfig {
+            max_attachments: Some(0),
+            ..LimitsConfig::default()
+        };
+
+        assert!(check_limits(&limits, &Accepted::default(), "image/png", 1).is_some());
+    }
+
+    #[tokio::test]
+    async fn accepts_unsanitized_gifs_by_default() {
+        let config: IngestionConfig = Figment::new()
+            .merge(Toml::string(r#"api_token = "secret""#))
+            .extract()
+            .unwrap();
+        let processors = postprocess::init(&[]).unwrap();
+        let mut gif = NamedTempFile::new().unwrap();
+
+        gif.write_all(b"GIF89a\x01\0\x01\0\0\0\0;").unwrap();
+
+        let pipeline = postprocess::run(&processors, gif.into_temp_path(), "image/gif").await;
+
+        assert!(pipeline.path.is_some());
+        assert!(!is_unsanitized(
+            &config.sanitized_mime_types,
+            pipeline.mime_type,
+            pipeline.sanitized
+        ));
+        assert!(is_unsanitized(
+            &config.sanitized_mime_types,
+            "image/jpeg",
+            false
+        ));
+    }
+
+    #[test]
+    fn attachment_keys() {
+        let hash = "47DEQpj8HBSa-_TImW-5JCeuQeRkm5NMpJWZG3hSuFU";
+
+        assert!(is_attachment_key(&format!("{ATTACHMENT_KEY_PREFIX}{hash}")));
+        assert!(!is_attachment_key(ATTACHMENT_KEY_PREFIX));
+        assert!(!is_attachment_key(&format!(
+            "{ATTACHMENT_KEY_PREFIX}{hash}.thumbnail.jpg"
+        )));
+        assert!(!is_attachment_key(&format!(
+            "{ATTACHMENT_KEY_PREFIX}../{hash}"
+        )));
+        assert!(!is_attachment_key(&format!("~meta/quarantine/{hash}")));
+        assert!(!is_attachment_key("index.html"));
+    }
+}
@@ -2,11 +2,16 @@ use std::net::SocketAddr;
 
 use axum::{
     extract::{DefaultBodyLimit, FromRequestParts},
-    http::{header::AUTHORIZATION, request::Parts, StatusCode},
-    response::IntoResponse,
+    http::{
+        header::{AUTHORIZATION, WWW_AUTHENTICATE},
+        request::Parts,
+        StatusCode,
+    },
+    response::{IntoResponse, Response},
     routing::get,
     Router,
 };
+use base64::prelude::{Engine, BASE64_STANDARD};
 use listenfd::ListenFd;
 use miette::IntoDiagnostic;
 use tokio::{net::TcpListener, signal};
@@ -16,7 +21,7 @@ use tower_http::{
 };
 use tracing::{debug, instrument};
 
-use crate::api;
+use crate::{api, config::GalleryConfig, gallery};
 
 pub struct AuthToken(pub String);
 
@@ -43,6 +48,46 @@ where
     }
 }
 
+/// The password of HTTP basic authentication, for pages that are visited with a browser.
+pub struct BasicAuthPassword(pub String);
+
+impl<S> FromRequestParts<S> for BasicAuthPassword
+where
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let password = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|x| x.as_bytes().strip_prefix(b"Basic "))
+            .and_then(|x| BASE64_STANDARD.decode(x).ok())
+            .and_then(|x| {
+                let credentials = String::from_utf8_lossy(&x).into_owned();
+
+                credentials
+                    .split_once(':')
+                    .map(|(_, password)| password.to_string())
+            });
+
+        match password {
+            Some(password) => Ok(BasicAuthPassword(password)),
+            None => Err(unauthorized()),
+        }
+    }
+}
+
+/// Returns a response that asks the browser for credentials.
+pub fn unauthorized() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        [(WWW_AUTHENTICATE, r#"Basic realm="meta-mail-ingress""#)],
+        "401 unauthorized",
+    )
+        .into_response()
+}
+
 #[instrument]
 async fn not_found() -> impl IntoResponse {
     (StatusCode::NOT_FOUND, "404 page not found")
@@ -80,12 +125,20 @@ pub async fn healthcheck() -> (StatusCode, &'static str) {
 }
 
 #[instrument(skip_all)]
-pub async fn start_server(state: crate::AppState) -> miette::Result<()> {
+pub async fn start_server(
+    state: crate::AppState,
+    gallery_config: &GalleryConfig,
+) -> miette::Result<()> {
     debug!("starting http server");
 
     let api_v1_router = api::v1::router();
-    let app = Router::new()
-        .nest("/api/v1", api_v1_router)
+    let mut app = Router::new().nest("/api/v1", api_v1_router);
+
+    if gallery_config.enabled {
+        app = app.merge(gallery::router(gallery_config));
+    }
+
+    let app = app
         .route("/livez", get(healthcheck))
         .route("/readyz", get(healthcheck))
         .with_state(state)
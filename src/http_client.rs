@@ -0,0 +1,234 @@
+use std::{error::Error as _, time::Duration};
+
+use reqwest::{IntoUrl, RequestBuilder, Response};
+use tracing::warn;
+
+use crate::{config::HttpConfig, Error};
+
+/// The delay before the first retry of a failed request, which doubles with every retry.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// The maximum delay between two attempts of a request.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// The HTTP client shared by everything that sends requests to other services.
+///
+/// Cloning it is cheap, and the clones share their connection pool.
+#[derive(Debug, Clone)]
+pub struct HttpClient {
+    /// The configured client.
+    client: reqwest::Client,
+    /// The number of times failed requests are retried.
+    max_retries: u32,
+}
+
+impl HttpClient {
+    /// Returns the client described by `config`.
+    pub fn new(config: &HttpConfig) -> Result<Self, Error> {
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(config.connect_timeout))
+            .timeout(Duration::from_secs(config.timeout))
+            .user_agent(&config.user_agent);
+
+        if let Some(ref proxy) = config.proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy.as_str())?);
+        }
+
+        Ok(HttpClient {
+            client: builder.build()?,
+            max_retries: config.max_retries,
+        })
+    }
+
+    /// Returns the underlying client, for callers that handle failures themselves.
+    pub fn client(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    /// Starts a `GET` request to `url`.
+    pub fn get(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.client.get(url)
+    }
+
+    /// Starts a `POST` request to `url`.
+    pub fn post(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.client.post(url)
+    }
+
+    /// Starts a `PUT` request to `url`.
+    pub fn put(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.client.put(url)
+    }
+
+    /// Sends the `request` and returns the response if it has a success status.
+    ///
+    /// Requests that can't connect or get a server error are retried with jittered exponential
+    /// backoff, unless their body is streamed. Requests that time out are only retried if their
+    /// method is idempotent, as the server may have handled them already.
+    pub async fn send(&self, request: RequestBuilder) -> Result<Response, Error> {
+        let request = request.build().map_err(|e| {
+            let url = e.url().map(ToString::to_string).unwrap_or_default();
+
+            Error::HttpRequestFailed(url, describe(e))
+        })?;
+        let url = request.url().to_string();
+        let idempotent = request.method().is_idempotent();
+        let mut backoff = INITIAL_BACKOFF;
+        let mut attempt = 0;
+
+        loop {
+            attempt += 1;
+
+            let retry = if attempt > self.max_retries {
+                None
+            } else {
+                request.try_clone()
+            };
+
+            let Some(current) = retry else {
+                return check(&url, self.client.execute(request).await);
+            };
+
+            let result = self.client.execute(current).await;
+            let reason = match result {
+                Ok(ref res) if res.status().is_server_error() => {
+                    format!("the server responded with {}", res.status())
+                }
+                Err(err) if err.is_connect() || (idempotent && err.is_timeout()) => describe(err),
+                result => return check(&url, result),
+            };
+            let delay = jitter(backoff);
+
+            warn!(%url, %attempt, %reason, ?delay, "request failed, retrying");
+
+            tokio::time::sleep(delay).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+}
+
+/// Returns the response of a request to `url` if it has a success status, or the reason it
+/// failed.
+fn check(url: &str, result: Result<Response, reqwest::Error>) -> Result<Response, Error> {
+    match result {
+        Ok(res) if res.status().is_success() => Ok(res),
+        Ok(res) => Err(Error::HttpRequestFailed(
+            url.to_string(),
+            format!("the server responded with {}", res.status()),
+        )),
+        Err(err) => Err(Error::HttpRequestFailed(url.to_string(), describe(err))),
+    }
+}
+
+/// Returns the description of `err` along with its causes, without the URL of the request.
+fn describe(err: reqwest::Error) -> String {
+    let err = err.without_url();
+    let mut description = err.to_string();
+    let mut source = err.source();
+
+    while let Some(err) = source {
+        let cause = err.to_string();
+
+        // Wrapping errors often repeat the description of their cause.
+        if !description.ends_with(&cause) {
+            description.push_str(": ");
+            description.push_str(&cause);
+        }
+
+        source = err.source();
+    }
+
+    description
+}
+
+/// Returns a random delay between half of `backoff` and `backoff`, so that clients retrying at
+/// the same time spread out.
+fn jitter(backoff: Duration) -> Duration {
+    let half = backoff / 2;
+
+    half + half.mul_f64(fastrand::f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use axum::{http::StatusCode, routing::any, Router};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Starts a server that fails the first `failures` requests with `failure` and answers the
+    /// others, and returns its URL along with the number of requests it received.
+    async fn server(
+        failures: usize,
+        failure: StatusCode,
+        delay: Duration,
+    ) -> (String, Arc<AtomicUsize>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&requests);
+        let app = Router::new().route(
+            "/",
+            any(move || async move {
+                let request = counter.fetch_add(1, Ordering::Relaxed);
+
+                tokio::time::sleep(delay).await;
+
+                if request < failures {
+                    failure
+                } else {
+                    StatusCode::OK
+                }
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        (url, requests)
+    }
+
+    fn client(timeout: u64) -> HttpClient {
+        HttpClient::new(&HttpConfig {
+            timeout,
+            max_retries: 1,
+            ..HttpConfig::default()
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors() {
+        let (url, requests) = server(1, StatusCode::BAD_GATEWAY, Duration::ZERO).await;
+        let http = client(5);
+
+        http.send(http.post(&url)).await.unwrap();
+
+        assert_eq!(requests.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn doesnt_retry_client_errors() {
+        let (url, requests) = server(1, StatusCode::BAD_REQUEST, Duration::ZERO).await;
+        let http = client(5);
+
+        assert!(http.send(http.put(&url)).await.is_err());
+        assert_eq!(requests.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn retries_only_idempotent_requests_on_timeouts() {
+        let (url, requests) = server(0, StatusCode::OK, Duration::from_secs(2)).await;
+        let http = client(1);
+
+        assert!(http.send(http.post(&url)).await.is_err());
+        assert_eq!(requests.load(Ordering::Relaxed), 1);
+
+        assert!(http.send(http.put(&url)).await.is_err());
+        assert_eq!(requests.load(Ordering::Relaxed), 3);
+    }
+}
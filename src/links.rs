@@ -0,0 +1,201 @@
+use linkify::{LinkFinder, LinkKind};
+use mail_parser::Message;
+use url::{form_urlencoded, Url};
+
+use crate::config::LinksConfig;
+
+/// Query parameters that only serve to track who followed a link.
+const TRACKING_PARAMETERS: &[&str] = &[
+    "fbclid",
+    "gclid",
+    "dclid",
+    "gbraid",
+    "wbraid",
+    "msclkid",
+    "yclid",
+    "igshid",
+    "mc_cid",
+    "mc_eid",
+    "mkt_tok",
+    "_hsenc",
+    "_hsmi",
+    "oly_anon_id",
+    "oly_enc_id",
+    "vero_id",
+];
+
+/// Returns the HTTP(S) URLs in the visible text of the body of `mail`, in order of appearance
+/// and without duplicates, tracking parameters or denied domains.
+///
+/// The targets of HTML links are ignored unless they appear in the text as well, so the
+/// unsubscribe and tracking links hidden behind link texts aren't extracted.
+pub fn extract(mail: &Message<'_>, config: &LinksConfig) -> Vec<Url> {
+    let Some(text) = mail.body_text(0) else {
+        return vec![];
+    };
+
+    let mut finder = LinkFinder::new();
+    let mut urls: Vec<Url> = vec![];
+
+    finder.kinds(&[LinkKind::Url]);
+
+    for candidate in finder.links(&text) {
+        if urls.len() >= config.max_links {
+            break;
+        }
+
+        let Some(url) = normalize(candidate.as_str()) else {
+            continue;
+        };
+
+        if is_denied(&url, &config.denied_domains) || urls.contains(&url) {
+            continue;
+        }
+
+        urls.push(url);
+    }
+
+    urls
+}
+
+/// Parses `candidate` as an HTTP(S) URL and strips its tracking parameters.
+fn normalize(candidate: &str) -> Option<Url> {
+    let mut url = Url::parse(candidate.trim()).ok()?;
+
+    if !matches!(url.scheme(), "http" | "https") || url.host_str().is_none() {
+        return None;
+    }
+
+    let Some(query) = url.query() else {
+        return Some(url);
+    };
+    // The kept parameters are carried over as they were, as re-encoding them would turn `?id`
+    // into `?id=` and `%20` into `+`.
+    let parameters: Vec<&str> = query.split('&').collect();
+    let kept: Vec<&str> = parameters
+        .iter()
+        .copied()
+        .filter(|parameter| {
+            form_urlencoded::parse(parameter.as_bytes())
+                .next()
+                .is_none_or(|(name, _)| !is_tracking_parameter(&name))
+        })
+        .collect();
+
+    if kept.len() < parameters.len() {
+        let query = kept.join("&");
+
+        url.set_query(Some(query.as_str()).filter(|x| !x.is_empty()));
+    }
+
+    Some(url)
+}
+
+/// Returns whether the query parameter `name` is used for tracking.
+fn is_tracking_parameter(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+
+    name.starts_with("utm_") || TRACKING_PARAMETERS.contains(&name.as_str())
+}
+
+/// Returns whether the host of `url` is one of the `denied` domains or a subdomain of one.
+fn is_denied(url: &Url, denied: &[String]) -> bool {
+    let Some(host) = url.host_str() else {
+        return true;
+    };
+
+    denied.iter().any(|domain| {
+        let domain = domain.trim_start_matches('.');
+
+        host.eq_ignore_ascii_case(domain)
+            || host
+                .to_ascii_lowercase()
+                .ends_with(&format!(".{}", domain.to_ascii_lowercase()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use mail_parser::MessageParser;
+
+    use super::*;
+
+    fn extract_from(raw: &str, config: &LinksConfig) -> Vec<String> {
+        let mail = MessageParser::default().parse(raw.as_bytes()).unwrap();
+
+        extract(&mail, config)
+            .into_iter()
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn normalizes_urls() {
+        let url = normalize(" https://example.com/a?utm_source=x&id=1&FBCLID=2 ").unwrap();
+
+        assert_eq!(url.as_str(), "https://example.com/a?id=1");
+        assert_eq!(
+            normalize("http://example.com/?utm_medium=mail")
+                .unwrap()
+                .as_str(),
+            "http://example.com/"
+        );
+        assert!(normalize("mailto:a@example.com").is_none());
+        assert!(normalize("javascript:alert(1)").is_none());
+        assert!(normalize("file:///etc/passwd").is_none());
+        assert!(normalize("not a url").is_none());
+    }
+
+    #[test]
+    fn keeps_the_remaining_query_as_it_was() {
+        let normalized = |x| normalize(x).unwrap().to_string();
+
+        assert_eq!(
+            normalized("https://example.com/?id&q=a%20b"),
+            "https://example.com/?id&q=a%20b"
+        );
+        assert_eq!(
+            normalized("https://example.com/?id&utm_source=x&q=a%20b"),
+            "https://example.com/?id&q=a%20b"
+        );
+    }
+
+    #[test]
+    fn denies_domains_and_subdomains() {
+        let denied = ["example.com".to_string(), ".example.org".to_string()];
+        let url = |x| Url::parse(x).unwrap();
+
+        assert!(is_denied(&url("https://example.com/"), &denied));
+        assert!(is_denied(&url("https://WWW.Example.com/"), &denied));
+        assert!(is_denied(&url("https://a.example.org/"), &denied));
+        assert!(!is_denied(&url("https://notexample.com/"), &denied));
+    }
+
+    #[test]
+    fn extracts_visible_urls_only() {
+        let raw = "Content-Type: text/html\r\n\r\n\
+                   <p>See https://example.com/a?utm_source=x and \
+                   <a href=\"https://example.com/b\">https://example.com/b</a>.</p>\
+                   <a href=\"https://tracker.example.net/unsubscribe\">Unsubscribe</a>";
+        let urls = extract_from(raw, &LinksConfig::default());
+
+        assert_eq!(urls, ["https://example.com/a", "https://example.com/b"]);
+    }
+
+    #[test]
+    fn limits_the_number_of_urls() {
+        let raw = "Subject: x\r\n\r\nhttps://a.example/ https://a.example/ https://b.example/ \
+                   https://c.example/\r\n";
+        let config = |max_links| LinksConfig {
+            enabled: true,
+            max_links,
+            ..LinksConfig::default()
+        };
+
+        assert_eq!(
+            extract_from(raw, &config(2)),
+            ["https://a.example/", "https://b.example/"]
+        );
+        assert!(extract_from(raw, &config(0)).is_empty());
+    }
+}
@@ -10,13 +10,26 @@ use miette::IntoDiagnostic;
 use tokio::sync::Mutex;
 
 mod api;
+mod archive;
+mod body;
 mod cli;
 mod config;
 mod error;
+mod gallery;
 mod handler;
 mod http;
+mod http_client;
+mod links;
+mod mime;
+mod notifier;
+mod notify;
+mod parts;
 mod postprocess;
+mod report;
+mod scan;
+mod store;
 mod tracing;
+mod webhook;
 
 pub use config::Config;
 pub use error::Error;
@@ -57,19 +70,40 @@ async fn main() -> miette::Result<()> {
 
     let sdk_config = load_aws_config(&config.aws).await;
     let s3_client = aws_s3::Client::new(&sdk_config);
-    let postprocessors = postprocess::init()?;
+    let postprocessors = postprocess::init(&config.postprocess)?;
+    let store = store::Store::open(&config.store.path)?;
+    let scanner = scan::Scanner::new(&config.scan, &config.aws.s3_config.bucket_name)?;
+    let http_client = http_client::HttpClient::new(&config.http)?;
+    let webhooks = webhook::Webhooks::start(&config.webhooks, &config.store.path, &http_client)?;
+    let notifiers = notifier::Notifiers::start(notifier::init(
+        &config.meta_webhook,
+        &config.notifications,
+        &config.notifiers,
+        &http_client,
+    )?);
     let mail_handler = Arc::new(Mutex::new(MailHandler::new(
         s3_client,
         config.aws.s3_config.clone(),
-        config.meta_webhook.token,
+        notifiers,
         postprocessors,
+        store,
+        scanner,
+        config.limits,
+        config.archives,
+        config.parts,
+        config.bodies,
+        config.links,
+        config.notifications,
+        webhooks,
+        config.ingestion.reject_unsanitized,
+        config.ingestion.sanitized_mime_types,
     )));
     let app_state = AppState {
         api_token: config.ingestion.api_token,
         mail_handler,
     };
 
-    http::start_server(app_state).await?;
+    http::start_server(app_state, &config.gallery).await?;
 
     Ok(())
 }
@@ -0,0 +1,243 @@
+/// Returns whether the `mime_type` matches the glob `pattern`, e.g. `image/*`.
+///
+/// A `*` matches any sequence of characters, including none. Matching ignores case.
+pub fn matches(pattern: &str, mime_type: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    let mime_type = mime_type.to_ascii_lowercase();
+    let mut parts = pattern.split('*');
+
+    // There is always at least one part, even for an empty pattern.
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = mime_type.strip_prefix(first) else {
+        return false;
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
+
+/// Returns whether the `mime_type` matches any of the glob `patterns`.
+pub fn matches_any<S: AsRef<str>>(patterns: &[S], mime_type: &str) -> bool {
+    patterns.iter().any(|x| matches(x.as_ref(), mime_type))
+}
+
+/// MIME types sniffing falls back to when it can't tell anything more specific.
+pub const GENERIC_TYPES: &[&str] = &["application/octet-stream", "text/plain"];
+
+/// MIME types browsers execute or render as documents, which are never taken from what a mail
+/// declares.
+const ACTIVE_TYPES: &[&str] = &[
+    "text/html",
+    "text/javascript",
+    "text/xml",
+    "application/javascript",
+    "application/xhtml+xml",
+    "application/xml",
+    "image/svg+xml",
+];
+
+/// Container types along with glob patterns of the more specific types built on them, which
+/// sniffing can't always tell apart from the container.
+///
+/// Office Open XML documents and Java archives, for example, are only sniffed as ZIP archives.
+const SUBCLASSES: &[(&str, &[&str])] = &[
+    (
+        "application/zip",
+        &[
+            "application/vnd.openxmlformats-officedocument.*",
+            "application/vnd.oasis.opendocument.*",
+            "application/vnd.android.package-archive",
+            "application/java-archive",
+            "application/x-java-archive",
+            "application/*+zip",
+        ],
+    ),
+    ("application/xml", &["*+xml"]),
+    ("text/xml", &["*+xml"]),
+    ("text/plain", &["text/*"]),
+];
+
+/// Returns whether `mime_type` is a more specific type of the container type `parent`.
+pub fn is_subclass(mime_type: &str, parent: &str) -> bool {
+    mime_type != parent
+        && SUBCLASSES.iter().any(|(container, subclasses)| {
+            *container == parent && matches_any(subclasses, mime_type)
+        })
+}
+
+/// Returns whether the types `a` and `b` describe compatible contents, i.e. they are the same or
+/// one is a more specific type of the other.
+pub fn compatible(a: &str, b: &str) -> bool {
+    a == b || is_subclass(a, b) || is_subclass(b, a)
+}
+
+/// The MIME type of an attachment, detected from its contents, declared `Content-Type` and
+/// filename.
+#[derive(Debug, Clone)]
+pub struct Detection {
+    /// The MIME type the attachment is handled as.
+    pub mime_type: &'static str,
+    /// The MIME type sniffed from the contents.
+    pub sniffed: &'static str,
+    /// The declared `Content-Type`, if any.
+    pub declared: Option<String>,
+    /// Whether the declared type or the filename extension contradicts the contents.
+    pub mismatch: bool,
+}
+
+/// Detects the MIME type of an attachment with the given `contents`, `declared` type and
+/// `filename`.
+///
+/// The sniffed type is used unless it is generic or a container, in which case a more specific
+/// declared or guessed type is preferred.
+pub fn detect(contents: &[u8], declared: Option<&str>, filename: Option<&str>) -> Detection {
+    let sniffed = tree_magic_mini::from_u8(contents);
+    let declared = declared.map(str::to_ascii_lowercase);
+    let guessed = filename.and_then(|x| mime_guess::from_path(x).first_raw());
+    let candidates = || {
+        declared
+            .as_deref()
+            .and_then(known)
+            .into_iter()
+            .chain(guessed)
+            .filter(|x| !GENERIC_TYPES.contains(x))
+    };
+
+    let mismatch = !GENERIC_TYPES.contains(&sniffed)
+        && candidates().any(|x| !compatible(x, sniffed) && !tree_magic_mini::match_u8(x, contents));
+    let mime_type = match sniffed {
+        "application/octet-stream" => candidates().find(|x| !ACTIVE_TYPES.contains(x)),
+        _ => candidates().find(|x| is_subclass(x, sniffed) && !ACTIVE_TYPES.contains(x)),
+    };
+
+    Detection {
+        mime_type: mime_type.unwrap_or(sniffed),
+        sniffed,
+        declared,
+        mismatch,
+    }
+}
+
+/// Returns the static representation of the given `mime_type` if it is a known type.
+fn known(mime_type: &str) -> Option<&'static str> {
+    mime_guess::get_mime_extensions_str(mime_type)?
+        .iter()
+        .find_map(|ext| {
+            mime_guess::from_ext(ext)
+                .iter_raw()
+                .find(|x| *x == mime_type)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use zip::{write::SimpleFileOptions, ZipWriter};
+
+    use super::*;
+
+    const DOCX: &str = "application/vnd.openxmlformats-officedocument.wordprocessingml.document";
+
+    /// Returns a ZIP archive with the given entries.
+    fn zip(entries: &[(&str, &str)]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(vec![]));
+
+        for (name, contents) in entries {
+            writer
+                .start_file(*name, SimpleFileOptions::default())
+                .unwrap();
+            writer.write_all(contents.as_bytes()).unwrap();
+        }
+
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn matches_globs() {
+        assert!(matches("image/*", "image/png"));
+        assert!(matches("IMAGE/*", "image/PNG"));
+        assert!(matches("*", "application/pdf"));
+        assert!(matches("application/*+xml", "application/atom+xml"));
+        assert!(matches("application/pdf", "application/pdf"));
+        assert!(!matches("application/pdf", "application/pdfx"));
+        assert!(!matches("image/*", "video/mp4"));
+        assert!(!matches("application/*+xml", "application/xml"));
+        assert!(!matches("", "text/plain"));
+        assert!(matches_any(&["video/*", "image/*"], "image/gif"));
+        assert!(!matches_any::<&str>(&[], "image/gif"));
+    }
+
+    #[test]
+    fn relates_containers_and_subclasses() {
+        assert!(is_subclass(DOCX, "application/zip"));
+        assert!(is_subclass("application/epub+zip", "application/zip"));
+        assert!(is_subclass("text/csv", "text/plain"));
+        assert!(!is_subclass("application/zip", DOCX));
+        assert!(!is_subclass("application/zip", "application/zip"));
+        assert!(compatible("application/zip", DOCX));
+        assert!(compatible(DOCX, "application/zip"));
+        assert!(!compatible("image/png", "application/zip"));
+    }
+
+    #[test]
+    fn prefers_sniffed_types() {
+        let detection = detect(b"\x89PNG\r\n\x1a\n", Some("image/jpeg"), Some("a.jpg"));
+
+        assert_eq!(detection.mime_type, "image/png");
+        assert!(detection.mismatch);
+    }
+
+    #[test]
+    fn refines_generic_types() {
+        let detection = detect(b"a,b\n1,2\n", Some("text/csv"), Some("a.csv"));
+
+        assert_eq!(detection.mime_type, "text/csv");
+        assert!(!detection.mismatch);
+
+        let detection = detect(b"\x00\x01\x02", None, Some("a.pdf"));
+
+        assert_eq!(detection.mime_type, "application/pdf");
+    }
+
+    #[test]
+    fn never_declares_active_types() {
+        let detection = detect(b"hello", Some("text/html"), Some("a.html"));
+
+        assert_eq!(detection.mime_type, "text/plain");
+    }
+
+    #[test]
+    fn office_documents_are_no_mismatch() {
+        let contents = zip(&[("[Content_Types].xml", "<Types/>")]);
+        let detection = detect(&contents, Some(DOCX), Some("report.docx"));
+
+        assert_eq!(detection.sniffed, "application/zip");
+        assert_eq!(detection.mime_type, DOCX);
+        assert!(!detection.mismatch);
+
+        let detection = detect(&contents, None, Some("app.jar"));
+
+        assert_eq!(detection.mime_type, "application/java-archive");
+        assert!(!detection.mismatch);
+    }
+
+    #[test]
+    fn archives_posing_as_images_are_a_mismatch() {
+        let detection = detect(&zip(&[("a.txt", "hello")]), Some("image/png"), None);
+
+        assert_eq!(detection.mime_type, "application/zip");
+        assert!(detection.mismatch);
+    }
+}
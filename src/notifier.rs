@@ -0,0 +1,297 @@
+use core::fmt::Debug;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{debug, error};
+
+use crate::{
+    config::{MetaWebhookConfig, NotificationFormat, NotificationsConfig, NotifierConfig},
+    http_client::HttpClient,
+    notify::{Notification, Templates},
+    Error,
+};
+
+mod discord;
+mod irc;
+mod matrix;
+mod meta_webhook;
+mod slack;
+
+/// The maximum number of notifications queued for a notifier, beyond which new ones are
+/// dropped.
+const QUEUE_CAPACITY: usize = 1024;
+
+#[async_trait]
+pub trait Notifier: Send + Sync {
+    /// Returns the name of the notifier.
+    fn name(&self) -> &str;
+
+    /// Renders the `notification` and sends it.
+    async fn send(&self, notification: &Notification) -> Result<(), Error>;
+}
+
+impl Debug for dyn Notifier {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Notifier{{name: {}}}", self.name())
+    }
+}
+
+/// Sends notifications to the notifiers in the background.
+///
+/// Each notifier has its own bounded queue, so a slow or failing chat service doesn't hold up
+/// the others or the ingestion of mails.
+#[derive(Debug, Clone, Default)]
+pub struct Notifiers {
+    queues: Vec<Queue>,
+}
+
+/// The queue of the notifications to send with a notifier.
+#[derive(Debug, Clone)]
+struct Queue {
+    /// The name of the notifier.
+    name: String,
+    /// The sender of the queue.
+    sender: mpsc::Sender<Arc<Notification>>,
+}
+
+impl Notifiers {
+    /// Starts a task for each of the `notifiers`, which sends the notifications queued for it
+    /// one after another.
+    pub fn start(notifiers: Vec<Box<dyn Notifier>>) -> Self {
+        let mut queues = vec![];
+
+        for notifier in notifiers {
+            let (sender, mut receiver) = mpsc::channel::<Arc<Notification>>(QUEUE_CAPACITY);
+
+            queues.push(Queue {
+                name: notifier.name().to_string(),
+                sender,
+            });
+
+            tokio::spawn(async move {
+                while let Some(notification) = receiver.recv().await {
+                    // Notifications are best effort, so failures are only logged.
+                    if let Err(err) = notifier.send(&notification).await {
+                        error!(%err, notifier = notifier.name(), kind = ?notification.kind, "could not send notification");
+                    }
+                }
+            });
+        }
+
+        Notifiers { queues }
+    }
+
+    /// Queues the `notification` for every notifier.
+    pub fn send(&self, notification: Notification) {
+        let notification = Arc::new(notification);
+
+        for queue in &self.queues {
+            match queue.sender.try_send(Arc::clone(&notification)) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    error!(notifier = %queue.name, kind = ?notification.kind, "dropping notification as its queue is full");
+                }
+                Err(TrySendError::Closed(_)) => {
+                    error!(notifier = %queue.name, kind = ?notification.kind, "could not queue notification as its task stopped");
+                }
+            }
+        }
+    }
+}
+
+/// Returns the notifiers for the meta webhook, unless it is disabled, and the chat services
+/// described by `configs`, which send their requests with `http`.
+pub fn init(
+    meta_webhook: &MetaWebhookConfig,
+    notifications: &NotificationsConfig,
+    configs: &[NotifierConfig],
+    http: &HttpClient,
+) -> Result<Vec<Box<dyn Notifier>>, Error> {
+    debug!("initializing notifiers");
+
+    let mut notifiers: Vec<Box<dyn Notifier>> = vec![];
+
+    if meta_webhook.enabled {
+        let templates = Templates::new(meta_webhook.format, &meta_webhook.templates)?;
+
+        notifiers.push(Box::new(meta_webhook::MetaWebhook::new(
+            &meta_webhook.token,
+            templates,
+            notifications.max_line_length,
+            http.clone(),
+        )));
+    }
+
+    for config in configs {
+        let notifier: Box<dyn Notifier> = match config {
+            NotifierConfig::Matrix(config) => {
+                let format = config.format.unwrap_or(NotificationFormat::Plain);
+                let templates = Templates::new(format, &config.templates)?;
+
+                Box::new(matrix::Matrix::new(config, templates, http.clone()))
+            }
+            NotifierConfig::Slack(config) => {
+                let format = config.format.unwrap_or(NotificationFormat::Plain);
+                let templates = Templates::new(format, &config.templates)?;
+
+                Box::new(slack::Slack::new(config, templates, http.clone()))
+            }
+            NotifierConfig::Discord(config) => {
+                let format = config.format.unwrap_or(NotificationFormat::Markdown);
+                let templates = Templates::new(format, &config.templates)?;
+
+                Box::new(discord::Discord::new(config, templates, http.clone()))
+            }
+            NotifierConfig::Irc(config) => {
+                let format = config.format.unwrap_or(NotificationFormat::Irc);
+                let templates = Templates::new(format, &config.templates)?;
+
+                Box::new(irc::Irc::new(
+                    config,
+                    templates,
+                    notifications.max_line_length,
+                ))
+            }
+        };
+
+        debug!(name = notifier.name(), "enabling notifier");
+
+        notifiers.push(notifier);
+    }
+
+    Ok(notifiers)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{
+        body::Bytes,
+        http::{Method, Uri},
+        response::{IntoResponse, Response},
+        Router,
+    };
+    use tokio::{net::TcpListener, sync::mpsc};
+
+    use crate::{config::HttpConfig, handler::MailInfo, notify::Kind};
+
+    use super::*;
+
+    /// A request received by a [`server`].
+    #[derive(Debug)]
+    pub struct Request {
+        pub method: Method,
+        pub path: String,
+        pub body: Bytes,
+    }
+
+    impl Request {
+        /// Returns the body of the request parsed as JSON.
+        pub fn json(&self) -> serde_json::Value {
+            serde_json::from_slice(&self.body).unwrap()
+        }
+    }
+
+    /// Starts a server answering every request with `respond`, and returns its URL along with
+    /// the requests it receives.
+    pub async fn server(
+        respond: impl Fn(&Request) -> Response + Send + Sync + 'static,
+    ) -> (String, mpsc::UnboundedReceiver<Request>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let respond = Arc::new(respond);
+        let app = Router::new().fallback(move |method: Method, uri: Uri, body: Bytes| {
+            let request = Request {
+                method,
+                path: uri.to_string(),
+                body,
+            };
+            let response = respond(&request);
+
+            sender.send(request).unwrap();
+
+            async move { response }
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        (url, receiver)
+    }
+
+    /// Answers a request with an empty JSON object.
+    pub fn ok(_: &Request) -> Response {
+        "{}".into_response()
+    }
+
+    /// Returns an HTTP client for tests.
+    pub fn http() -> HttpClient {
+        HttpClient::new(&HttpConfig::default()).unwrap()
+    }
+
+    /// Records the kinds of the notifications it sends, after waiting for `delay`.
+    struct Recorder {
+        delay: Duration,
+        sent: mpsc::UnboundedSender<Kind>,
+    }
+
+    #[async_trait]
+    impl Notifier for Recorder {
+        fn name(&self) -> &str {
+            "recorder"
+        }
+
+        async fn send(&self, notification: &Notification) -> Result<(), Error> {
+            tokio::time::sleep(self.delay).await;
+            self.sent.send(notification.kind).unwrap();
+
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_notifications_in_the_background() {
+        let (sent, mut slow) = mpsc::unbounded_channel();
+        let slow_notifier = Recorder {
+            delay: Duration::from_secs(60),
+            sent,
+        };
+        let (sent, mut fast) = mpsc::unbounded_channel();
+        let fast_notifier = Recorder {
+            delay: Duration::ZERO,
+            sent,
+        };
+        let notifiers = Notifiers::start(vec![Box::new(slow_notifier), Box::new(fast_notifier)]);
+        let started = std::time::Instant::now();
+
+        notifiers.send(notification("https://example.org/a.jpg", None));
+        notifiers.send(Notification::link(
+            "https://example.org/",
+            &MailInfo::default(),
+        ));
+
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(fast.recv().await, Some(Kind::Attachment));
+        assert_eq!(fast.recv().await, Some(Kind::Link));
+        assert!(slow.try_recv().is_err());
+    }
+
+    /// Returns a notification about an image stored at `url`, with the given `thumbnail`.
+    pub fn notification(url: &str, thumbnail: Option<String>) -> Notification {
+        let info = MailInfo {
+            sender: Some("eve@example.org"),
+            subject: Some("<!channel> @everyone hi"),
+            ..MailInfo::default()
+        };
+
+        Notification {
+            kind: Kind::Attachment,
+            filename: Some("a.jpg".to_string()),
+            mime: Some("image/jpeg".to_string()),
+            thumbnail,
+            ..Notification::link(url, &info)
+        }
+    }
+}
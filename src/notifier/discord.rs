@@ -0,0 +1,147 @@
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use tracing::{debug, instrument};
+use url::Url;
+
+use super::Notifier;
+use crate::{
+    config::DiscordConfig,
+    http_client::HttpClient,
+    notify::{Notification, Templates},
+    Error,
+};
+
+/// The maximum length of the content of a Discord message, in characters.
+const MAX_CONTENT_LENGTH: usize = 2000;
+
+/// Sends notifications to a Discord channel through a webhook, with embeds previewing images.
+pub struct Discord {
+    /// The URL of the webhook.
+    webhook_url: Url,
+    /// The name messages are posted under, if overridden.
+    username: Option<String>,
+    /// The templates notifications are rendered with.
+    templates: Templates,
+    /// The HTTP client.
+    http: HttpClient,
+}
+
+impl Discord {
+    pub fn new(config: &DiscordConfig, templates: Templates, http: HttpClient) -> Self {
+        Discord {
+            webhook_url: config.webhook_url.clone(),
+            username: config.username.clone(),
+            templates,
+            http,
+        }
+    }
+}
+
+#[async_trait]
+impl Notifier for Discord {
+    fn name(&self) -> &str {
+        "discord"
+    }
+
+    #[instrument(skip_all)]
+    async fn send(&self, notification: &Notification) -> Result<(), Error> {
+        let content = truncate(self.templates.render(notification)?, MAX_CONTENT_LENGTH);
+        let mut payload = json!({
+            "content": content,
+            // Mails are untrusted, so their contents mustn't be able to ping anyone.
+            "allowed_mentions": { "parse": [] }
+        });
+
+        if let Some(ref username) = self.username {
+            payload["username"] = json!(username);
+        }
+
+        if let Some(preview) = notification.preview() {
+            payload["embeds"] = json!([embed(preview, notification)]);
+        }
+
+        let request = self.http.post(self.webhook_url.clone()).json(&payload);
+
+        self.http.send(request).await?;
+
+        debug!("sent discord message");
+
+        Ok(())
+    }
+}
+
+/// Returns the embed showing the image at `url` previewing the object of `notification`.
+fn embed(url: &str, notification: &Notification) -> Value {
+    json!({
+        "title": truncate(notification.filename.clone().unwrap_or_else(|| "attachment".to_string()), 256),
+        "url": notification.url,
+        "image": { "url": url }
+    })
+}
+
+/// Returns `text` truncated to at most `length` characters.
+fn truncate(mut text: String, length: usize) -> String {
+    if let Some((idx, _)) = text.char_indices().nth(length) {
+        text.truncate(idx);
+    }
+
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use axum::http::Method;
+
+    use crate::{
+        config::NotificationFormat,
+        notifier::tests::{http, notification, ok, server},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn posts_messages_without_mentions_and_with_embeds() {
+        let (url, mut requests) = server(ok).await;
+        let config = DiscordConfig {
+            webhook_url: format!("{url}/hook").parse().unwrap(),
+            username: Some("ingress".to_string()),
+            format: None,
+            templates: HashMap::new(),
+        };
+        let templates = Templates::new(NotificationFormat::Markdown, &HashMap::new()).unwrap();
+        let notification = notification(
+            "https://files.example.org/a.jpg",
+            Some("https://files.example.org/thumbnails/a.jpg".to_string()),
+        );
+
+        Discord::new(&config, templates, http())
+            .send(&notification)
+            .await
+            .unwrap();
+
+        let request = requests.recv().await.unwrap();
+        let payload = request.json();
+
+        assert_eq!(request.method, Method::POST);
+        assert_eq!(request.path, "/hook");
+        assert!(payload["content"].as_str().unwrap().contains("\\@everyone"));
+        assert_eq!(payload["allowed_mentions"], json!({ "parse": [] }));
+        assert_eq!(payload["username"], "ingress");
+        assert_eq!(
+            payload["embeds"],
+            json!([{
+                "title": "a.jpg",
+                "url": "https://files.example.org/a.jpg",
+                "image": { "url": "https://files.example.org/thumbnails/a.jpg" }
+            }])
+        );
+    }
+
+    #[test]
+    fn truncates_at_character_boundaries() {
+        assert_eq!(truncate("äöü".to_string(), 2), "äö");
+        assert_eq!(truncate("abc".to_string(), 5), "abc");
+    }
+}
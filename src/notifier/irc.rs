@@ -0,0 +1,656 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    sync::mpsc::{self, error::TrySendError},
+    time::{self, Instant},
+};
+use tracing::{debug, error, info, instrument, warn};
+
+use super::{Notifier, QUEUE_CAPACITY};
+use crate::{
+    config::IrcConfig,
+    notify::{self, Notification, Templates},
+    Error,
+};
+
+/// The delay before the first reconnection attempt, which doubles with every failed attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(2);
+
+/// The maximum delay between two reconnection attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// How long a connection must have lasted for the backoff to be reset when it is lost.
+const STABLE_CONNECTION: Duration = Duration::from_secs(600);
+
+/// How long the server may stay silent before it is pinged, and then before the connection is
+/// considered lost.
+const PING_INTERVAL: Duration = Duration::from_secs(120);
+
+/// The maximum length of a line received from the server, in bytes.
+const MAX_LINE_LENGTH: usize = 8192;
+
+/// The maximum length of the payload of an `AUTHENTICATE` command, in bytes.
+const MAX_AUTHENTICATE_LENGTH: usize = 400;
+
+/// The number of alternative nicknames tried while registering, when the configured one is in
+/// use.
+const MAX_NICK_ATTEMPTS: usize = 4;
+
+/// How often the configured nickname is reclaimed while an alternative one is used.
+const NICK_RECLAIM_INTERVAL: Duration = Duration::from_secs(300);
+
+/// An I/O stream to an IRC server, either plain or TLS.
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Sends notifications to IRC channels through a persistent connection.
+///
+/// The connection is maintained by a background task, which queues messages while it is
+/// (re)connecting. Lines beyond the capacity of the queue are dropped.
+pub struct Irc {
+    /// The templates notifications are rendered with.
+    templates: Templates,
+    /// The maximum length of a message line, in bytes.
+    max_line_length: usize,
+    /// The queue of lines to send to the channels.
+    queue: mpsc::Sender<String>,
+}
+
+impl Irc {
+    pub fn new(config: &IrcConfig, templates: Templates, max_line_length: usize) -> Self {
+        let (queue, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        let client = Client {
+            config: config.clone(),
+        };
+
+        tokio::spawn(client.run(receiver));
+
+        Irc {
+            templates,
+            max_line_length,
+            queue,
+        }
+    }
+}
+
+#[async_trait]
+impl Notifier for Irc {
+    fn name(&self) -> &str {
+        "irc"
+    }
+
+    async fn send(&self, notification: &Notification) -> Result<(), Error> {
+        let msg = self.templates.render(notification)?;
+
+        // IRC truncates long lines and ends messages at line breaks, so each line of the message
+        // is sent on its own, split further when it is too long.
+        for line in msg.lines().filter(|x| !x.trim().is_empty()) {
+            for line in notify::split_message(line, self.max_line_length) {
+                match self.queue.try_send(line) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(_)) => {
+                        error!("dropping irc line as its queue is full");
+                    }
+                    Err(TrySendError::Closed(_)) => {
+                        return Err(Error::Irc("the connection task stopped".to_string()));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Delays messages so that no more than a burst of them is sent at once.
+struct Throttle {
+    /// The delay between messages once the burst is used up.
+    interval: Duration,
+    /// How far ahead of time messages may be sent.
+    window: Duration,
+    /// The time the next message would be sent at without bursts.
+    next: Instant,
+}
+
+impl Throttle {
+    fn new(burst: u32, interval: Duration) -> Self {
+        Throttle {
+            interval,
+            window: interval * burst.saturating_sub(1),
+            next: Instant::now(),
+        }
+    }
+
+    /// Waits until the next message may be sent.
+    async fn wait(&mut self) {
+        let now = Instant::now();
+
+        self.next = self.next.max(now);
+
+        if let Some(delay) = (self.next - now).checked_sub(self.window) {
+            time::sleep(delay).await;
+        }
+
+        self.next += self.interval;
+    }
+}
+
+/// The state of a connection to the server.
+struct Session {
+    /// The nickname currently used.
+    nick: String,
+    /// The number of alternative nicknames tried while registering.
+    nick_attempts: usize,
+    /// Whether the server accepted the registration.
+    registered: bool,
+    /// Whether the server was pinged since it last sent anything.
+    pinged: bool,
+    /// The time the server last sent anything.
+    last_received: Instant,
+}
+
+/// A message received from the server.
+#[derive(Debug, Eq, PartialEq)]
+struct Message<'a> {
+    /// The nickname or server name the message is from, if given.
+    source: Option<&'a str>,
+    /// The command or numeric reply.
+    command: &'a str,
+    /// The parameters of the command, including the trailing one.
+    params: Vec<&'a str>,
+}
+
+impl<'a> Message<'a> {
+    /// Parses a `line` received from the server, without its line ending.
+    fn parse(line: &'a str) -> Option<Self> {
+        let mut rest = line;
+        let mut source = None;
+
+        // Tags come before the prefix.
+        if rest.starts_with('@') {
+            rest = rest.split_once(' ')?.1;
+        }
+
+        if let Some(prefix) = rest.strip_prefix(':') {
+            let (prefix, middle) = prefix.split_once(' ')?;
+
+            source = prefix.split(['!', '@']).next();
+            rest = middle;
+        }
+
+        let (middle, trailing) = match rest.split_once(" :") {
+            Some((middle, trailing)) => (middle, Some(trailing)),
+            None => (rest, None),
+        };
+        let mut params: Vec<&str> = middle.split(' ').filter(|x| !x.is_empty()).collect();
+
+        if params.is_empty() {
+            return None;
+        }
+
+        let command = params.remove(0);
+
+        params.extend(trailing);
+
+        Some(Message {
+            source,
+            command,
+            params,
+        })
+    }
+
+    /// Returns the parameter at `idx`, or an empty string if there is none.
+    fn param(&self, idx: usize) -> &'a str {
+        self.params.get(idx).copied().unwrap_or_default()
+    }
+}
+
+/// Maintains the connection to the server.
+struct Client {
+    config: IrcConfig,
+}
+
+impl Client {
+    /// Keeps connecting to the server and sending the lines received through `lines` to the
+    /// channels, until the queue is closed.
+    async fn run(self, mut lines: mpsc::Receiver<String>) {
+        let mut backoff = INITIAL_BACKOFF;
+
+        loop {
+            let started = Instant::now();
+
+            match self.session(&mut lines).await {
+                Ok(()) => return,
+                Err(err) => {
+                    if started.elapsed() > STABLE_CONNECTION {
+                        backoff = INITIAL_BACKOFF;
+                    }
+
+                    warn!(%err, server = %self.config.server, ?backoff, "irc connection lost, reconnecting");
+                }
+            }
+
+            time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    /// Opens a connection to the server.
+    async fn connect(&self) -> Result<Box<dyn Stream>, Error> {
+        let config = &self.config;
+        let stream = TcpStream::connect((config.server.as_str(), config.port)).await?;
+
+        if !config.tls {
+            return Ok(Box::new(stream));
+        }
+
+        let connector = native_tls::TlsConnector::new().map_err(Error::TlsSetup)?;
+        let stream = tokio_native_tls::TlsConnector::from(connector)
+            .connect(&config.server, stream)
+            .await
+            .map_err(|e| Error::TlsHandshake(config.server.clone(), e))?;
+
+        Ok(Box::new(stream))
+    }
+
+    /// Connects and registers with the server, then sends the lines received through `lines`
+    /// until the connection fails, or the queue is closed.
+    #[instrument(skip_all, fields(server = %self.config.server))]
+    async fn session(&self, lines: &mut mpsc::Receiver<String>) -> Result<(), Error> {
+        let config = &self.config;
+        let mut stream = self.connect().await?;
+        let mut session = Session {
+            nick: config.nick.clone(),
+            nick_attempts: 0,
+            registered: false,
+            pinged: false,
+            last_received: Instant::now(),
+        };
+        let mut throttle =
+            Throttle::new(config.burst, Duration::from_millis(config.message_interval));
+        let mut reclaim = time::interval_at(
+            Instant::now() + NICK_RECLAIM_INTERVAL,
+            NICK_RECLAIM_INTERVAL,
+        );
+        let mut buf = vec![];
+        let mut chunk = [0; 4096];
+
+        debug!("connected to irc server");
+
+        if config.sasl_password.is_some() {
+            write_line(&mut stream, "CAP REQ :sasl").await?;
+        }
+
+        if let Some(ref password) = config.password {
+            write_line(&mut stream, &format!("PASS {password}")).await?;
+        }
+
+        let username = config.username.as_deref().unwrap_or(&config.nick);
+        let realname = config.realname.as_deref().unwrap_or(&config.nick);
+
+        write_line(&mut stream, &format!("NICK {}", config.nick)).await?;
+        write_line(&mut stream, &format!("USER {username} 0 * :{realname}")).await?;
+
+        loop {
+            let timeout = if session.pinged {
+                PING_INTERVAL * 2
+            } else {
+                PING_INTERVAL
+            };
+
+            tokio::select! {
+                read = stream.read(&mut chunk) => {
+                    let read = read?;
+
+                    if read == 0 {
+                        return Err(Error::Irc("the server closed the connection".to_string()));
+                    }
+
+                    session.last_received = Instant::now();
+                    session.pinged = false;
+                    buf.extend_from_slice(&chunk[..read]);
+
+                    while let Some(idx) = buf.iter().position(|x| *x == b'\n') {
+                        let line: Vec<u8> = buf.drain(..=idx).collect();
+                        let line = String::from_utf8_lossy(&line);
+
+                        self.handle(line.trim_end_matches(['\r', '\n']), &mut session, &mut stream)
+                            .await?;
+                    }
+
+                    if buf.len() > MAX_LINE_LENGTH {
+                        return Err(Error::Irc("the server sent an overlong line".to_string()));
+                    }
+                }
+                line = lines.recv(), if session.registered => {
+                    let Some(line) = line else {
+                        write_line(&mut stream, "QUIT").await?;
+
+                        return Ok(());
+                    };
+
+                    for channel in &config.channels {
+                        throttle.wait().await;
+                        write_line(&mut stream, &format!("PRIVMSG {channel} :{line}")).await?;
+                    }
+                }
+                _ = reclaim.tick(), if session.registered && session.nick != config.nick => {
+                    write_line(&mut stream, &format!("NICK {}", config.nick)).await?;
+                }
+                () = time::sleep_until(session.last_received + timeout) => {
+                    if session.pinged {
+                        return Err(Error::Irc("the server stopped responding".to_string()));
+                    }
+
+                    session.pinged = true;
+                    write_line(&mut stream, &format!("PING :{}", config.server)).await?;
+                }
+            }
+        }
+    }
+
+    /// Handles a `line` received from the server.
+    async fn handle(
+        &self,
+        line: &str,
+        session: &mut Session,
+        stream: &mut Box<dyn Stream>,
+    ) -> Result<(), Error> {
+        let Some(msg) = Message::parse(line) else {
+            return Ok(());
+        };
+        let config = &self.config;
+
+        match msg.command {
+            "PING" => write_line(stream, &format!("PONG :{}", msg.param(0))).await?,
+            "CAP" if msg.param(1) == "ACK" => {
+                write_line(stream, "AUTHENTICATE PLAIN").await?;
+            }
+            "CAP" if msg.param(1) == "NAK" => {
+                return Err(Error::Irc("the server does not support SASL".to_string()));
+            }
+            "AUTHENTICATE" if msg.param(0) == "+" => {
+                let username = config.sasl_username.as_deref().unwrap_or(&config.nick);
+                let password = config.sasl_password.as_deref().unwrap_or_default();
+                let payload = BASE64_STANDARD.encode(format!("\0{username}\0{password}"));
+
+                for line in authenticate_lines(&payload) {
+                    write_line(stream, &line).await?;
+                }
+            }
+            // Logged in successfully.
+            "903" => write_line(stream, "CAP END").await?,
+            // Logging in failed, was aborted or the account is locked.
+            "902" | "904" | "905" | "906" => {
+                return Err(Error::Irc(format!(
+                    "sasl authentication failed: {}",
+                    msg.params.last().unwrap_or(&"")
+                )));
+            }
+            // The nickname is in use, so try another one while registering. Once registered, the
+            // configured one is reclaimed periodically.
+            "433" if !session.registered => {
+                if session.nick_attempts >= MAX_NICK_ATTEMPTS {
+                    return Err(Error::Irc(format!(
+                        "the nickname `{}' and its alternatives are in use",
+                        config.nick
+                    )));
+                }
+
+                session.nick_attempts += 1;
+                session.nick.push('_');
+                write_line(stream, &format!("NICK {}", session.nick)).await?;
+            }
+            "NICK" if msg.source == Some(session.nick.as_str()) => {
+                info!(nick = %msg.param(0), "changed irc nickname");
+
+                session.nick = msg.param(0).to_string();
+            }
+            // Welcome.
+            "001" => {
+                info!(nick = %session.nick, "registered with irc server");
+
+                session.registered = true;
+
+                if !config.channels.is_empty() {
+                    write_line(stream, &format!("JOIN {}", config.channels.join(","))).await?;
+                }
+            }
+            "ERROR" => {
+                return Err(Error::Irc(format!(
+                    "the server closed the connection: {}",
+                    msg.param(0)
+                )));
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the `AUTHENTICATE` commands sending the base64-encoded `payload`, which is split into
+/// chunks of at most 400 bytes and ends with an empty one if the last chunk is full.
+fn authenticate_lines(payload: &str) -> Vec<String> {
+    let mut lines: Vec<String> = payload
+        .as_bytes()
+        .chunks(MAX_AUTHENTICATE_LENGTH)
+        .map(|x| format!("AUTHENTICATE {}", String::from_utf8_lossy(x)))
+        .collect();
+
+    if payload.len().is_multiple_of(MAX_AUTHENTICATE_LENGTH) {
+        lines.push("AUTHENTICATE +".to_string());
+    }
+
+    lines
+}
+
+/// Writes a `line` to the server, with any line breaks in it removed so it can't inject
+/// commands.
+async fn write_line(stream: &mut Box<dyn Stream>, line: &str) -> Result<(), Error> {
+    let mut line = line.replace(['\r', '\n'], " ");
+
+    line.push_str("\r\n");
+    stream.write_all(line.as_bytes()).await?;
+    stream.flush().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tokio::{
+        io::{AsyncBufReadExt, BufReader, Lines},
+        net::{
+            tcp::{OwnedReadHalf, OwnedWriteHalf},
+            TcpListener,
+        },
+    };
+
+    use super::*;
+
+    #[test]
+    fn parses_messages() {
+        assert_eq!(
+            Message::parse("@time=x :nick!user@host PRIVMSG #chan :hello there"),
+            Some(Message {
+                source: Some("nick"),
+                command: "PRIVMSG",
+                params: vec!["#chan", "hello there"],
+            })
+        );
+        assert_eq!(
+            Message::parse(":irc.example.org 433 * uplink :Nickname is already in use"),
+            Some(Message {
+                source: Some("irc.example.org"),
+                command: "433",
+                params: vec!["*", "uplink", "Nickname is already in use"],
+            })
+        );
+        assert_eq!(
+            Message::parse("PING :a b"),
+            Some(Message {
+                source: None,
+                command: "PING",
+                params: vec!["a b"],
+            })
+        );
+        assert_eq!(
+            Message::parse("AUTHENTICATE +").map(|x| x.param(1)),
+            Some("")
+        );
+        assert_eq!(Message::parse(""), None);
+        assert_eq!(Message::parse(":source"), None);
+        assert_eq!(Message::parse("@tags"), None);
+    }
+
+    #[test]
+    fn splits_authenticate_payloads() {
+        assert_eq!(authenticate_lines("abc"), ["AUTHENTICATE abc"]);
+
+        let payload = "a".repeat(800);
+        let lines = authenticate_lines(&payload);
+
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], format!("AUTHENTICATE {}", "a".repeat(400)));
+        assert_eq!(lines[2], "AUTHENTICATE +");
+        assert_eq!(authenticate_lines(&payload[..401]).len(), 2);
+    }
+
+    async fn reply(writer: &mut OwnedWriteHalf, line: &str) {
+        writer
+            .write_all(format!("{line}\r\n").as_bytes())
+            .await
+            .unwrap();
+    }
+
+    async fn expect(lines: &mut Lines<BufReader<OwnedReadHalf>>, expected: &str) {
+        let line = time::timeout(Duration::from_secs(5), lines.next_line())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(line.as_deref(), Some(expected));
+    }
+
+    fn config(listener: &TcpListener, sasl_password: Option<&str>) -> IrcConfig {
+        IrcConfig {
+            server: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+            tls: false,
+            nick: "uplink".to_string(),
+            username: None,
+            realname: None,
+            password: None,
+            sasl_username: None,
+            sasl_password: sasl_password.map(String::from),
+            channels: vec!["#a".to_string()],
+            burst: 1,
+            message_interval: 200,
+            format: None,
+            templates: HashMap::new(),
+        }
+    }
+
+    async fn expect_closed(lines: &mut Lines<BufReader<OwnedReadHalf>>) {
+        let line = time::timeout(Duration::from_secs(5), lines.next_line())
+            .await
+            .unwrap();
+
+        assert!(matches!(line, Ok(None) | Err(_)), "{line:?}");
+    }
+
+    #[tokio::test]
+    async fn registers_and_sends_throttled_messages() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = config(&listener, Some("secret"));
+        let (queue, receiver) = mpsc::channel(QUEUE_CAPACITY);
+
+        tokio::spawn(Client { config }.run(receiver));
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        expect(&mut lines, "CAP REQ :sasl").await;
+        expect(&mut lines, "NICK uplink").await;
+        expect(&mut lines, "USER uplink 0 * :uplink").await;
+        reply(&mut writer, ":irc.example.org CAP * ACK :sasl").await;
+        expect(&mut lines, "AUTHENTICATE PLAIN").await;
+        reply(&mut writer, "AUTHENTICATE +").await;
+        expect(&mut lines, "AUTHENTICATE AHVwbGluawBzZWNyZXQ=").await;
+        reply(
+            &mut writer,
+            ":irc.example.org 903 uplink :SASL authentication successful",
+        )
+        .await;
+        expect(&mut lines, "CAP END").await;
+        reply(
+            &mut writer,
+            ":irc.example.org 433 * uplink :Nickname is already in use",
+        )
+        .await;
+        expect(&mut lines, "NICK uplink_").await;
+        reply(&mut writer, ":irc.example.org 001 uplink_ :Welcome").await;
+        expect(&mut lines, "JOIN #a").await;
+        reply(&mut writer, "PING :token").await;
+        expect(&mut lines, "PONG :token").await;
+
+        for line in ["one", "two", "three"] {
+            queue.send(line.to_string()).await.unwrap();
+        }
+
+        let started = Instant::now();
+
+        expect(&mut lines, "PRIVMSG #a :one").await;
+        expect(&mut lines, "PRIVMSG #a :two").await;
+        expect(&mut lines, "PRIVMSG #a :three").await;
+
+        assert!(started.elapsed() >= Duration::from_millis(400));
+
+        drop(queue);
+        expect(&mut lines, "QUIT").await;
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_nicknames_in_use() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (_queue, receiver) = mpsc::channel(QUEUE_CAPACITY);
+
+        tokio::spawn(
+            Client {
+                config: config(&listener, None),
+            }
+            .run(receiver),
+        );
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+
+        expect(&mut lines, "NICK uplink").await;
+        expect(&mut lines, "USER uplink 0 * :uplink").await;
+
+        for nick in ["uplink_", "uplink__", "uplink___", "uplink____"] {
+            reply(
+                &mut writer,
+                ":irc.example.org 433 * x :Nickname is already in use",
+            )
+            .await;
+            expect(&mut lines, &format!("NICK {nick}")).await;
+        }
+
+        reply(
+            &mut writer,
+            ":irc.example.org 433 * x :Nickname is already in use",
+        )
+        .await;
+        expect_closed(&mut lines).await;
+    }
+}
@@ -0,0 +1,279 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use reqwest::header::CONTENT_TYPE;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracing::{debug, instrument, warn};
+use url::form_urlencoded;
+
+use super::Notifier;
+use crate::{
+    config::MatrixConfig,
+    http_client::HttpClient,
+    notify::{Notification, Templates},
+    Error,
+};
+
+/// The maximum size of a thumbnail that is uploaded, in bytes.
+const MAX_THUMBNAIL_SIZE: usize = 4 * 1024 * 1024;
+
+/// The response of the media repository to an upload.
+#[derive(Deserialize)]
+struct Upload {
+    /// The `mxc://` URI of the uploaded file.
+    content_uri: String,
+}
+
+/// Sends notifications to a Matrix room as `m.room.message` events, optionally followed by the
+/// thumbnails of images uploaded to the media repository.
+pub struct Matrix {
+    /// The base URL of the homeserver, without a trailing slash.
+    homeserver: String,
+    /// The access token of the account notifications are sent from.
+    access_token: String,
+    /// The id of the room notifications are sent to.
+    room_id: String,
+    /// Whether thumbnails are uploaded and sent along.
+    upload_thumbnails: bool,
+    /// The templates notifications are rendered with.
+    templates: Templates,
+    /// The HTTP client.
+    http: HttpClient,
+    /// The number of events sent, which makes transaction ids unique.
+    transactions: AtomicU64,
+}
+
+impl Matrix {
+    pub fn new(config: &MatrixConfig, templates: Templates, http: HttpClient) -> Self {
+        Matrix {
+            homeserver: config.homeserver.as_str().trim_end_matches('/').to_string(),
+            access_token: config.access_token.clone(),
+            room_id: config.room_id.clone(),
+            upload_thumbnails: config.upload_thumbnails,
+            templates,
+            http,
+            transactions: AtomicU64::new(0),
+        }
+    }
+
+    /// Sends an `m.room.message` event with the given `content` to the room.
+    async fn send_event(&self, content: &Value) -> Result<(), Error> {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |x| x.as_millis());
+        let txn_id = format!(
+            "{started}.{}",
+            self.transactions.fetch_add(1, Ordering::Relaxed)
+        );
+        let url = format!(
+            "{}/_matrix/client/v3/rooms/{}/send/m.room.message/{txn_id}",
+            self.homeserver,
+            encode(&self.room_id)
+        );
+
+        // Retries reuse the transaction id, so the homeserver doesn't send the event twice.
+        let request = self
+            .http
+            .put(url)
+            .bearer_auth(&self.access_token)
+            .json(content);
+
+        self.http.send(request).await?;
+
+        Ok(())
+    }
+
+    /// Uploads the image at `url` to the media repository and sends it to the room.
+    async fn send_image(&self, url: &str, notification: &Notification) -> Result<(), Error> {
+        let mut image = self.http.send(self.http.get(url)).await?;
+        let mime_type = image
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|x| x.to_str().ok())
+            .filter(|x| x.starts_with("image/"))
+            .unwrap_or("image/jpeg")
+            .to_string();
+        let too_large = || Error::ResponseTooLarge(url.to_string(), MAX_THUMBNAIL_SIZE);
+
+        if image
+            .content_length()
+            .is_some_and(|x| x > MAX_THUMBNAIL_SIZE as u64)
+        {
+            return Err(too_large());
+        }
+
+        let mut bytes = vec![];
+
+        while let Some(chunk) = image.chunk().await? {
+            if bytes.len() + chunk.len() > MAX_THUMBNAIL_SIZE {
+                return Err(too_large());
+            }
+
+            bytes.extend_from_slice(&chunk);
+        }
+
+        let size = bytes.len();
+        let filename = notification.filename.as_deref().unwrap_or("attachment");
+        let request = self
+            .http
+            .post(format!(
+                "{}/_matrix/media/v3/upload?filename={}",
+                self.homeserver,
+                encode(filename)
+            ))
+            .bearer_auth(&self.access_token)
+            .header(CONTENT_TYPE, &mime_type)
+            .body(bytes);
+        let upload: Upload = self.http.send(request).await?.json().await?;
+
+        self.send_event(&json!({
+            "msgtype": "m.image",
+            "body": filename,
+            "url": upload.content_uri,
+            "info": { "mimetype": mime_type, "size": size }
+        }))
+        .await
+    }
+}
+
+#[async_trait]
+impl Notifier for Matrix {
+    fn name(&self) -> &str {
+        "matrix"
+    }
+
+    #[instrument(skip_all)]
+    async fn send(&self, notification: &Notification) -> Result<(), Error> {
+        let body = self.templates.render(notification)?;
+
+        self.send_event(&json!({ "msgtype": "m.notice", "body": body }))
+            .await?;
+
+        debug!("sent matrix message");
+
+        // Only actual thumbnails are uploaded, rather than originals of any size.
+        if let Some(thumbnail) = notification
+            .thumbnail
+            .as_deref()
+            .filter(|_| self.upload_thumbnails)
+        {
+            // The message itself has been sent already, so a missing thumbnail is only logged.
+            if let Err(err) = self.send_image(thumbnail, notification).await {
+                warn!(%err, %thumbnail, "could not send matrix thumbnail");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Percent-encodes `value` for use in a URL path segment or query.
+fn encode(value: &str) -> String {
+    form_urlencoded::byte_serialize(value.as_bytes()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use axum::{
+        http::Method,
+        response::{IntoResponse, Response},
+    };
+
+    use crate::{
+        config::NotificationFormat,
+        notifier::tests::{http, notification, server, Request},
+    };
+
+    use super::*;
+
+    fn respond(request: &Request) -> Response {
+        match request.path.as_str() {
+            "/thumbnail.jpg" => ([(CONTENT_TYPE, "image/jpeg")], vec![1; 16]).into_response(),
+            "/large.jpg" => vec![0; MAX_THUMBNAIL_SIZE + 1].into_response(),
+            x if x.starts_with("/_matrix/media/") => {
+                r#"{"content_uri": "mxc://example.org/abc"}"#.into_response()
+            }
+            _ => r#"{"event_id": "$abc"}"#.into_response(),
+        }
+    }
+
+    fn matrix(homeserver: &str) -> Matrix {
+        let config = MatrixConfig {
+            homeserver: homeserver.parse().unwrap(),
+            access_token: "token".to_string(),
+            room_id: "!room:example.org".to_string(),
+            upload_thumbnails: true,
+            format: None,
+            templates: HashMap::new(),
+        };
+        let templates = Templates::new(NotificationFormat::Plain, &HashMap::new()).unwrap();
+
+        Matrix::new(&config, templates, http())
+    }
+
+    #[tokio::test]
+    async fn sends_messages_and_thumbnails() {
+        let (url, mut requests) = server(respond).await;
+        let thumbnail = format!("{url}/thumbnail.jpg");
+        let notification = notification(&format!("{url}/a.jpg"), Some(thumbnail));
+
+        matrix(&url).send(&notification).await.unwrap();
+
+        let message = requests.recv().await.unwrap();
+        let prefix = "/_matrix/client/v3/rooms/%21room%3Aexample.org/send/m.room.message/";
+
+        assert_eq!(message.method, Method::PUT);
+        assert!(message.path.starts_with(prefix), "{}", message.path);
+        assert_eq!(message.json()["msgtype"], "m.notice");
+        assert!(message.json()["body"].as_str().unwrap().contains("/a.jpg"));
+
+        assert_eq!(requests.recv().await.unwrap().path, "/thumbnail.jpg");
+
+        let upload = requests.recv().await.unwrap();
+
+        assert_eq!(upload.method, Method::POST);
+        assert_eq!(upload.path, "/_matrix/media/v3/upload?filename=a.jpg");
+        assert_eq!(upload.body.as_ref(), [1; 16]);
+
+        let image = requests.recv().await.unwrap();
+
+        assert_eq!(image.method, Method::PUT);
+        assert!(image.path.starts_with(prefix));
+        assert_ne!(image.path, message.path);
+        assert_eq!(image.json()["msgtype"], "m.image");
+        assert_eq!(image.json()["url"], "mxc://example.org/abc");
+        assert_eq!(image.json()["info"]["size"], 16);
+    }
+
+    #[tokio::test]
+    async fn doesnt_upload_originals_or_large_thumbnails() {
+        let (url, mut requests) = server(respond).await;
+        let matrix = matrix(&url);
+
+        matrix
+            .send(&notification(&format!("{url}/a.jpg"), None))
+            .await
+            .unwrap();
+        matrix
+            .send(&notification(
+                &format!("{url}/a.jpg"),
+                Some(format!("{url}/large.jpg")),
+            ))
+            .await
+            .unwrap();
+
+        let paths: Vec<_> = std::iter::from_fn(|| requests.try_recv().ok())
+            .map(|x| x.path)
+            .collect();
+
+        assert_eq!(paths.len(), 3, "{paths:?}");
+        assert_eq!(paths[2], "/large.jpg");
+        assert!(!paths.iter().any(|x| x.contains("/media/")));
+    }
+}
@@ -0,0 +1,76 @@
+use async_trait::async_trait;
+use serde_json::json;
+use tracing::{debug, instrument};
+
+use super::Notifier;
+use crate::{
+    http_client::HttpClient,
+    notify::{self, Notification, Templates},
+    Error,
+};
+
+/// The endpoint of the meta webhook.
+const META_WEBHOOK_URL: &str = "https://meta-webhook.infra.rwx.im/trigger";
+
+/// Sends notifications to IRC through the meta webhook.
+pub struct MetaWebhook {
+    /// The bearer token.
+    token: String,
+    /// The templates notifications are rendered with.
+    templates: Templates,
+    /// The maximum length of a message line, in bytes.
+    max_line_length: usize,
+    /// The HTTP client.
+    http: HttpClient,
+}
+
+impl MetaWebhook {
+    pub fn new(
+        token: &str,
+        templates: Templates,
+        max_line_length: usize,
+        http: HttpClient,
+    ) -> Self {
+        MetaWebhook {
+            token: token.to_string(),
+            templates,
+            max_line_length,
+            http,
+        }
+    }
+}
+
+#[async_trait]
+impl Notifier for MetaWebhook {
+    fn name(&self) -> &str {
+        "meta_webhook"
+    }
+
+    #[instrument(skip_all)]
+    async fn send(&self, notification: &Notification) -> Result<(), Error> {
+        let msg = self.templates.render(notification)?;
+
+        // IRC truncates long lines, so long messages are sent as several lines.
+        for line in notify::split_message(&msg, self.max_line_length) {
+            let payload = json!({
+                "method": "message",
+                "params": {
+                    "network": "irc.rwx.im:6697",
+                    "channel": "#uplink",
+                    "message": line
+                }
+            });
+
+            let request = self
+                .http
+                .post(META_WEBHOOK_URL)
+                .bearer_auth(&self.token)
+                .json(&payload);
+            let res = self.http.send(request).await?.text().await?;
+
+            debug!(%res, "sent chat message");
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,133 @@
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use tracing::{debug, instrument};
+use url::Url;
+
+use super::Notifier;
+use crate::{
+    config::SlackConfig,
+    http_client::HttpClient,
+    notify::{Notification, Templates},
+    Error,
+};
+
+/// Sends notifications to a Slack channel through an incoming webhook, as Block Kit messages
+/// with image previews.
+pub struct Slack {
+    /// The URL of the incoming webhook.
+    webhook_url: Url,
+    /// The templates notifications are rendered with.
+    templates: Templates,
+    /// The HTTP client.
+    http: HttpClient,
+}
+
+impl Slack {
+    pub fn new(config: &SlackConfig, templates: Templates, http: HttpClient) -> Self {
+        Slack {
+            webhook_url: config.webhook_url.clone(),
+            templates,
+            http,
+        }
+    }
+}
+
+#[async_trait]
+impl Notifier for Slack {
+    fn name(&self) -> &str {
+        "slack"
+    }
+
+    #[instrument(skip_all)]
+    async fn send(&self, notification: &Notification) -> Result<(), Error> {
+        // Mails are untrusted, so their contents mustn't be able to mention or link anything.
+        let text = escape(&self.templates.render(notification)?);
+        let mut blocks = vec![json!({
+            "type": "section",
+            "text": { "type": "mrkdwn", "text": text }
+        })];
+
+        if let Some(preview) = notification.preview() {
+            blocks.push(image_block(preview, notification));
+        }
+
+        let request = self
+            .http
+            .post(self.webhook_url.clone())
+            .json(&json!({ "text": text, "blocks": blocks }));
+
+        self.http.send(request).await?;
+
+        debug!("sent slack message");
+
+        Ok(())
+    }
+}
+
+/// Returns the block showing the image at `url` previewing the object of `notification`.
+fn image_block(url: &str, notification: &Notification) -> Value {
+    json!({
+        "type": "image",
+        "image_url": url,
+        "alt_text": notification.filename.as_deref().unwrap_or("attachment")
+    })
+}
+
+/// Escapes the characters Slack treats as control characters in message text.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use axum::http::Method;
+
+    use crate::{
+        config::NotificationFormat,
+        notifier::tests::{http, notification, ok, server},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn posts_escaped_blocks_with_previews() {
+        let (url, mut requests) = server(ok).await;
+        let config = SlackConfig {
+            webhook_url: format!("{url}/hook").parse().unwrap(),
+            format: None,
+            templates: HashMap::new(),
+        };
+        let templates = Templates::new(NotificationFormat::Plain, &HashMap::new()).unwrap();
+        let notification = notification(
+            "https://files.example.org/a.jpg",
+            Some("https://files.example.org/thumbnails/a.jpg".to_string()),
+        );
+
+        Slack::new(&config, templates, http())
+            .send(&notification)
+            .await
+            .unwrap();
+
+        let request = requests.recv().await.unwrap();
+        let payload = request.json();
+        let text = payload["text"].as_str().unwrap();
+
+        assert_eq!(request.method, Method::POST);
+        assert_eq!(request.path, "/hook");
+        assert!(text.contains("&lt;!channel&gt;"), "{text}");
+        assert_eq!(payload["blocks"][0]["type"], "section");
+        assert_eq!(payload["blocks"][0]["text"]["text"], text);
+        assert_eq!(
+            payload["blocks"][1],
+            json!({
+                "type": "image",
+                "image_url": "https://files.example.org/thumbnails/a.jpg",
+                "alt_text": "a.jpg"
+            })
+        );
+    }
+}
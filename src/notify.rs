@@ -0,0 +1,791 @@
+use std::collections::HashMap;
+
+use minijinja::Environment;
+use serde::Serialize;
+
+use crate::{
+    config::{AwsS3Config, NotificationFormat, NotificationsConfig},
+    handler::MailInfo,
+    report::AttachmentReport,
+    store::Outcome,
+    Error,
+};
+
+/// What a notification is about, which selects the template it is rendered with.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    /// A stored attachment.
+    Attachment,
+    /// An attachment malware was detected in, that was not stored.
+    Malware,
+    /// The files extracted from an archive.
+    Archive,
+    /// A summary of the attachments of a mail.
+    Summary,
+    /// A stored mail body.
+    Body,
+    /// A URL found in the body of a mail.
+    Link,
+    /// An attachment that was taken down.
+    Takedown,
+}
+
+impl Kind {
+    /// All kinds of notifications.
+    const ALL: [Kind; 7] = [
+        Kind::Attachment,
+        Kind::Malware,
+        Kind::Archive,
+        Kind::Summary,
+        Kind::Body,
+        Kind::Link,
+        Kind::Takedown,
+    ];
+
+    /// Returns the name of the template for this kind of notification.
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Attachment => "attachment",
+            Kind::Malware => "malware",
+            Kind::Archive => "archive",
+            Kind::Summary => "summary",
+            Kind::Body => "body",
+            Kind::Link => "link",
+            Kind::Takedown => "takedown",
+        }
+    }
+}
+
+/// A notification, with the variables available to its template.
+#[derive(Debug, Clone, Serialize)]
+pub struct Notification {
+    /// What the notification is about.
+    pub kind: Kind,
+    /// The subject of the mail, if any.
+    pub subject: Option<String>,
+    /// The sender of the mail.
+    pub sender: String,
+    /// The intended recipient of the mail, if known.
+    pub recipient: Option<String>,
+    /// The filename of the attachment, if any.
+    pub filename: Option<String>,
+    /// The human-readable size of the attachment, or the total size of a summary.
+    pub size: Option<String>,
+    /// The size of the attachment, or the total size of a summary, in bytes.
+    pub bytes: Option<u64>,
+    /// The MIME type of the attachment.
+    pub mime: Option<String>,
+    /// The public URL of the stored object, or the URL found in a mail body.
+    pub url: Option<String>,
+    /// The public URL of the thumbnail of the stored object, if any.
+    pub thumbnail: Option<String>,
+    /// The public URLs of the stored files of an archive or summary.
+    pub urls: Vec<String>,
+    /// The key of the stored object.
+    pub key: Option<String>,
+    /// Whether the attachment already existed in the bucket.
+    pub cached: bool,
+    /// What happened to the attachment.
+    pub outcome: Option<String>,
+    /// The name of the detected malware, if any.
+    pub signature: Option<String>,
+    /// The number of files malware was detected in.
+    pub infected: usize,
+    /// The filename of the archive the files were extracted from.
+    pub archive: Option<String>,
+    /// The number of stored files of an archive or summary.
+    pub count: usize,
+    /// The number of stored files of a summary that aren't linked in `urls`.
+    pub more: usize,
+    /// The URL linked in summaries for the files that aren't listed.
+    pub summary_url: Option<String>,
+    /// The beginning of a stored mail body.
+    pub excerpt: Option<String>,
+}
+
+impl Notification {
+    /// Returns an empty notification of the given `kind` about the mail described by `info`.
+    fn new(kind: Kind, info: &MailInfo<'_>) -> Self {
+        Notification {
+            kind,
+            subject: info.subject.map(String::from),
+            sender: info.sender.unwrap_or("unknown").to_string(),
+            recipient: info.recipient.map(String::from),
+            filename: None,
+            size: None,
+            bytes: None,
+            mime: None,
+            url: None,
+            thumbnail: None,
+            urls: vec![],
+            key: None,
+            cached: false,
+            outcome: None,
+            signature: None,
+            infected: 0,
+            archive: None,
+            count: 0,
+            more: 0,
+            summary_url: None,
+            excerpt: None,
+        }
+    }
+
+    /// Returns the notification about the attachment described by `report`, if there is
+    /// anything to notify about.
+    pub fn attachment(
+        report: &AttachmentReport,
+        info: &MailInfo<'_>,
+        s3_config: &AwsS3Config,
+    ) -> Option<Self> {
+        let signature = report.scan.as_ref().and_then(|x| x.signature.clone());
+
+        let Some(ref key) = report.record.key else {
+            return signature.map(|signature| Notification {
+                filename: report.record.filename.clone(),
+                mime: Some(report.record.mime_type.clone()),
+                outcome: Some(report.record.outcome.to_string()),
+                signature: Some(signature),
+                ..Notification::new(Kind::Malware, info)
+            });
+        };
+
+        Some(Notification {
+            filename: report.record.filename.clone(),
+            size: Some(human_size(report.record.size)),
+            bytes: Some(report.record.size),
+            mime: Some(report.record.mime_type.clone()),
+            url: public_url(s3_config, key),
+            thumbnail: report
+                .record
+                .thumbnail_key
+                .as_deref()
+                .and_then(|x| public_url(s3_config, x)),
+            key: Some(key.clone()),
+            cached: report.record.outcome == Outcome::Cached,
+            outcome: Some(report.record.outcome.to_string()),
+            signature,
+            ..Notification::new(Kind::Attachment, info)
+        })
+    }
+
+    /// Returns the notification linking the files extracted from the archive `name`, if any
+    /// were stored.
+    pub fn archive(
+        reports: &[AttachmentReport],
+        name: &str,
+        info: &MailInfo<'_>,
+        s3_config: &AwsS3Config,
+    ) -> Option<Self> {
+        let urls: Vec<String> = reports
+            .iter()
+            .filter_map(|x| x.record.key.as_deref())
+            .filter_map(|x| public_url(s3_config, x))
+            .collect();
+
+        if urls.is_empty() {
+            return None;
+        }
+
+        Some(Notification {
+            filename: Some(name.to_string()),
+            archive: Some(name.to_string()),
+            count: urls.len(),
+            urls,
+            infected: infected(reports),
+            ..Notification::new(Kind::Archive, info)
+        })
+    }
+
+    /// Returns the notification summarizing the stored attachments described by `reports`,
+    /// linking at most the configured number of them.
+    pub fn summary(
+        reports: &[AttachmentReport],
+        info: &MailInfo<'_>,
+        config: &NotificationsConfig,
+        s3_config: &AwsS3Config,
+    ) -> Self {
+        let keys: Vec<&str> = reports
+            .iter()
+            .filter_map(|x| x.record.key.as_deref())
+            .collect();
+        let total_size: u64 = reports
+            .iter()
+            .filter(|x| x.record.key.is_some())
+            .map(|x| x.record.size)
+            .sum();
+
+        Notification {
+            size: Some(human_size(total_size)),
+            bytes: Some(total_size),
+            urls: keys
+                .iter()
+                .take(config.max_links)
+                .filter_map(|x| public_url(s3_config, x))
+                .collect(),
+            count: keys.len(),
+            more: keys.len().saturating_sub(config.max_links),
+            summary_url: config.summary_url.as_ref().map(ToString::to_string),
+            infected: infected(reports),
+            ..Notification::new(Kind::Summary, info)
+        }
+    }
+
+    /// Returns the notification linking the stored body described by `report`, with its
+    /// `excerpt`.
+    pub fn body(
+        report: &AttachmentReport,
+        excerpt: &str,
+        info: &MailInfo<'_>,
+        s3_config: &AwsS3Config,
+    ) -> Option<Self> {
+        let key = report.record.key.as_ref()?;
+
+        Some(Notification {
+            filename: report.record.filename.clone(),
+            size: Some(human_size(report.record.size)),
+            bytes: Some(report.record.size),
+            mime: Some(report.record.mime_type.clone()),
+            url: public_url(s3_config, key),
+            key: Some(key.clone()),
+            cached: report.record.outcome == Outcome::Cached,
+            outcome: Some(report.record.outcome.to_string()),
+            excerpt: Some(excerpt.to_string()).filter(|x| !x.is_empty()),
+            ..Notification::new(Kind::Body, info)
+        })
+    }
+
+    /// Returns the notification about the `url` found in the body of a mail.
+    pub fn link(url: &str, info: &MailInfo<'_>) -> Self {
+        Notification {
+            url: Some(url.to_string()),
+            ..Notification::new(Kind::Link, info)
+        }
+    }
+
+    /// Returns the notification about the takedown of the attachment with the given `key`.
+    pub fn takedown(key: &str, s3_config: &AwsS3Config) -> Self {
+        Notification {
+            key: Some(key.to_string()),
+            url: public_url(s3_config, key),
+            ..Notification::new(Kind::Takedown, &MailInfo::default())
+        }
+    }
+
+    /// Returns the URL of an image previewing the stored object, which is its thumbnail or the
+    /// object itself if it is an image.
+    pub fn preview(&self) -> Option<&str> {
+        if self.kind != Kind::Attachment {
+            return None;
+        }
+
+        self.thumbnail.as_deref().or_else(|| {
+            self.url.as_deref().filter(|_| {
+                self.mime
+                    .as_deref()
+                    .is_some_and(|x| x.starts_with("image/"))
+            })
+        })
+    }
+}
+
+/// Renders notifications with the templates of a sink.
+#[derive(Debug)]
+pub struct Templates {
+    /// The environment holding a template for each kind of notification.
+    env: Environment<'static>,
+}
+
+impl Templates {
+    /// Returns the default templates for `format`, with the given templates overriding them by
+    /// name.
+    pub fn new(
+        format: NotificationFormat,
+        overrides: &HashMap<String, String>,
+    ) -> Result<Self, Error> {
+        if let Some(name) = overrides
+            .keys()
+            .find(|x| !Kind::ALL.iter().any(|kind| kind.name() == x.as_str()))
+        {
+            return Err(Error::UnknownTemplate(name.clone()));
+        }
+
+        let mut env = Environment::new();
+
+        env.add_filter("escape_markdown", escape_markdown);
+
+        for kind in Kind::ALL {
+            let source = match overrides.get(kind.name()) {
+                Some(source) => source.clone(),
+                None => default_template(format, kind).to_string(),
+            };
+
+            env.add_template_owned(kind.name(), source)
+                .map_err(|e| Error::InvalidTemplate(kind.name().to_string(), e))?;
+        }
+
+        Ok(Templates { env })
+    }
+
+    /// Renders the given `notification` with the template for its kind.
+    pub fn render(&self, notification: &Notification) -> Result<String, Error> {
+        let name = notification.kind.name();
+
+        self.env
+            .get_template(name)
+            .and_then(|x| x.render(notification))
+            .map_err(|e| Error::RenderTemplate(name.to_string(), e))
+    }
+}
+
+/// Escapes the characters of `text` that Markdown would interpret, and joins its lines, so
+/// untrusted values can't format or break up a message.
+fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '\r' | '\n' => escaped.push(' '),
+            '\\' | '`' | '*' | '_' | '{' | '}' | '[' | ']' | '(' | ')' | '<' | '>' | '#' | '+'
+            | '-' | '.' | '!' | '|' | '~' | '@' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Returns the built-in template for notifications of the given `kind` in `format`.
+fn default_template(format: NotificationFormat, kind: Kind) -> &'static str {
+    match (format, kind) {
+        (NotificationFormat::Irc, Kind::Attachment) => {
+            "\x0310> {% if subject %}“\x0f{{ subject }}\x0310” from\x0f {{ sender }}\x0310: \
+             {% else %}Mail received from\x0f {{ sender }}\x0310 {% endif %}{{ url }}\
+             {% if signature %} \x0304(malware detected: {{ signature }})\x0f{% endif %}"
+        }
+        (NotificationFormat::Irc, Kind::Malware) => {
+            "\x0304> Malware {{ signature }} detected in attachment from\x0f {{ sender }}\x0304, \
+             it was {{ outcome }}\x0f"
+        }
+        (NotificationFormat::Irc, Kind::Archive) => {
+            "\x0310> {% if subject %}“\x0f{{ subject }}\x0310” from\x0f {{ sender }}\x0310, \
+             {% else %}Mail received from\x0f {{ sender }}\x0310, {% endif %}{{ count }} files \
+             from {{ archive }}: {{ urls | join(\" \") }}\
+             {% if infected %} \x0304(malware detected in {{ infected }} files)\x0f{% endif %}"
+        }
+        (NotificationFormat::Irc, Kind::Summary) => {
+            "\x0310> {% if subject %}“\x0f{{ subject }}\x0310” from\x0f {{ sender }}\x0310 with \
+             {% else %}Mail received from\x0f {{ sender }}\x0310 with {% endif %}{{ count }} \
+             attachments ({{ size }}): {{ urls | join(\" \") }}\
+             {% if more %} and {{ more }} more{% if summary_url %} at {{ summary_url }}{% endif %}{% endif %}\
+             {% if infected %} \x0304(malware detected in {{ infected }} attachments)\x0f{% endif %}"
+        }
+        (NotificationFormat::Irc, Kind::Body) => {
+            "\x0310> {% if subject %}“\x0f{{ subject }}\x0310” from\x0f {{ sender }}\x0310: \
+             {% else %}Mail received from\x0f {{ sender }}\x0310 {% endif %}{{ url }}\
+             {% if excerpt %} \x0314— {{ excerpt }}\x0f{% endif %}"
+        }
+        (NotificationFormat::Irc, Kind::Link) => {
+            "\x0310> {% if subject %}“\x0f{{ subject }}\x0310” from\x0f {{ sender }}\x0310 links to \
+             {% else %}Link received from\x0f {{ sender }}\x0310: {% endif %}{{ url }}"
+        }
+        (NotificationFormat::Irc, Kind::Takedown) => "\x0310> Attachment taken down:\x0f {{ key }}",
+        (NotificationFormat::Plain, Kind::Attachment) => {
+            "> {% if subject %}“{{ subject }}” from {{ sender }}: {% else %}Mail received from \
+             {{ sender }} {% endif %}{{ url }}\
+             {% if signature %} (malware detected: {{ signature }}){% endif %}"
+        }
+        (NotificationFormat::Plain, Kind::Malware) => {
+            "> Malware {{ signature }} detected in attachment from {{ sender }}, it was {{ outcome }}"
+        }
+        (NotificationFormat::Plain, Kind::Archive) => {
+            "> {% if subject %}“{{ subject }}” from {{ sender }}, {% else %}Mail received from \
+             {{ sender }}, {% endif %}{{ count }} files from {{ archive }}: {{ urls | join(\" \") }}\
+             {% if infected %} (malware detected in {{ infected }} files){% endif %}"
+        }
+        (NotificationFormat::Plain, Kind::Summary) => {
+            "> {% if subject %}“{{ subject }}” from {{ sender }} with {% else %}Mail received from \
+             {{ sender }} with {% endif %}{{ count }} attachments ({{ size }}): {{ urls | join(\" \") }}\
+             {% if more %} and {{ more }} more{% if summary_url %} at {{ summary_url }}{% endif %}{% endif %}\
+             {% if infected %} (malware detected in {{ infected }} attachments){% endif %}"
+        }
+        (NotificationFormat::Plain, Kind::Body) => {
+            "> {% if subject %}“{{ subject }}” from {{ sender }}: {% else %}Mail received from \
+             {{ sender }} {% endif %}{{ url }}{% if excerpt %} — {{ excerpt }}{% endif %}"
+        }
+        (NotificationFormat::Plain, Kind::Link) => {
+            "> {% if subject %}“{{ subject }}” from {{ sender }} links to {% else %}Link received \
+             from {{ sender }}: {% endif %}{{ url }}"
+        }
+        (NotificationFormat::Plain, Kind::Takedown) => "> Attachment taken down: {{ key }}",
+        (NotificationFormat::Markdown, Kind::Attachment) => {
+            "{% if subject %}**{{ subject | escape_markdown }}** from \
+             {{ sender | escape_markdown }}{% else %}Mail received from \
+             {{ sender | escape_markdown }}{% endif %}: \
+             [{{ (filename or \"attachment\") | escape_markdown }}]({{ url }}) ({{ size }})\
+             {% if signature %} ⚠️ malware detected: `{{ signature }}`{% endif %}"
+        }
+        (NotificationFormat::Markdown, Kind::Malware) => {
+            "⚠️ Malware `{{ signature }}` detected in attachment from \
+             {{ sender | escape_markdown }}, it was {{ outcome }}"
+        }
+        (NotificationFormat::Markdown, Kind::Archive) => {
+            "{% if subject %}**{{ subject | escape_markdown }}** from \
+             {{ sender | escape_markdown }}{% else %}Mail received from \
+             {{ sender | escape_markdown }}{% endif %}, {{ count }} files from \
+             {{ archive | escape_markdown }}:\
+             {% for url in urls %}\n- {{ url }}{% endfor %}\
+             {% if infected %}\n\n⚠️ malware detected in {{ infected }} files{% endif %}"
+        }
+        (NotificationFormat::Markdown, Kind::Summary) => {
+            "{% if subject %}**{{ subject | escape_markdown }}** from \
+             {{ sender | escape_markdown }}{% else %}Mail received from \
+             {{ sender | escape_markdown }}{% endif %} with {{ count }} attachments ({{ size }}):\
+             {% for url in urls %}\n- {{ url }}{% endfor %}\
+             {% if more %}\n- and {{ more }} more{% if summary_url %} at {{ summary_url }}{% endif %}{% endif %}\
+             {% if infected %}\n\n⚠️ malware detected in {{ infected }} attachments{% endif %}"
+        }
+        (NotificationFormat::Markdown, Kind::Body) => {
+            "{% if subject %}**{{ subject | escape_markdown }}** from \
+             {{ sender | escape_markdown }}{% else %}Mail received from \
+             {{ sender | escape_markdown }}{% endif %}: \
+             [{{ (filename or \"body\") | escape_markdown }}]({{ url }})\
+             {% if excerpt %}\n> {{ excerpt | escape_markdown }}{% endif %}"
+        }
+        (NotificationFormat::Markdown, Kind::Link) => {
+            "{% if subject %}**{{ subject | escape_markdown }}** from \
+             {{ sender | escape_markdown }} links to{% else %}Link received from \
+             {{ sender | escape_markdown }}:{% endif %} {{ url }}"
+        }
+        (NotificationFormat::Markdown, Kind::Takedown) => "Attachment taken down: `{{ key }}`",
+    }
+}
+
+/// Returns the public URL of the object with the given `key`, if the bucket has one.
+fn public_url(s3_config: &AwsS3Config, key: &str) -> Option<String> {
+    s3_config.object_url(key).map(String::from)
+}
+
+/// Returns the number of the attachments described by `reports` that malware was detected in.
+fn infected(reports: &[AttachmentReport]) -> usize {
+    reports
+        .iter()
+        .filter(|x| x.scan.as_ref().is_some_and(|x| x.signature.is_some()))
+        .count()
+}
+
+/// Splits `message` into lines of at most `max_length` bytes, breaking at spaces where possible.
+///
+/// Continuation lines start with the IRC formatting codes active at the end of the previous
+/// line, as clients reset the formatting at the start of each message.
+pub fn split_message(message: &str, max_length: usize) -> Vec<String> {
+    let max_length = max_length.max(1);
+    let mut lines = vec![];
+    let mut line = String::new();
+    let mut prefix = String::new();
+
+    for word in message.split(' ') {
+        if line.len() > prefix.len() && line.len() + 1 + word.len() > max_length {
+            prefix = Formatting::of(&line).codes();
+            lines.push(std::mem::replace(&mut line, prefix.clone()));
+        }
+
+        if line.len() > prefix.len() {
+            line.push(' ');
+        }
+
+        line.push_str(word);
+
+        // Words longer than a line are broken up at character boundaries.
+        while line.len() > max_length {
+            let rest = line.split_off(break_index(&line, max_length));
+
+            prefix = Formatting::of(&line).codes();
+            lines.push(std::mem::replace(&mut line, format!("{prefix}{rest}")));
+        }
+    }
+
+    if line.len() > prefix.len() || lines.is_empty() {
+        lines.push(line);
+    }
+
+    lines
+}
+
+/// Returns the index to break `line` at, at most `max_length` where possible but after the
+/// leading formatting codes, and neither inside a character nor inside a formatting code.
+fn break_index(line: &str, max_length: usize) -> usize {
+    let codes: Vec<_> = formatting_codes(line)
+        .map(|(idx, code)| idx..idx + code.len())
+        .collect();
+    let mut min = 0;
+
+    for code in &codes {
+        if code.start != min {
+            break;
+        }
+
+        min = code.end;
+    }
+    let breakable = |idx: usize| {
+        line.is_char_boundary(idx) && !codes.iter().any(|x| x.start < idx && idx < x.end)
+    };
+
+    (min + 1..=max_length.min(line.len()))
+        .rev()
+        .chain(max_length + 1..line.len())
+        .find(|&idx| breakable(idx))
+        .unwrap_or(line.len())
+}
+
+/// Returns the IRC formatting codes in `line` along with their byte offsets.
+fn formatting_codes(line: &str) -> impl Iterator<Item = (usize, &str)> {
+    let bytes = line.as_bytes();
+    let mut idx = 0;
+
+    std::iter::from_fn(move || {
+        while idx < bytes.len() {
+            let start = idx;
+
+            idx += 1;
+
+            match bytes[start] {
+                0x02 | 0x0f | 0x11 | 0x16 | 0x1d | 0x1e | 0x1f => {}
+                0x03 => {
+                    let digits = |idx: usize| {
+                        bytes[idx..]
+                            .iter()
+                            .take(2)
+                            .take_while(|x| x.is_ascii_digit())
+                            .count()
+                    };
+                    let foreground = digits(idx);
+
+                    idx += foreground;
+
+                    if foreground > 0 && bytes.get(idx) == Some(&b',') && digits(idx + 1) > 0 {
+                        idx += 1 + digits(idx + 1);
+                    }
+                }
+                _ => continue,
+            }
+
+            return Some((start, &line[start..idx]));
+        }
+
+        None
+    })
+}
+
+/// The IRC formatting active at some point of a message.
+#[derive(Debug, Default)]
+struct Formatting {
+    /// The active foreground and background colours.
+    colour: Option<(u8, Option<u8>)>,
+    /// The active bold, italics, underline, strikethrough, monospace and reverse codes.
+    toggles: Vec<u8>,
+}
+
+impl Formatting {
+    /// Returns the formatting active at the end of `line`.
+    fn of(line: &str) -> Self {
+        let mut formatting = Self::default();
+
+        for (_, code) in formatting_codes(line) {
+            match code.as_bytes()[0] {
+                0x0f => formatting = Self::default(),
+                0x03 => {
+                    let mut colours = code[1..].split(',').map(|x| x.parse().ok());
+
+                    formatting.colour = match colours.next().flatten() {
+                        Some(foreground) => {
+                            let background = colours.next().flatten().or_else(|| {
+                                formatting.colour.and_then(|(_, background)| background)
+                            });
+
+                            Some((foreground, background))
+                        }
+                        None => None,
+                    };
+                }
+                toggle => {
+                    if let Some(idx) = formatting.toggles.iter().position(|&x| x == toggle) {
+                        formatting.toggles.remove(idx);
+                    } else {
+                        formatting.toggles.push(toggle);
+                    }
+                }
+            }
+        }
+
+        formatting
+    }
+
+    /// Returns the codes that restore this formatting.
+    fn codes(&self) -> String {
+        let mut codes: String = self.toggles.iter().map(|&x| char::from(x)).collect();
+
+        match self.colour {
+            Some((foreground, Some(background))) => {
+                codes.push_str(&format!("\x03{foreground:02},{background:02}"));
+            }
+            Some((foreground, None)) => codes.push_str(&format!("\x03{foreground:02}")),
+            None => {}
+        }
+
+        codes
+    }
+}
+
+/// Returns the given `size` in bytes in a human-readable form, e.g. `3.4 MiB`.
+#[allow(clippy::cast_precision_loss)]
+fn human_size(size: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+
+    if size < 1024 {
+        return format!("{size} B");
+    }
+
+    let mut value = size as f64 / 1024.0;
+    let mut unit = UNITS[0];
+
+    for next in &UNITS[1..] {
+        if value < 1024.0 {
+            break;
+        }
+
+        value /= 1024.0;
+        unit = next;
+    }
+
+    format!("{value:.1} {unit}")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{mime::Detection, store::Outcome};
+
+    use super::*;
+
+    const INFO: MailInfo<'static> = MailInfo {
+        message_id: None,
+        sender: Some("*eve*@example.org"),
+        recipient: None,
+        subject: Some("[click](https://evil.example/)\n# hi"),
+    };
+
+    fn bucket(public_url: Option<&str>) -> AwsS3Config {
+        AwsS3Config {
+            bucket_name: "bucket".to_string(),
+            public_url: public_url.map(|x| x.parse().unwrap()),
+        }
+    }
+
+    fn report(key: &str) -> AttachmentReport {
+        let detection = Detection {
+            mime_type: "image/png",
+            sniffed: "image/png",
+            declared: None,
+            mismatch: false,
+        };
+        let mut report = AttachmentReport::new(Some("a_b.png"), &detection, 2048);
+
+        report.record.key = Some(key.to_string());
+        report.record.thumbnail_key = Some(format!("thumbnails/{key}"));
+        report.record.outcome = Outcome::Uploaded;
+
+        report
+    }
+
+    #[test]
+    fn links_objects_under_the_public_url() {
+        let s3_config = bucket(Some("https://files.example.org/"));
+        let notification = Notification::attachment(&report("abc.png"), &INFO, &s3_config).unwrap();
+
+        assert_eq!(
+            notification.url.as_deref(),
+            Some("https://files.example.org/abc.png")
+        );
+        assert_eq!(
+            notification.thumbnail.as_deref(),
+            Some("https://files.example.org/thumbnails/abc.png")
+        );
+
+        let notification = Notification::takedown("abc.png", &bucket(None));
+
+        assert!(notification.url.is_none());
+    }
+
+    #[test]
+    fn escapes_markdown() {
+        assert_eq!(
+            escape_markdown("[a](b) *c*\r\n`d`"),
+            "\\[a\\]\\(b\\) \\*c\\*  \\`d\\`"
+        );
+    }
+
+    #[test]
+    fn escapes_mail_values_in_markdown_templates() {
+        let templates = Templates::new(NotificationFormat::Markdown, &HashMap::new()).unwrap();
+        let s3_config = bucket(Some("https://files.example.org/"));
+        let notification = Notification::attachment(&report("abc.png"), &INFO, &s3_config).unwrap();
+
+        assert_eq!(
+            templates.render(&notification).unwrap(),
+            "**\\[click\\]\\(https://evil\\.example/\\) \\# hi** from \\*eve\\*\\@example\\.org: \
+             [a\\_b\\.png](https://files.example.org/abc.png) (2.0 KiB)"
+        );
+
+        let mut notification = Notification::link("https://example.org/", &INFO);
+
+        notification.subject = None;
+
+        assert_eq!(
+            templates.render(&notification).unwrap(),
+            "Link received from \\*eve\\*\\@example\\.org: https://example.org/"
+        );
+    }
+
+    #[test]
+    fn splits_at_spaces() {
+        assert_eq!(split_message("aaa bbb ccc", 7), ["aaa bbb", "ccc"]);
+        assert_eq!(split_message("aaaaaaaaa b", 4), ["aaaa", "aaaa", "a b"]);
+        assert_eq!(split_message("ää", 3), ["ä", "ä"]);
+        assert_eq!(split_message("", 10), [""]);
+    }
+
+    #[test]
+    fn keeps_formatting_on_continuation_lines() {
+        let lines = split_message("\x0310aaa \x02bbb ccc\x0f ddd", 12);
+
+        assert_eq!(lines, ["\x0310aaa \x02bbb", "\x02\x0310ccc\x0f ddd"]);
+    }
+
+    #[test]
+    fn keeps_formatting_in_broken_up_words() {
+        let lines = split_message("\x034,2aaaaaaaa", 8);
+
+        assert_eq!(lines, ["\x034,2aaaa", "\x0304,02aa", "\x0304,02aa"]);
+        assert!(lines.iter().all(|x| x.len() <= 8));
+    }
+
+    #[test]
+    fn doesnt_break_inside_formatting_codes() {
+        assert_eq!(
+            split_message("a\x0310bbb", 3),
+            ["a", "\x0310b", "\x0310b", "\x0310b"]
+        );
+    }
+
+    #[test]
+    fn tracks_formatting() {
+        assert_eq!(Formatting::of("\x0304,02a\x0305b").codes(), "\x0305,02");
+        assert_eq!(Formatting::of("\x0304a\x03b").codes(), "");
+        assert_eq!(Formatting::of("\x02\x1da\x02").codes(), "\x1d");
+        assert_eq!(Formatting::of("\x02\x0304a\x0fb").codes(), "");
+    }
+}
@@ -0,0 +1,222 @@
+use std::io::Cursor;
+
+use image::ImageReader;
+use mail_parser::{Message, MessagePart, MimeHeaders, PartType};
+
+use crate::config::PartsConfig;
+
+/// A part of a mail that is processed as an attachment.
+#[derive(Debug, Clone, Copy)]
+pub struct Part<'a> {
+    /// The MIME part.
+    pub part: &'a MessagePart<'a>,
+    /// Whether the part is displayed inline, rather than attached.
+    pub inline: bool,
+    /// Whether the part was extracted from a forwarded message.
+    pub forwarded: bool,
+}
+
+/// Returns the parts of `mail` that are processed as attachments according to `config`.
+///
+/// The attachments of forwarded `message/rfc822` parts are included in place of the forwarded
+/// messages, up to the configured nesting depth.
+pub fn collect<'a>(mail: &'a Message<'a>, config: &PartsConfig) -> Vec<Part<'a>> {
+    let mut parts = vec![];
+
+    collect_nested(mail, config, 0, &mut parts);
+
+    parts
+}
+
+fn collect_nested<'a>(
+    message: &'a Message<'a>,
+    config: &PartsConfig,
+    depth: usize,
+    parts: &mut Vec<Part<'a>>,
+) {
+    for part in message.attachments() {
+        if let Some(nested) = part.message().filter(|_| config.forwarded) {
+            if depth < config.max_depth {
+                collect_nested(nested, config, depth + 1, parts);
+
+                continue;
+            }
+        }
+
+        let inline = is_inline(part);
+
+        if inline && !config.inline {
+            continue;
+        }
+
+        parts.push(Part {
+            part,
+            inline,
+            forwarded: depth > 0,
+        });
+    }
+}
+
+/// Returns whether `part` is displayed inline, either by its disposition or because it is
+/// referenced by its Content-ID.
+fn is_inline(part: &MessagePart<'_>) -> bool {
+    if matches!(part.body, PartType::InlineBinary(_)) {
+        return true;
+    }
+
+    match part.content_disposition() {
+        Some(disposition) => disposition.is_inline(),
+        None => part.content_id().is_some(),
+    }
+}
+
+/// Returns the reason the inline image with the given `contents` is skipped as too small
+/// according to `config`, if it is.
+pub fn too_small(config: &PartsConfig, mime_type: &str, contents: &[u8]) -> Option<String> {
+    if !mime_type.starts_with("image/") {
+        return None;
+    }
+
+    let size = contents.len() as u64;
+
+    if let Some(min) = config.min_inline_size.filter(|x| size < *x) {
+        return Some(format!(
+            "the inline image is {size} bytes, less than the minimum of {min} bytes"
+        ));
+    }
+
+    let min = config.min_inline_dimension?;
+    let (width, height) = ImageReader::new(Cursor::new(contents))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()?;
+
+    (width < min || height < min).then(|| {
+        format!("the inline image is {width}x{height}, smaller than the minimum of {min}x{min}")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, ImageFormat, RgbImage};
+    use mail_parser::MessageParser;
+
+    use super::*;
+
+    /// Returns a mail with an attachment `name` that forwards the given `forwarded` mail.
+    fn forwarding(name: &str, forwarded: &str) -> String {
+        format!(
+            "From: a@example.com\r\nSubject: {name}\r\nMIME-Version: 1.0\r\n\
+             Content-Type: multipart/mixed; boundary=\"{name}\"\r\n\r\n\
+             --{name}\r\nContent-Type: text/plain\r\n\r\nbody\r\n\
+             --{name}\r\nContent-Type: application/pdf\r\n\
+             Content-Disposition: attachment; filename=\"{name}.pdf\"\r\n\r\n%PDF-1.4\r\n\
+             --{name}\r\nContent-Type: message/rfc822\r\n\r\n{forwarded}\r\n\
+             --{name}--\r\n"
+        )
+    }
+
+    fn config(forwarded: bool, max_depth: usize) -> PartsConfig {
+        PartsConfig {
+            forwarded,
+            max_depth,
+            ..PartsConfig::default()
+        }
+    }
+
+    fn names<'a>(parts: &[Part<'a>]) -> Vec<(Option<&'a str>, bool)> {
+        parts
+            .iter()
+            .map(|x| (x.part.attachment_name(), x.forwarded))
+            .collect()
+    }
+
+    #[test]
+    fn extracts_forwarded_attachments_up_to_max_depth() {
+        let innermost = "From: c@example.com\r\nSubject: innermost\r\n\r\nhello\r\n";
+        let raw = forwarding("outer", &forwarding("inner", innermost));
+        let mail = MessageParser::default().parse(raw.as_bytes()).unwrap();
+
+        // The innermost message has no attachments to extract.
+        assert_eq!(
+            names(&collect(&mail, &config(true, 3))),
+            [(Some("outer.pdf"), false), (Some("inner.pdf"), true)]
+        );
+
+        // Messages nested deeper than the limit are kept as attachments.
+        let parts = collect(&mail, &config(true, 1));
+
+        assert_eq!(
+            names(&parts),
+            [
+                (Some("outer.pdf"), false),
+                (Some("inner.pdf"), true),
+                (None, true)
+            ]
+        );
+        assert!(parts[2].part.message().is_some());
+
+        let parts = collect(&mail, &config(false, 3));
+
+        assert_eq!(names(&parts), [(Some("outer.pdf"), false), (None, false)]);
+        assert!(parts[1].part.message().is_some());
+    }
+
+    #[test]
+    fn keeps_forwarded_messages_by_default() {
+        let raw = forwarding("outer", "Subject: inner\r\n\r\nhello\r\n");
+        let mail = MessageParser::default().parse(raw.as_bytes()).unwrap();
+        let parts = collect(&mail, &PartsConfig::default());
+
+        assert_eq!(names(&parts), [(Some("outer.pdf"), false), (None, false)]);
+    }
+
+    #[test]
+    fn processes_inline_parts_by_default() {
+        let raw = "From: a@example.com\r\nMIME-Version: 1.0\r\n\
+                   Content-Type: multipart/related; boundary=\"b\"\r\n\r\n\
+                   --b\r\nContent-Type: text/html\r\n\r\n<img src=\"cid:logo\">\r\n\
+                   --b\r\nContent-Type: image/png\r\nContent-ID: <logo>\r\n\
+                   Content-Disposition: inline; filename=\"logo.png\"\r\n\r\npng\r\n\
+                   --b--\r\n";
+        let mail = MessageParser::default().parse(raw.as_bytes()).unwrap();
+        let config = PartsConfig {
+            inline: false,
+            ..PartsConfig::default()
+        };
+
+        assert!(collect(&mail, &PartsConfig::default())[0].inline);
+        assert!(collect(&mail, &config).is_empty());
+    }
+
+    /// Returns a PNG of the given dimensions.
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut contents = vec![];
+
+        DynamicImage::ImageRgb8(RgbImage::new(width, height))
+            .write_to(&mut Cursor::new(&mut contents), ImageFormat::Png)
+            .unwrap();
+        contents
+    }
+
+    #[test]
+    fn skips_small_inline_images() {
+        let config = PartsConfig {
+            min_inline_size: Some(100),
+            min_inline_dimension: Some(16),
+            ..PartsConfig::default()
+        };
+        let large = png(64, 64);
+
+        assert!(large.len() >= 100);
+        assert_eq!(too_small(&config, "image/png", &large), None);
+        assert!(too_small(&config, "image/png", &large[..50]).is_some());
+        assert!(too_small(&config, "image/png", &png(64, 8)).is_some_and(|x| x.contains("64x8")));
+        // Only images are checked.
+        assert_eq!(too_small(&config, "application/pdf", b"%PDF"), None);
+        // Images that can't be decoded aren't skipped for their dimensions.
+        assert_eq!(too_small(&config, "image/png", &[0; 200]), None);
+        assert_eq!(too_small(&PartsConfig::default(), "image/png", b"x"), None);
+    }
+}
@@ -1,105 +1,609 @@
-use std::process::Command;
+use core::fmt::Debug;
+use std::{
+    fs,
+    num::NonZeroUsize,
+    sync::{Arc, LazyLock},
+    thread,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use tempfile::{NamedTempFile, TempPath};
+use tokio::sync::Semaphore;
+use tracing::{debug, error, warn};
+
+use crate::{
+    config::{Policy, PostProcessConfig},
+    mime,
+    report::{ProcessorReport, ProcessorStatus},
+    Error,
+};
 
-use tempfile::TempPath;
-use tracing::debug;
+mod document;
+mod external;
+mod native;
+mod thumbnail;
+mod transcode;
 
-use crate::Error;
+/// The default maximum duration of a single post-processor run.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// An artifact derived from a post-processed file, uploaded alongside the original.
+pub struct Derived {
+    /// The name of the artifact, appended to the key of the original object.
+    pub name: &'static str,
+    /// The path to the artifact.
+    pub path: TempPath,
+    /// The MIME type of the artifact.
+    pub mime_type: &'static str,
+}
+
+impl Derived {
+    /// Returns the key of the artifact derived from the object with the given `key`.
+    pub fn key(&self, key: &str) -> String {
+        format!("{key}.{}", self.name)
+    }
+}
+
+/// The output of a post-processor.
+pub struct Output {
+    /// The path to the processed file.
+    pub path: TempPath,
+    /// Additional artifacts derived from the processed file.
+    pub derived: Vec<Derived>,
+    /// The MIME type of the processed file, if the post-processor converted it.
+    pub mime_type: Option<&'static str>,
+    /// The file extension of the processed file, without a leading dot, if the post-processor
+    /// converted it.
+    pub extension: Option<&'static str>,
+    /// Notes on what the post-processor did, for the processing report.
+    pub notes: Vec<String>,
+    /// Whether the post-processor passed the file through as it was, e.g. because its contents
+    /// turned out to be nothing it handles. Such files don't count as sanitized, and their type
+    /// isn't detected again.
+    pub unchanged: bool,
+}
+
+impl Output {
+    /// Returns the output of a post-processor that passed the file at `path` through as it was,
+    /// noting the `reason`.
+    pub fn unchanged(path: TempPath, reason: &str) -> Self {
+        Output {
+            notes: vec![reason.to_string()],
+            unchanged: true,
+            ..path.into()
+        }
+    }
+}
+
+impl From<TempPath> for Output {
+    fn from(path: TempPath) -> Self {
+        Output {
+            path,
+            derived: vec![],
+            mime_type: None,
+            extension: None,
+            notes: vec![],
+            unchanged: false,
+        }
+    }
+}
+
+#[async_trait]
+pub trait PostProcessor: Send + Sync {
+    /// Returns the name of the post-processor.
+    fn name(&self) -> &str;
 
-pub trait PostProcessor: Send {
     /// Checks whether the post-processor is functional.
     fn check(&self) -> Result<bool, Error>;
 
     /// Returns true if the postprocessor should run for the provided `mime_type`.
     fn applicable(&self, mime_type: &'static str) -> bool;
 
-    fn apply(&self, path: TempPath) -> Result<TempPath, Error>;
-}
+    async fn apply(&self, path: TempPath) -> Result<Output, Error>;
 
-use core::fmt::Debug;
+    /// Returns the maximum duration a single run of the post-processor may take.
+    fn timeout(&self) -> Duration {
+        DEFAULT_TIMEOUT
+    }
 
-impl Debug for dyn PostProcessor {
-    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "PostProcessor{{}}")
+    /// Returns what to do with an attachment when the post-processor fails on it.
+    fn policy(&self) -> Policy {
+        Policy::Required
+    }
+
+    /// Returns whether the post-processor removes privacy-sensitive metadata from the files it
+    /// doesn't pass through unchanged.
+    fn sanitizes(&self) -> bool {
+        false
     }
 }
 
-struct RotateImageExif;
+/// The result of running the post-processing pipeline on a file.
+pub struct Pipeline {
+    /// The path to the processed file, or `None` if a required post-processor failed.
+    pub path: Option<TempPath>,
+    /// Additional artifacts derived from the processed file.
+    pub derived: Vec<Derived>,
+    /// The MIME type of the processed file.
+    pub mime_type: &'static str,
+    /// The file extension of the processed file, if a post-processor converted it.
+    pub extension: Option<&'static str>,
+    /// The post-processors that ran, in order.
+    pub reports: Vec<ProcessorReport>,
+    /// Whether a metadata-sanitizing post-processor ran successfully.
+    pub sanitized: bool,
+}
 
-impl PostProcessor for RotateImageExif {
-    fn applicable(&self, mime_type: &str) -> bool {
-        matches!(
-            mime_type,
-            "image/jpeg" | "image/png" | "image/heic" | "image/webp"
-        )
-    }
+/// Runs the `processors` applicable to `mime_type` on the file at `path`, in order.
+///
+/// Post-processors that convert the file change the MIME type the following ones are matched
+/// against, and the type is detected again once all of them ran if any rewrote the file. When a
+/// required post-processor fails the pipeline stops and the file is discarded. When a
+/// best-effort post-processor fails, the pipeline continues with the output of the previous
+/// stage.
+pub async fn run(
+    processors: &[Box<dyn PostProcessor>],
+    path: TempPath,
+    mime_type: &'static str,
+) -> Pipeline {
+    let mut pipeline = Pipeline {
+        path: Some(path),
+        derived: vec![],
+        mime_type,
+        extension: None,
+        reports: vec![],
+        sanitized: false,
+    };
+    let mut rewritten = false;
 
-    fn check(&self) -> Result<bool, Error> {
-        match Command::new("exiftran").arg("-h").output() {
-            Ok(output) => Ok(output.status.success()),
-            Err(_) => Err(Error::ToolCheckFailed("exiftran".to_string())),
+    for processor in processors {
+        if !processor.applicable(pipeline.mime_type) {
+            continue;
         }
+
+        let Some(path) = pipeline.path.take() else {
+            break;
+        };
+
+        let policy = processor.policy();
+
+        // Keep a copy of the previous stage's output in case a best-effort processor fails.
+        let backup = match policy {
+            Policy::Required => None,
+            Policy::BestEffort => match backup(&path) {
+                Ok(backup) => Some(backup),
+                Err(err) => {
+                    error!(%err, "could not back up file before post-processing");
+
+                    pipeline.path = Some(path);
+                    continue;
+                }
+            },
+        };
+
+        let timeout = processor.timeout();
+        let result = tokio::time::timeout(timeout, processor.apply(path))
+            .await
+            .unwrap_or(Err(Error::PostProcessTimeout(
+                processor.name().to_string(),
+                timeout,
+            )));
+
+        let (status, error, notes) = match result {
+            Ok(output) => {
+                pipeline.path = Some(output.path);
+                pipeline.derived.extend(output.derived);
+
+                if let Some(mime_type) = output.mime_type {
+                    debug!(processor = processor.name(), %mime_type, "post-processor converted file");
+
+                    pipeline.mime_type = mime_type;
+                }
+
+                if let Some(extension) = output.extension {
+                    pipeline.extension = Some(extension);
+                }
+
+                pipeline.sanitized |= processor.sanitizes() && !output.unchanged;
+                rewritten |= !output.unchanged;
+
+                (ProcessorStatus::Applied, None, output.notes)
+            }
+            Err(err) => {
+                error!(%err, processor = processor.name(), ?policy, "post-processing failed");
+
+                pipeline.path = backup;
+
+                (ProcessorStatus::Failed, Some(err.to_string()), vec![])
+            }
+        };
+
+        pipeline.reports.push(ProcessorReport {
+            name: processor.name().to_string(),
+            policy,
+            status,
+            error,
+            notes,
+        });
     }
 
-    fn apply(&self, path: TempPath) -> Result<TempPath, Error> {
-        debug!("running exiftran on {path}", path = &path.display());
+    // External commands can convert files without declaring it, so the declared type is only
+    // kept as long as the contents still match it.
+    if let Some(path) = pipeline.path.as_ref().filter(|_| rewritten) {
+        if let Some(mime_type) = redetect(path, pipeline.mime_type) {
+            warn!(
+                declared = pipeline.mime_type,
+                detected = mime_type,
+                "post-processed file does not match its declared type"
+            );
 
-        match Command::new("exiftran")
-            .args(["-i", "-a"])
-            .arg(&path)
-            .status()
-        {
-            Ok(status) if status.success() => Ok(path),
-            _ => Err(Error::PostProcessFailed("exiftran failed".to_string())),
+            pipeline.mime_type = mime_type;
         }
     }
+
+    pipeline
+}
+
+/// Returns the type detected from the contents of the file at `path` if they contradict the
+/// `declared` type.
+///
+/// Detection only tells generic types and containers apart from the more specific types built
+/// on them, so the declared type is kept rather than downgraded to one of those.
+fn redetect(path: &TempPath, declared: &'static str) -> Option<&'static str> {
+    // Generic types match any contents.
+    if !mime::GENERIC_TYPES.contains(&declared) && tree_magic_mini::match_filepath(declared, path) {
+        return None;
+    }
+
+    tree_magic_mini::from_filepath(path).filter(|detected| {
+        !mime::GENERIC_TYPES.contains(detected) && !mime::compatible(declared, detected)
+    })
+}
+
+/// Copies the file at `path` to a new temporary file.
+fn backup(path: &TempPath) -> Result<TempPath, Error> {
+    let file = NamedTempFile::new().map_err(Error::CreateTempFile)?;
+
+    fs::copy(path, file.path())?;
+
+    Ok(file.into_temp_path())
+}
+
+/// Runs the blocking closure `f` on a thread where blocking is acceptable.
+///
+/// Blocking work can't be cancelled and keeps running when its post-processor times out, so the
+/// number of closures running at the same time is limited to the available parallelism.
+async fn blocking<T, F>(f: F) -> Result<T, Error>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, Error> + Send + 'static,
+{
+    static PERMITS: LazyLock<Arc<Semaphore>> = LazyLock::new(|| {
+        let parallelism = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+
+        Arc::new(Semaphore::new(parallelism))
+    });
+
+    let permit = Arc::clone(&PERMITS)
+        .acquire_owned()
+        .await
+        .expect("the semaphore is never closed");
+
+    tokio::task::spawn_blocking(move || {
+        // Only released once the work is done, even if the post-processor timed out.
+        let _permit = permit;
+
+        f()
+    })
+    .await
+    .map_err(|e| Error::PostProcessFailed(format!("post-processor panicked: {e}")))?
+}
+
+impl Debug for dyn PostProcessor {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "PostProcessor{{name: {}}}", self.name())
+    }
 }
 
-struct RemoveExif;
+/// A post-processor with configured overrides.
+struct Configured {
+    /// The wrapped post-processor.
+    inner: Box<dyn PostProcessor>,
+    /// Glob patterns of the MIME types to run on, if overridden.
+    mime_types: Option<Vec<String>>,
+    /// The maximum duration of a single run, if overridden.
+    timeout: Option<Duration>,
+    /// What to do when the post-processor fails, if overridden.
+    policy: Option<Policy>,
+}
 
-impl PostProcessor for RemoveExif {
-    fn applicable(&self, mime_type: &str) -> bool {
-        matches!(
-            mime_type,
-            "image/jpeg"
-                | "image/png"
-                | "image/heic"
-                | "image/webp"
-                | "video/mp4"
-                | "video/heic"
-                | "video/mpeg"
-                | "video/quicktime"
-                | "video/3gpp"
-                | "video/x-msvideo"
-                | "video/x-ms-wmv"
-        )
+#[async_trait]
+impl PostProcessor for Configured {
+    fn name(&self) -> &str {
+        self.inner.name()
     }
 
     fn check(&self) -> Result<bool, Error> {
-        match Command::new("exiftool").arg("-ver").output() {
-            Ok(output) => Ok(output.status.success()),
-            Err(_) => Err(Error::ToolCheckFailed("exiftool".to_string())),
+        self.inner.check()
+    }
+
+    fn applicable(&self, mime_type: &'static str) -> bool {
+        match self.mime_types {
+            Some(ref mime_types) => mime::matches_any(mime_types, mime_type),
+            None => self.inner.applicable(mime_type),
         }
     }
 
-    fn apply(&self, path: TempPath) -> Result<TempPath, Error> {
-        debug!("running exiftool on {path}", path = &path.display());
+    async fn apply(&self, path: TempPath) -> Result<Output, Error> {
+        self.inner.apply(path).await
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout.unwrap_or_else(|| self.inner.timeout())
+    }
+
+    fn policy(&self) -> Policy {
+        self.policy.unwrap_or_else(|| self.inner.policy())
+    }
+
+    fn sanitizes(&self) -> bool {
+        self.inner.sanitizes()
+    }
+}
+
+/// Returns the built-in post-processor named in `config`, if any.
+fn builtin(config: &PostProcessConfig) -> Result<Option<Box<dyn PostProcessor>>, Error> {
+    Ok(match config.name.as_str() {
+        "normalize_orientation" => Some(Box::new(native::NormalizeOrientation)),
+        "strip_metadata" => Some(Box::new(native::StripMetadata)),
+        "strip_pdf_metadata" => Some(Box::new(document::StripPdfMetadata)),
+        "strip_office_metadata" => Some(Box::new(document::StripOfficeMetadata)),
+        "remove_exif" => Some(Box::new(external::RemoveExif)),
+        "thumbnail" => Some(Box::new(thumbnail::Thumbnail::new())),
+        "transcode" => Some(Box::new(transcode::Transcode::new(config)?)),
+        _ => None,
+    })
+}
+
+/// Returns the default post-processing pipeline.
+///
+/// External tools that are unavailable are left out with a warning, and attachments they would
+/// have sanitized are rejected if `reject_unsanitized` is enabled.
+fn defaults() -> Vec<Box<dyn PostProcessor>> {
+    let mut processors: Vec<Box<dyn PostProcessor>> = vec![
+        Box::new(native::NormalizeOrientation),
+        Box::new(native::StripMetadata),
+        Box::new(document::StripPdfMetadata),
+        Box::new(document::StripOfficeMetadata),
+    ];
 
-        match Command::new("exiftool").args(["-all="]).arg(&path).status() {
-            Ok(status) if status.success() => Ok(path),
-            _ => Err(Error::PostProcessFailed("exiftool failed".to_string())),
+    // The external tools cover the formats the native processors can't handle.
+    let external: Vec<Box<dyn PostProcessor>> = vec![Box::new(external::RemoveExif)];
+
+    for processor in external {
+        if !processor.check().unwrap_or(false) {
+            warn!(
+                name = processor.name(),
+                "tool is not available, skipping postprocessor"
+            );
+
+            continue;
         }
+
+        processors.push(processor);
     }
+
+    processors.push(Box::new(thumbnail::Thumbnail::new()));
+    processors
 }
 
-pub fn init() -> Result<Vec<Box<dyn PostProcessor>>, Error> {
+pub fn init(configs: &[PostProcessConfig]) -> Result<Vec<Box<dyn PostProcessor>>, Error> {
     debug!("initializing postprocessors");
 
-    let processors: Vec<Box<dyn PostProcessor>> =
-        vec![Box::new(RotateImageExif), Box::new(RemoveExif)];
+    if configs.is_empty() {
+        return Ok(defaults());
+    }
+
+    let mut processors: Vec<Box<dyn PostProcessor>> = vec![];
+
+    for config in configs.iter().filter(|x| x.enabled) {
+        let (inner, mime_types): (Box<dyn PostProcessor>, _) = match config.command {
+            Some(ref command) => {
+                let mime_types = config.mime_types.as_deref().unwrap_or_default();
+                let processor = external::CommandProcessor::new(
+                    &config.name,
+                    command,
+                    &config.args,
+                    mime_types,
+                );
+
+                (Box::new(processor), None)
+            }
+            None => {
+                let processor = builtin(config)?
+                    .ok_or_else(|| Error::UnknownPostProcessor(config.name.clone()))?;
+
+                (processor, config.mime_types.clone())
+            }
+        };
+
+        // Unlike the default pipeline, explicitly configured post-processors are required.
+        if !inner.check()? {
+            return Err(Error::ToolCheckFailed(config.name.clone()));
+        }
+
+        debug!(name = inner.name(), "enabling postprocessor");
 
-    for processor in &processors {
-        let _ = processor.check()?;
+        processors.push(Box::new(Configured {
+            inner,
+            mime_types,
+            timeout: config.timeout.map(Duration::from_secs),
+            policy: config.policy,
+        }));
     }
 
     Ok(processors)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Cursor, Write},
+        time::Instant,
+    };
+
+    use super::*;
+
+    /// Returns a temporary file with the given `contents`.
+    fn file(contents: &[u8]) -> TempPath {
+        let mut file = NamedTempFile::new().unwrap();
+
+        file.write_all(contents).unwrap();
+        file.into_temp_path()
+    }
+
+    /// Returns a `sh` command post-processor running `script` on text files.
+    fn shell(script: &str, timeout: Duration, policy: Policy) -> Box<dyn PostProcessor> {
+        let args = ["-c", script, "sh", "{input}"].map(String::from);
+
+        Box::new(Configured {
+            inner: Box::new(external::CommandProcessor::new(
+                "shell",
+                "sh",
+                &args,
+                &["text/*".to_string()],
+            )),
+            mime_types: None,
+            timeout: Some(timeout),
+            policy: Some(policy),
+        })
+    }
+
+    #[test]
+    fn leaves_unavailable_tools_out_of_the_defaults() {
+        let available = external::RemoveExif.check().unwrap_or(false);
+        let processors = init(&[]).unwrap();
+
+        assert_eq!(
+            processors.iter().any(|x| x.name() == "remove_exif"),
+            available
+        );
+        assert!(processors.iter().any(|x| x.name() == "strip_metadata"));
+    }
+
+    #[tokio::test]
+    async fn keeps_declared_type_of_untouched_files() {
+        let pipeline = run(&[], file(b"a,b\n1,2\n"), "text/csv").await;
+
+        assert_eq!(pipeline.mime_type, "text/csv");
+    }
+
+    #[tokio::test]
+    async fn keeps_declared_type_of_files_processors_left_untouched() {
+        let mut png = vec![];
+
+        image::RgbImage::new(2, 2)
+            .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+
+        let processors: [Box<dyn PostProcessor>; 2] = [
+            Box::new(native::NormalizeOrientation),
+            Box::new(thumbnail::Thumbnail::new()),
+        ];
+        // The contents don't match the declared type, which would be noticed if the file had
+        // been rewritten.
+        let pipeline = run(&processors, file(&png), "image/jpeg").await;
+
+        assert_eq!(pipeline.mime_type, "image/jpeg");
+        assert_eq!(pipeline.derived.len(), 1);
+        assert!(pipeline
+            .reports
+            .iter()
+            .all(|x| x.status == ProcessorStatus::Applied));
+    }
+
+    #[tokio::test]
+    async fn never_downgrades_declared_types() {
+        let processors = [shell("true", DEFAULT_TIMEOUT, Policy::Required)];
+        let pipeline = run(&processors, file(b"a,b\n1,2\n"), "text/csv").await;
+
+        assert_eq!(pipeline.mime_type, "text/csv");
+    }
+
+    #[tokio::test]
+    async fn detects_undeclared_conversions() {
+        let processors = [shell(
+            "printf '\\211PNG\\r\\n\\032\\n' > \"$1\"",
+            DEFAULT_TIMEOUT,
+            Policy::Required,
+        )];
+        let pipeline = run(&processors, file(b"hello\n"), "text/plain").await;
+
+        assert_eq!(pipeline.mime_type, "image/png");
+    }
+
+    #[tokio::test]
+    async fn keeps_input_when_best_effort_processor_times_out() {
+        let processors = [shell(
+            "echo changed > \"$1\"; sleep 5",
+            Duration::from_millis(200),
+            Policy::BestEffort,
+        )];
+        let pipeline = run(&processors, file(b"original\n"), "text/plain").await;
+
+        assert_eq!(fs::read(pipeline.path.unwrap()).unwrap(), b"original\n");
+        assert_eq!(pipeline.reports[0].status, ProcessorStatus::Failed);
+        assert!(pipeline.reports[0]
+            .error
+            .as_deref()
+            .unwrap()
+            .contains("timed out"));
+    }
+
+    #[tokio::test]
+    async fn discards_file_when_required_processor_times_out() {
+        let processors = [shell(
+            "sleep 5",
+            Duration::from_millis(200),
+            Policy::Required,
+        )];
+        let started = Instant::now();
+        let pipeline = run(&processors, file(b"original\n"), "text/plain").await;
+
+        assert!(pipeline.path.is_none());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn kills_commands_that_time_out() {
+        let marker = NamedTempFile::new().unwrap().into_temp_path();
+        let script = format!("sleep 1; echo alive > {}", marker.display());
+        let processors = [shell(
+            &script,
+            Duration::from_millis(200),
+            Policy::BestEffort,
+        )];
+
+        run(&processors, file(b"original\n"), "text/plain").await;
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+
+        assert!(fs::read(&marker).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn kills_processes_started_by_commands_that_time_out() {
+        let marker = NamedTempFile::new().unwrap().into_temp_path();
+        let script = format!("(sleep 1; echo alive > {}) & wait", marker.display());
+        let processors = [shell(
+            &script,
+            Duration::from_millis(200),
+            Policy::BestEffort,
+        )];
+
+        run(&processors, file(b"original\n"), "text/plain").await;
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+
+        assert!(fs::read(&marker).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,343 @@
+use std::{fs, io::Write};
+
+use async_trait::async_trait;
+use lopdf::{Document, Object};
+use tempfile::{NamedTempFile, TempPath};
+use tracing::debug;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use super::{blocking, Output, PostProcessor};
+use crate::{mime, Error};
+
+/// PDF dictionary keys that refer to metadata rather than content.
+///
+/// `Metadata` holds the XMP streams of the catalog, pages and images, `PieceInfo` holds private
+/// data of the application that produced the document, and `Creator` names the author of
+/// optional content configurations.
+const PDF_METADATA_KEYS: &[&[u8]] = &[b"Metadata", b"PieceInfo", b"Creator"];
+
+/// The MIME types of documents that are ZIP packages.
+///
+/// Office Open XML documents are only sniffed as ZIP archives, so those are checked as well.
+const PACKAGE_MIME_TYPES: &[&str] = &[
+    "application/zip",
+    "application/vnd.openxmlformats-officedocument.*",
+    "application/vnd.oasis.opendocument.*",
+];
+
+/// The parts of an Office Open XML package that hold metadata, along with the empty parts they
+/// are replaced with.
+const OOXML_METADATA_PARTS: &[(&str, &str)] = &[
+    (
+        "docProps/core.xml",
+        concat!(
+            r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>"#,
+            r#"<cp:coreProperties xmlns:cp="http://schemas.openxmlformats.org/package/2006/metadata/core-properties" "#,
+            r#"xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:dcterms="http://purl.org/dc/terms/" "#,
+            r#"xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance"/>"#,
+        ),
+    ),
+    (
+        "docProps/app.xml",
+        concat!(
+            r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>"#,
+            r#"<Properties xmlns="http://schemas.openxmlformats.org/officeDocument/2006/extended-properties" "#,
+            r#"xmlns:vt="http://schemas.openxmlformats.org/officeDocument/2006/docPropsVTypes"/>"#,
+        ),
+    ),
+    (
+        "docProps/custom.xml",
+        concat!(
+            r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>"#,
+            r#"<Properties xmlns="http://schemas.openxmlformats.org/officeDocument/2006/custom-properties" "#,
+            r#"xmlns:vt="http://schemas.openxmlformats.org/officeDocument/2006/docPropsVTypes"/>"#,
+        ),
+    ),
+];
+
+/// The parts of an OpenDocument package that hold metadata, along with the empty parts they are
+/// replaced with.
+const ODF_METADATA_PARTS: &[(&str, &str)] = &[(
+    "meta.xml",
+    concat!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+        r#"<office:document-meta xmlns:office="urn:oasis:names:tc:opendocument:xmlns:office:1.0" "#,
+        r#"office:version="1.2"><office:meta/></office:document-meta>"#,
+    ),
+)];
+
+/// Converts a PDF error into a post-processing error.
+fn pdf_error(err: lopdf::Error) -> Error {
+    Error::PostProcessFailed(format!("PDF processing failed: {err}"))
+}
+
+/// Converts a ZIP error into a post-processing error.
+fn zip_error(err: zip::result::ZipError) -> Error {
+    Error::PostProcessFailed(format!("document processing failed: {err}"))
+}
+
+/// Removes the document information dictionary and XMP metadata from PDFs.
+///
+/// The document is rewritten from scratch, which also drops the earlier revisions kept by
+/// incremental updates.
+pub struct StripPdfMetadata;
+
+#[async_trait]
+impl PostProcessor for StripPdfMetadata {
+    fn name(&self) -> &str {
+        "strip_pdf_metadata"
+    }
+
+    fn applicable(&self, mime_type: &str) -> bool {
+        mime_type == "application/pdf"
+    }
+
+    fn check(&self) -> Result<bool, Error> {
+        Ok(true)
+    }
+
+    async fn apply(&self, path: TempPath) -> Result<Output, Error> {
+        blocking(move || Self::strip(path)).await
+    }
+
+    fn sanitizes(&self) -> bool {
+        true
+    }
+}
+
+impl StripPdfMetadata {
+    /// Removes the metadata of the PDF at `path`, in place.
+    fn strip(path: TempPath) -> Result<Output, Error> {
+        debug!("stripping metadata from {path}", path = &path.display());
+
+        let mut document = Document::load(&path).map_err(pdf_error)?;
+
+        if document.is_encrypted() {
+            return Err(Error::PostProcessFailed(
+                "could not decrypt PDF to strip its metadata".to_string(),
+            ));
+        }
+
+        document.trailer.remove(b"Info");
+        // The action runs before references are followed, so the removed metadata streams are
+        // never reached and get pruned below.
+        document.traverse_objects(|object| {
+            let dict = match object {
+                Object::Dictionary(dict) => dict,
+                Object::Stream(stream) => &mut stream.dict,
+                _ => return,
+            };
+
+            for key in PDF_METADATA_KEYS {
+                dict.remove(key);
+            }
+        });
+        document.prune_objects();
+        document.save(&path)?;
+
+        Ok(path.into())
+    }
+}
+
+/// Replaces the document properties of Office Open XML and OpenDocument files with empty ones.
+///
+/// ZIP archives that are neither are passed through unchanged.
+pub struct StripOfficeMetadata;
+
+#[async_trait]
+impl PostProcessor for StripOfficeMetadata {
+    fn name(&self) -> &str {
+        "strip_office_metadata"
+    }
+
+    fn applicable(&self, mime_type: &str) -> bool {
+        mime::matches_any(PACKAGE_MIME_TYPES, mime_type)
+    }
+
+    fn check(&self) -> Result<bool, Error> {
+        Ok(true)
+    }
+
+    async fn apply(&self, path: TempPath) -> Result<Output, Error> {
+        blocking(move || Self::strip(path)).await
+    }
+
+    fn sanitizes(&self) -> bool {
+        true
+    }
+}
+
+impl StripOfficeMetadata {
+    /// Rewrites the package at `path` without its metadata.
+    fn strip(path: TempPath) -> Result<Output, Error> {
+        let mut archive = ZipArchive::new(fs::File::open(&path)?).map_err(zip_error)?;
+        let parts = if archive.index_for_name("[Content_Types].xml").is_some() {
+            OOXML_METADATA_PARTS
+        } else if archive.index_for_name("mimetype").is_some() {
+            ODF_METADATA_PARTS
+        } else {
+            debug!("skipping archive as it is not an office document");
+
+            return Ok(Output::unchanged(path, "not an office document"));
+        };
+
+        debug!("stripping metadata from {path}", path = &path.display());
+
+        let mut writer = ZipWriter::new(NamedTempFile::new().map_err(Error::CreateTempFile)?);
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+        // Entries are copied in their original order and without recompressing them, as
+        // OpenDocument requires the uncompressed `mimetype` entry to come first.
+        for index in 0..archive.len() {
+            let entry = archive.by_index_raw(index).map_err(zip_error)?;
+
+            match parts.iter().find(|(name, _)| *name == entry.name()) {
+                Some((name, replacement)) => {
+                    writer.start_file(*name, options).map_err(zip_error)?;
+                    writer.write_all(replacement.as_bytes())?;
+                }
+                None => writer.raw_copy_file(entry).map_err(zip_error)?,
+            }
+        }
+
+        let file = writer.finish().map_err(zip_error)?;
+
+        Ok(file.into_temp_path().into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use lopdf::{dictionary, Stream};
+
+    use super::*;
+    use crate::postprocess;
+
+    /// Returns a ZIP archive with the given entries.
+    fn package(entries: &[(&str, &str)]) -> TempPath {
+        let mut writer = ZipWriter::new(NamedTempFile::new().unwrap());
+
+        for (name, contents) in entries {
+            writer
+                .start_file(*name, SimpleFileOptions::default())
+                .unwrap();
+            writer.write_all(contents.as_bytes()).unwrap();
+        }
+
+        writer.finish().unwrap().into_temp_path()
+    }
+
+    /// Returns the contents of the entry `name` of the ZIP archive at `path`.
+    fn entry(path: &TempPath, name: &str) -> String {
+        let mut archive = ZipArchive::new(fs::File::open(path).unwrap()).unwrap();
+        let mut contents = String::new();
+
+        archive
+            .by_name(name)
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        contents
+    }
+
+    /// Returns a PDF with an Info dictionary and an XMP metadata stream, both naming the author.
+    fn pdf() -> TempPath {
+        let mut document = Document::with_version("1.5");
+        let pages_id = document.new_object_id();
+        let page_id = document.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "MediaBox" => vec![0.into(), 0.into(), 10.into(), 10.into()],
+        });
+        let metadata_id = document.add_object(Stream::new(
+            dictionary! { "Type" => "Metadata", "Subtype" => "XML" },
+            b"<x:xmpmeta>Jane Doe</x:xmpmeta>".to_vec(),
+        ));
+        let catalog_id = document.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+            "Metadata" => metadata_id,
+        });
+        let info_id = document.add_object(dictionary! {
+            "Author" => Object::string_literal("Jane Doe"),
+        });
+
+        document.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages",
+                "Kids" => vec![page_id.into()],
+                "Count" => 1,
+            }),
+        );
+        document.trailer.set("Root", catalog_id);
+        document.trailer.set("Info", info_id);
+
+        let file = NamedTempFile::new().unwrap();
+
+        document.save(file.path()).unwrap();
+        file.into_temp_path()
+    }
+
+    #[tokio::test]
+    async fn strips_pdf_metadata() {
+        let output = StripPdfMetadata.apply(pdf()).await.unwrap();
+        let document = Document::load(&output.path).unwrap();
+
+        assert!(document.trailer.get(b"Info").is_err());
+        assert!(document.catalog().unwrap().get(b"Metadata").is_err());
+        assert_eq!(document.get_pages().len(), 1);
+        assert!(!String::from_utf8_lossy(&fs::read(&output.path).unwrap()).contains("Jane Doe"));
+    }
+
+    #[test]
+    fn strips_ooxml_properties() {
+        let path = package(&[
+            ("[Content_Types].xml", "<Types/>"),
+            ("docProps/core.xml", "<dc:creator>Jane Doe</dc:creator>"),
+            ("word/document.xml", "<w:document/>"),
+        ]);
+        let output = StripOfficeMetadata::strip(path).unwrap();
+
+        assert!(!output.unchanged);
+        assert!(!entry(&output.path, "docProps/core.xml").contains("Jane Doe"));
+        assert_eq!(entry(&output.path, "word/document.xml"), "<w:document/>");
+    }
+
+    #[test]
+    fn strips_odf_properties() {
+        let path = package(&[
+            ("mimetype", "application/vnd.oasis.opendocument.text"),
+            (
+                "meta.xml",
+                "<meta:initial-creator>Jane Doe</meta:initial-creator>",
+            ),
+        ]);
+        let output = StripOfficeMetadata::strip(path).unwrap();
+
+        assert!(!output.unchanged);
+        assert!(!entry(&output.path, "meta.xml").contains("Jane Doe"));
+    }
+
+    #[test]
+    fn passes_plain_archives_through() {
+        let path = package(&[("notes.txt", "hello")]);
+        let before = fs::read(&path).unwrap();
+        let output = StripOfficeMetadata::strip(path).unwrap();
+
+        assert!(output.unchanged);
+        assert_eq!(fs::read(&output.path).unwrap(), before);
+    }
+
+    #[tokio::test]
+    async fn plain_archives_are_not_sanitized() {
+        let processors: [Box<dyn PostProcessor>; 1] = [Box::new(StripOfficeMetadata)];
+        let path = package(&[("notes.txt", "hello")]);
+        let pipeline = postprocess::run(&processors, path, "application/zip").await;
+
+        assert!(pipeline.path.is_some());
+        assert!(!pipeline.sanitized);
+    }
+}
@@ -0,0 +1,236 @@
+use std::{
+    env,
+    path::Path,
+    process::{Command as StdCommand, ExitStatus, Stdio},
+};
+
+use async_trait::async_trait;
+use tempfile::{Builder, TempPath};
+use tokio::process::Command;
+use tracing::{debug, warn};
+
+use super::{Output, PostProcessor};
+use crate::{mime, Error};
+
+/// The maximum size of the address space of external tools, in bytes.
+const MEMORY_LIMIT: u64 = 2 * 1024 * 1024 * 1024;
+
+/// The maximum CPU time of external tools, in seconds.
+const CPU_TIME_LIMIT: u64 = 120;
+
+/// Kills the process group led by a child process when dropped, unless it was disarmed.
+#[cfg(unix)]
+struct ProcessGroup(Option<u32>);
+
+#[cfg(unix)]
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        if let Some(pgid) = self.0.and_then(|x| libc::pid_t::try_from(x).ok()) {
+            // SAFETY: `killpg` only sends a signal. The group leader hasn't been reaped yet, so
+            // its id can't have been reused.
+            unsafe {
+                libc::killpg(pgid, libc::SIGKILL);
+            }
+        }
+    }
+}
+
+/// Runs the given external `command` with resource limits and returns its exit status.
+///
+/// The command runs in its own process group, which is killed if the returned future is
+/// dropped before it completes, e.g. because the post-processor timed out. This also kills
+/// the processes the command started, like those of `sh -c` scripts.
+pub(super) async fn run(command: &mut Command) -> Result<ExitStatus, Error> {
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .kill_on_drop(true);
+
+    #[cfg(unix)]
+    command.process_group(0);
+
+    #[cfg(unix)]
+    // SAFETY: the closure only calls `setrlimit`, which is async-signal-safe.
+    unsafe {
+        command.pre_exec(|| {
+            let limit = |resource, value: u64| {
+                let rlimit = libc::rlimit {
+                    rlim_cur: value as libc::rlim_t,
+                    rlim_max: value as libc::rlim_t,
+                };
+
+                if libc::setrlimit(resource, &rlimit) == 0 {
+                    Ok(())
+                } else {
+                    Err(std::io::Error::last_os_error())
+                }
+            };
+
+            limit(libc::RLIMIT_AS, MEMORY_LIMIT)?;
+            limit(libc::RLIMIT_CPU, CPU_TIME_LIMIT)
+        });
+    }
+
+    let mut child = command.spawn()?;
+    #[cfg(unix)]
+    let mut group = ProcessGroup(child.id());
+    let status = child.wait().await?;
+
+    #[cfg(unix)]
+    {
+        group.0 = None;
+    }
+
+    Ok(status)
+}
+
+/// Removes metadata from the formats the native processors don't cover, using `exiftool`.
+pub struct RemoveExif;
+
+#[async_trait]
+impl PostProcessor for RemoveExif {
+    fn name(&self) -> &str {
+        "remove_exif"
+    }
+
+    fn applicable(&self, mime_type: &str) -> bool {
+        matches!(
+            mime_type,
+            "image/heic"
+                | "video/mp4"
+                | "video/heic"
+                | "video/mpeg"
+                | "video/quicktime"
+                | "video/3gpp"
+                | "video/x-msvideo"
+                | "video/x-ms-wmv"
+        )
+    }
+
+    fn check(&self) -> Result<bool, Error> {
+        match StdCommand::new("exiftool").arg("-ver").output() {
+            Ok(output) => Ok(output.status.success()),
+            Err(_) => Err(Error::ToolCheckFailed("exiftool".to_string())),
+        }
+    }
+
+    async fn apply(&self, path: TempPath) -> Result<Output, Error> {
+        remove_exif(&path).await?;
+
+        Ok(path.into())
+    }
+
+    fn sanitizes(&self) -> bool {
+        true
+    }
+}
+
+/// Removes all metadata from the file at `path` in place, using `exiftool`.
+pub(super) async fn remove_exif(path: &Path) -> Result<(), Error> {
+    debug!("running exiftool on {path}", path = &path.display());
+
+    match run(Command::new("exiftool")
+        .args(["-all=", "-overwrite_original"])
+        .arg(path))
+    .await
+    {
+        Ok(status) if status.success() => Ok(()),
+        _ => Err(Error::PostProcessFailed("exiftool failed".to_string())),
+    }
+}
+
+/// Runs a configured external command on files.
+pub struct CommandProcessor {
+    /// The name of the post-processor.
+    name: String,
+    /// The command to run.
+    command: String,
+    /// The arguments of the command, with `{input}` and `{output}` placeholders.
+    args: Vec<String>,
+    /// Glob patterns of the MIME types to run on.
+    mime_types: Vec<String>,
+}
+
+impl CommandProcessor {
+    pub fn new(name: &str, command: &str, args: &[String], mime_types: &[String]) -> Self {
+        if mime_types.is_empty() {
+            warn!(%name, "command postprocessor has no mime types and will never run");
+        }
+
+        CommandProcessor {
+            name: name.to_string(),
+            command: command.to_string(),
+            args: args.to_vec(),
+            mime_types: mime_types.to_vec(),
+        }
+    }
+
+    /// Returns whether the command writes its result to a separate `{output}` file rather than
+    /// modifying `{input}` in place.
+    fn has_output(&self) -> bool {
+        self.args.iter().any(|x| x.contains("{output}"))
+    }
+}
+
+#[async_trait]
+impl PostProcessor for CommandProcessor {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn applicable(&self, mime_type: &str) -> bool {
+        mime::matches_any(&self.mime_types, mime_type)
+    }
+
+    fn check(&self) -> Result<bool, Error> {
+        if executable_exists(&self.command) {
+            Ok(true)
+        } else {
+            Err(Error::ToolCheckFailed(self.command.clone()))
+        }
+    }
+
+    async fn apply(&self, path: TempPath) -> Result<Output, Error> {
+        let output = if self.has_output() {
+            Some(
+                Builder::new()
+                    .tempfile()
+                    .map_err(Error::CreateTempFile)?
+                    .into_temp_path(),
+            )
+        } else {
+            None
+        };
+
+        let args = self.args.iter().map(|arg| {
+            let mut arg = arg.replace("{input}", &path.to_string_lossy());
+
+            if let Some(ref output) = output {
+                arg = arg.replace("{output}", &output.to_string_lossy());
+            }
+
+            arg
+        });
+
+        debug!(command = %self.command, "running {name} on {path}", name = self.name, path = &path.display());
+
+        match run(Command::new(&self.command).args(args)).await {
+            Ok(status) if status.success() => Ok(output.unwrap_or(path).into()),
+            Ok(status) => Err(Error::PostProcessFailed(format!(
+                "{} failed with {status}",
+                self.command
+            ))),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Returns whether the given `command` can be executed, either as a path or from `PATH`.
+pub(super) fn executable_exists(command: &str) -> bool {
+    if command.contains('/') {
+        return Path::new(command).is_file();
+    }
+
+    env::var_os("PATH")
+        .is_some_and(|paths| env::split_paths(&paths).any(|dir| dir.join(command).is_file()))
+}
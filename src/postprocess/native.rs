@@ -0,0 +1,272 @@
+use std::{
+    fs,
+    io::{BufWriter, Write},
+};
+
+use async_trait::async_trait;
+use image::{
+    codecs::{jpeg::JpegEncoder, png::PngEncoder, webp::WebPEncoder},
+    metadata::Orientation,
+    DynamicImage, ImageDecoder, ImageEncoder, ImageFormat, ImageReader,
+};
+use img_parts::{jpeg::Jpeg, png::Png, webp::WebP, Bytes, ImageEXIF};
+use tempfile::TempPath;
+use tracing::debug;
+
+use super::{blocking, Output, PostProcessor};
+use crate::Error;
+
+/// The JPEG quality used when an image has to be re-encoded.
+const REENCODE_QUALITY: u8 = 90;
+
+/// JPEG markers of segments that carry metadata rather than image data.
+///
+/// APP0 (JFIF), APP2 (ICC profiles) and APP14 (Adobe colour transform) are needed to display
+/// the image correctly and are kept.
+const JPEG_METADATA_MARKERS: &[u8] = &[
+    0xE1, // APP1: EXIF and XMP
+    0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0xEA, 0xEB, 0xEC, // APP3-APP12
+    0xED, // APP13: IPTC and Photoshop
+    0xEF, // APP15
+    0xFE, // COM
+];
+
+/// PNG chunks that carry metadata rather than image data.
+const PNG_METADATA_CHUNKS: &[[u8; 4]] = &[*b"eXIf", *b"tEXt", *b"zTXt", *b"iTXt", *b"tIME"];
+
+/// Returns whether the native processors can handle the given `mime_type`.
+fn supported(mime_type: &str) -> bool {
+    matches!(mime_type, "image/jpeg" | "image/png" | "image/webp")
+}
+
+/// Converts an image error into a post-processing error.
+fn image_error(err: image::ImageError) -> Error {
+    Error::PostProcessFailed(format!("image processing failed: {err}"))
+}
+
+/// Rotates and flips images according to their EXIF orientation.
+pub struct NormalizeOrientation;
+
+#[async_trait]
+impl PostProcessor for NormalizeOrientation {
+    fn name(&self) -> &str {
+        "normalize_orientation"
+    }
+
+    fn applicable(&self, mime_type: &str) -> bool {
+        supported(mime_type)
+    }
+
+    fn check(&self) -> Result<bool, Error> {
+        Ok(true)
+    }
+
+    async fn apply(&self, path: TempPath) -> Result<Output, Error> {
+        blocking(move || Self::normalize(path)).await
+    }
+}
+
+impl NormalizeOrientation {
+    /// Applies the EXIF orientation of the image at `path` to its pixels, in place.
+    ///
+    /// Images without an orientation to apply are left untouched, as rotating means
+    /// re-encoding, which is lossy for JPEGs. The ICC profile is carried over.
+    fn normalize(path: TempPath) -> Result<Output, Error> {
+        let reader = ImageReader::open(&path)?.with_guessed_format()?;
+        let Some(format) = reader.format() else {
+            return Err(Error::PostProcessFailed("unknown image format".to_string()));
+        };
+        let mut decoder = reader.into_decoder().map_err(image_error)?;
+        let orientation = decoder.orientation().map_err(image_error)?;
+
+        if orientation == Orientation::NoTransforms {
+            return Ok(Output::unchanged(path, "already upright"));
+        }
+
+        debug!(
+            ?orientation,
+            "normalizing orientation of {path}",
+            path = &path.display()
+        );
+
+        let icc_profile = decoder.icc_profile().map_err(image_error)?;
+        let mut image = DynamicImage::from_decoder(decoder).map_err(image_error)?;
+        image.apply_orientation(orientation);
+
+        let mut writer = BufWriter::new(fs::File::create(&path)?);
+
+        match format {
+            ImageFormat::Jpeg => {
+                let encoder = JpegEncoder::new_with_quality(&mut writer, REENCODE_QUALITY);
+
+                encode(&image, encoder, icc_profile)?;
+            }
+            ImageFormat::Png => encode(&image, PngEncoder::new(&mut writer), icc_profile)?,
+            ImageFormat::WebP => {
+                encode(&image, WebPEncoder::new_lossless(&mut writer), icc_profile)?;
+            }
+            format => image.write_to(&mut writer, format).map_err(image_error)?,
+        }
+
+        writer.flush()?;
+
+        Ok(path.into())
+    }
+}
+
+/// Encodes `image` with `encoder`, embedding the `icc_profile` if there is one.
+fn encode(
+    image: &DynamicImage,
+    mut encoder: impl ImageEncoder,
+    icc_profile: Option<Vec<u8>>,
+) -> Result<(), Error> {
+    if let Some(icc_profile) = icc_profile {
+        encoder
+            .set_icc_profile(icc_profile)
+            .map_err(|e| image_error(image::ImageError::Unsupported(e)))?;
+    }
+
+    image.write_with_encoder(encoder).map_err(image_error)
+}
+
+/// Removes EXIF, XMP, IPTC and textual metadata from images.
+pub struct StripMetadata;
+
+#[async_trait]
+impl PostProcessor for StripMetadata {
+    fn name(&self) -> &str {
+        "strip_metadata"
+    }
+
+    fn applicable(&self, mime_type: &str) -> bool {
+        supported(mime_type)
+    }
+
+    fn check(&self) -> Result<bool, Error> {
+        Ok(true)
+    }
+
+    async fn apply(&self, path: TempPath) -> Result<Output, Error> {
+        blocking(move || Self::strip(path)).await
+    }
+
+    fn sanitizes(&self) -> bool {
+        true
+    }
+}
+
+impl StripMetadata {
+    /// Removes the metadata of the image at `path`, in place.
+    fn strip(path: TempPath) -> Result<Output, Error> {
+        debug!("stripping metadata from {path}", path = &path.display());
+
+        let contents = Bytes::from(fs::read(&path)?);
+        let invalid = |err: img_parts::Error| {
+            Error::PostProcessFailed(format!("could not parse image: {err}"))
+        };
+
+        let stripped = match image::guess_format(&contents) {
+            Ok(ImageFormat::Jpeg) => {
+                let mut jpeg = Jpeg::from_bytes(contents).map_err(invalid)?;
+
+                jpeg.segments_mut()
+                    .retain(|x| !JPEG_METADATA_MARKERS.contains(&x.marker()));
+                jpeg.encoder().bytes()
+            }
+            Ok(ImageFormat::Png) => {
+                let mut png = Png::from_bytes(contents).map_err(invalid)?;
+
+                png.chunks_mut()
+                    .retain(|x| !PNG_METADATA_CHUNKS.contains(&x.kind()));
+                png.encoder().bytes()
+            }
+            Ok(ImageFormat::WebP) => {
+                let mut webp = WebP::from_bytes(contents).map_err(invalid)?;
+
+                webp.remove_chunks_by_id(img_parts::webp::CHUNK_XMP);
+                // Also updates the feature flags of the extended header.
+                webp.set_exif(None);
+                webp.encoder().bytes()
+            }
+            _ => {
+                return Err(Error::PostProcessFailed(
+                    "unsupported image type".to_string(),
+                ))
+            }
+        };
+
+        fs::write(&path, stripped)?;
+
+        Ok(path.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{GenericImageView, RgbImage};
+    use img_parts::ImageICC;
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    /// An EXIF block with only the orientation tag, set to "rotate 90° clockwise".
+    const EXIF_ROTATE_90: &[u8] = &[
+        b'M', b'M', 0, 42, 0, 0, 0, 8, // TIFF header
+        0, 1, // one entry
+        0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0, // orientation
+        0, 0, 0, 0, // no further IFDs
+    ];
+
+    const ICC_PROFILE: &[u8] = b"not really an icc profile";
+
+    /// Returns a 2x1 JPEG with the given `exif` metadata and an ICC profile.
+    fn jpeg(exif: Option<&[u8]>) -> TempPath {
+        let mut contents = vec![];
+
+        DynamicImage::ImageRgb8(RgbImage::new(2, 1))
+            .write_to(&mut Cursor::new(&mut contents), ImageFormat::Jpeg)
+            .unwrap();
+
+        let mut jpeg = Jpeg::from_bytes(contents.into()).unwrap();
+
+        jpeg.set_exif(exif.map(Bytes::copy_from_slice));
+        jpeg.set_icc_profile(Some(Bytes::from_static(ICC_PROFILE)));
+
+        let file = NamedTempFile::new().unwrap();
+
+        fs::write(file.path(), jpeg.encoder().bytes()).unwrap();
+        file.into_temp_path()
+    }
+
+    #[test]
+    fn leaves_upright_images_untouched() {
+        let path = jpeg(None);
+        let before = fs::read(&path).unwrap();
+        let output = NormalizeOrientation::normalize(path).unwrap();
+
+        assert!(output.unchanged);
+        assert_eq!(fs::read(&output.path).unwrap(), before);
+    }
+
+    #[test]
+    fn rotates_images_and_keeps_icc_profile() {
+        let output = NormalizeOrientation::normalize(jpeg(Some(EXIF_ROTATE_90))).unwrap();
+        let contents = fs::read(&output.path).unwrap();
+        let image = image::load_from_memory(&contents).unwrap();
+        let jpeg = Jpeg::from_bytes(contents.into()).unwrap();
+
+        assert_eq!(image.dimensions(), (1, 2));
+        assert_eq!(jpeg.icc_profile().as_deref(), Some(ICC_PROFILE));
+    }
+
+    #[test]
+    fn strips_exif_and_keeps_icc_profile() {
+        let output = StripMetadata::strip(jpeg(Some(EXIF_ROTATE_90))).unwrap();
+        let jpeg = Jpeg::from_bytes(fs::read(&output.path).unwrap().into()).unwrap();
+
+        assert!(jpeg.exif().is_none());
+        assert_eq!(jpeg.icc_profile().as_deref(), Some(ICC_PROFILE));
+    }
+}
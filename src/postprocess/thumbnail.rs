@@ -0,0 +1,223 @@
+use std::{
+    io::{BufWriter, Write},
+    path::PathBuf,
+    process::Command as StdCommand,
+};
+
+use async_trait::async_trait;
+use image::{codecs::jpeg::JpegEncoder, ImageReader};
+use tempfile::{Builder, TempPath};
+use tokio::process::Command;
+use tracing::{debug, warn};
+
+use super::{blocking, external, Derived, Output, PostProcessor};
+use crate::{config::Policy, Error};
+
+/// The maximum width and height of generated thumbnails, in pixels.
+const THUMBNAIL_SIZE: u32 = 320;
+
+/// The JPEG quality of generated thumbnails.
+const THUMBNAIL_QUALITY: u8 = 80;
+
+/// Generates thumbnails of images and poster frames of videos.
+pub struct Thumbnail {
+    /// Whether `ffmpeg` is available to extract poster frames from videos.
+    ffmpeg: bool,
+}
+
+impl Thumbnail {
+    pub fn new() -> Self {
+        let ffmpeg = StdCommand::new("ffmpeg")
+            .arg("-version")
+            .output()
+            .is_ok_and(|x| x.status.success());
+
+        if !ffmpeg {
+            warn!("ffmpeg is not available, poster frames will not be generated for videos");
+        }
+
+        Thumbnail { ffmpeg }
+    }
+
+    /// Generates a JPEG thumbnail of the image at the given `path`.
+    fn image_thumbnail(path: PathBuf) -> Result<TempPath, Error> {
+        let image = ImageReader::open(path)?
+            .with_guessed_format()?
+            .decode()
+            .map_err(|e| Error::PostProcessFailed(format!("could not decode image: {e}")))?;
+        // `thumbnail` scales small images up to the size, which only makes them larger.
+        let thumbnail = if image.width() > THUMBNAIL_SIZE || image.height() > THUMBNAIL_SIZE {
+            image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+        } else {
+            image
+        }
+        .into_rgb8();
+        let file = Builder::new()
+            .suffix(".jpg")
+            .tempfile()
+            .map_err(Error::CreateTempFile)?;
+        let mut writer = BufWriter::new(file.as_file());
+
+        JpegEncoder::new_with_quality(&mut writer, THUMBNAIL_QUALITY)
+            .encode_image(&thumbnail)
+            .map_err(|e| Error::PostProcessFailed(format!("could not encode thumbnail: {e}")))?;
+        writer.flush()?;
+        drop(writer);
+
+        Ok(file.into_temp_path())
+    }
+
+    /// Extracts a scaled-down JPEG poster frame from the video at the given `path`.
+    async fn video_poster(path: &TempPath) -> Result<TempPath, Error> {
+        let output = Builder::new()
+            .suffix(".jpg")
+            .tempfile()
+            .map_err(Error::CreateTempFile)?
+            .into_temp_path();
+        let scale =
+            format!("scale={THUMBNAIL_SIZE}:{THUMBNAIL_SIZE}:force_original_aspect_ratio=decrease");
+
+        match external::run(
+            Command::new("ffmpeg")
+                .args(["-loglevel", "error", "-y", "-i"])
+                .arg(path)
+                .args(["-frames:v", "1", "-vf", &scale])
+                .arg(&output),
+        )
+        .await
+        {
+            Ok(status) if status.success() => Ok(output),
+            _ => Err(Error::PostProcessFailed("ffmpeg failed".to_string())),
+        }
+    }
+}
+
+#[async_trait]
+impl PostProcessor for Thumbnail {
+    fn name(&self) -> &str {
+        "thumbnail"
+    }
+
+    fn applicable(&self, mime_type: &str) -> bool {
+        match mime_type {
+            "image/jpeg" | "image/png" | "image/webp" | "image/gif" => true,
+            "video/mp4" | "video/mpeg" | "video/quicktime" | "video/webm" | "video/3gpp"
+            | "video/x-msvideo" | "video/x-ms-wmv" => self.ffmpeg,
+            _ => false,
+        }
+    }
+
+    fn policy(&self) -> Policy {
+        // A missing thumbnail is no reason to drop the attachment itself.
+        Policy::BestEffort
+    }
+
+    fn check(&self) -> Result<bool, Error> {
+        Ok(true)
+    }
+
+    async fn apply(&self, path: TempPath) -> Result<Output, Error> {
+        debug!("generating thumbnail of {path}", path = &path.display());
+
+        let thumbnail = match tree_magic_mini::from_filepath(&path) {
+            Some(mime_type) if mime_type.starts_with("video/") => Self::video_poster(&path).await?,
+            _ => {
+                let image_path = path.to_path_buf();
+
+                blocking(move || Self::image_thumbnail(image_path)).await?
+            }
+        };
+
+        Ok(Output {
+            derived: vec![Derived {
+                name: "thumbnail.jpg",
+                path: thumbnail,
+                mime_type: "image/jpeg",
+            }],
+            // The thumbnail is a separate object, the file itself is left as it was.
+            unchanged: true,
+            ..path.into()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use image::{GenericImageView, ImageFormat, RgbImage};
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    /// Returns a temporary file with the given `contents`.
+    fn file(contents: &[u8]) -> TempPath {
+        let mut file = NamedTempFile::new().unwrap();
+
+        file.write_all(contents).unwrap();
+        file.into_temp_path()
+    }
+
+    /// Returns a PNG of the given `width` and `height`.
+    fn png(width: u32, height: u32) -> TempPath {
+        let file = NamedTempFile::new().unwrap();
+
+        RgbImage::new(width, height)
+            .save_with_format(file.path(), ImageFormat::Png)
+            .unwrap();
+        file.into_temp_path()
+    }
+
+    async fn thumbnail(path: TempPath) -> Derived {
+        let mut output = Thumbnail { ffmpeg: false }.apply(path).await.unwrap();
+
+        assert!(output.unchanged);
+        assert_eq!(output.derived.len(), 1);
+        output.derived.remove(0)
+    }
+
+    #[tokio::test]
+    async fn scales_images_down_keeping_the_aspect_ratio() {
+        let derived = thumbnail(png(1000, 500)).await;
+        let image = image::open(&derived.path).unwrap();
+
+        assert_eq!(derived.mime_type, "image/jpeg");
+        assert_eq!(image.dimensions(), (THUMBNAIL_SIZE, THUMBNAIL_SIZE / 2));
+    }
+
+    #[tokio::test]
+    async fn never_scales_images_up() {
+        let derived = thumbnail(png(100, 40)).await;
+
+        assert_eq!(image::open(&derived.path).unwrap().dimensions(), (100, 40));
+    }
+
+    #[tokio::test]
+    async fn derives_the_thumbnail_key() {
+        let derived = thumbnail(png(10, 10)).await;
+
+        assert_eq!(
+            derived.key("attachments/abc"),
+            "attachments/abc.thumbnail.jpg"
+        );
+    }
+
+    #[test]
+    fn skips_videos_without_ffmpeg() {
+        let with = Thumbnail { ffmpeg: true };
+        let without = Thumbnail { ffmpeg: false };
+
+        assert!(with.applicable("video/mp4"));
+        assert!(!without.applicable("video/mp4"));
+        assert!(without.applicable("image/png"));
+    }
+
+    #[tokio::test]
+    async fn fails_on_videos_without_a_poster_frame() {
+        // An MP4 header without any frames.
+        let path = file(b"\0\0\0\x18ftypmp42\0\0\0\0mp42isom");
+        let result = Thumbnail { ffmpeg: true }.apply(path).await;
+
+        assert!(matches!(result, Err(Error::PostProcessFailed(_))));
+    }
+}
@@ -0,0 +1,313 @@
+use std::{
+    fs,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use async_trait::async_trait;
+use image::{
+    codecs::{jpeg::JpegEncoder, webp::WebPEncoder},
+    imageops::FilterType,
+    ImageReader,
+};
+use tempfile::{Builder, TempPath};
+use tokio::process::Command;
+use tracing::debug;
+
+use super::{blocking, external, Derived, Output, PostProcessor};
+use crate::{
+    config::{ImageFormat, PostProcessConfig},
+    mime, Error,
+};
+
+/// The default JPEG quality of converted images.
+const DEFAULT_QUALITY: u8 = 85;
+
+/// Converts images browsers can't display, and optionally large PNGs, to JPEG or WebP.
+///
+/// HEIF images are decoded with `heif-convert`, which also applies their orientation. The
+/// converted image carries no metadata, and a kept original has its metadata removed with
+/// `exiftool`. WebP is always encoded losslessly, so `quality` only applies to JPEG.
+///
+/// `heif-convert` is only required if HEIF images are among the MIME types it runs on.
+#[derive(Clone, Copy)]
+pub struct Transcode {
+    /// The format to convert images to.
+    format: ImageFormat,
+    /// The JPEG quality to encode images with.
+    quality: u8,
+    /// The maximum width and height of converted images, in pixels.
+    max_dimension: Option<u32>,
+    /// The minimum size of PNGs to convert, in bytes.
+    min_png_size: Option<u64>,
+    /// Whether to keep the unconverted original as a derived object.
+    keep_original: bool,
+    /// Whether HEIF images are converted, which requires `heif-convert`.
+    heif: bool,
+}
+
+impl Transcode {
+    pub fn new(config: &PostProcessConfig) -> Result<Self, Error> {
+        let format = config.format.unwrap_or_default();
+
+        if format == ImageFormat::Webp && config.quality.is_some() {
+            return Err(Error::UnsupportedQuality);
+        }
+
+        let heif = config.mime_types.as_deref().is_none_or(|x| {
+            mime::matches_any(x, "image/heif") || mime::matches_any(x, "image/heic")
+        });
+
+        Ok(Transcode {
+            format,
+            quality: config.quality.unwrap_or(DEFAULT_QUALITY).clamp(1, 100),
+            max_dimension: config.max_dimension,
+            min_png_size: config.min_png_size,
+            keep_original: config.keep_original,
+            heif,
+        })
+    }
+
+    /// Decodes the HEIF image at `path` into a PNG in the directory `dir`.
+    async fn decode_heif(path: &Path, dir: &Path) -> Result<PathBuf, Error> {
+        // `heif-convert` may write auxiliary images next to its output, which are cleaned up
+        // along with `dir`.
+        let output = dir.join("decoded.png");
+
+        match external::run(Command::new("heif-convert").arg(path).arg(&output)).await {
+            Ok(status) if status.success() => Ok(output),
+            _ => Err(Error::PostProcessFailed("heif-convert failed".to_string())),
+        }
+    }
+
+    /// Encodes the image at `path` in the configured format, scaling it down if needed.
+    ///
+    /// Returns the encoded image along with its original dimensions if it was scaled down.
+    fn encode(&self, path: &Path) -> Result<(TempPath, Option<(u32, u32)>), Error> {
+        let mut image = ImageReader::open(path)?
+            .with_guessed_format()?
+            .decode()
+            .map_err(|e| Error::PostProcessFailed(format!("could not decode image: {e}")))?;
+        let mut scaled = None;
+
+        if let Some(max) = self.max_dimension {
+            if image.width() > max || image.height() > max {
+                scaled = Some((image.width(), image.height()));
+                image = image.resize(max, max, FilterType::Lanczos3);
+            }
+        }
+
+        let file = Builder::new()
+            .suffix(&format!(".{}", self.extension()))
+            .tempfile()
+            .map_err(Error::CreateTempFile)?;
+        let mut writer = BufWriter::new(file.as_file());
+        let result = match self.format {
+            ImageFormat::Jpeg => JpegEncoder::new_with_quality(&mut writer, self.quality)
+                .encode_image(&image.into_rgb8()),
+            ImageFormat::Webp => image.write_with_encoder(WebPEncoder::new_lossless(&mut writer)),
+        };
+
+        result.map_err(|e| Error::PostProcessFailed(format!("could not encode image: {e}")))?;
+        writer.flush()?;
+        drop(writer);
+
+        Ok((file.into_temp_path(), scaled))
+    }
+
+    /// Returns the MIME type of the configured output format.
+    fn mime_type(&self) -> &'static str {
+        match self.format {
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Webp => "image/webp",
+        }
+    }
+
+    /// Returns the file extension of the configured output format.
+    fn extension(&self) -> &'static str {
+        match self.format {
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Webp => "webp",
+        }
+    }
+}
+
+#[async_trait]
+impl PostProcessor for Transcode {
+    fn name(&self) -> &str {
+        "transcode"
+    }
+
+    fn applicable(&self, mime_type: &str) -> bool {
+        match mime_type {
+            "image/heif" | "image/heic" => true,
+            "image/png" => self.min_png_size.is_some(),
+            _ => false,
+        }
+    }
+
+    fn check(&self) -> Result<bool, Error> {
+        let mut tools = vec![];
+
+        if self.heif {
+            tools.push("heif-convert");
+        }
+
+        if self.keep_original {
+            tools.push("exiftool");
+        }
+
+        match tools.into_iter().find(|x| !external::executable_exists(x)) {
+            Some(tool) => Err(Error::ToolCheckFailed(tool.to_string())),
+            None => Ok(true),
+        }
+    }
+
+    async fn apply(&self, path: TempPath) -> Result<Output, Error> {
+        let original_type = tree_magic_mini::from_filepath(&path).unwrap_or_default();
+        let dir = tempfile::tempdir().map_err(Error::CreateTempFile)?;
+        let source = match original_type {
+            "image/heif" | "image/heic" => Self::decode_heif(&path, dir.path()).await?,
+            "image/png" => {
+                let size = fs::metadata(&path)?.len();
+
+                if self.min_png_size.is_none_or(|x| size < x) {
+                    return Ok(Output::unchanged(path, "too small to convert"));
+                }
+
+                path.to_path_buf()
+            }
+            _ => return Ok(Output::unchanged(path, "not a convertible image")),
+        };
+
+        debug!(
+            format = ?self.format,
+            "converting {original_type} image {path}",
+            path = &path.display()
+        );
+
+        let transcode = *self;
+        let (converted, scaled) = blocking(move || transcode.encode(&source)).await?;
+        let mut notes = vec![format!("converted from {original_type}")];
+
+        if let Some((width, height)) = scaled {
+            notes.push(format!("scaled down from {width}x{height}"));
+        }
+
+        // Derived objects are public, so the original is only kept without its metadata.
+        let derived = if self.keep_original {
+            external::remove_exif(&path).await?;
+
+            vec![Derived {
+                name: match original_type {
+                    "image/png" => "original.png",
+                    _ => "original.heic",
+                },
+                path,
+                mime_type: original_type,
+            }]
+        } else {
+            vec![]
+        };
+
+        Ok(Output {
+            path: converted,
+            derived,
+            mime_type: Some(self.mime_type()),
+            extension: Some(self.extension()),
+            notes,
+            unchanged: false,
+        })
+    }
+
+    fn sanitizes(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use figment::{
+        providers::{Format, Toml},
+        Figment,
+    };
+    use image::{DynamicImage, RgbImage};
+
+    use super::*;
+
+    /// Returns a PNG of the given dimensions.
+    fn png(width: u32, height: u32) -> TempPath {
+        let file = Builder::new().suffix(".png").tempfile().unwrap();
+
+        DynamicImage::ImageRgb8(RgbImage::new(width, height))
+            .save(file.path())
+            .unwrap();
+        file.into_temp_path()
+    }
+
+    fn transcode(min_png_size: u64) -> Transcode {
+        Transcode {
+            format: ImageFormat::Jpeg,
+            quality: DEFAULT_QUALITY,
+            max_dimension: Some(16),
+            min_png_size: Some(min_png_size),
+            keep_original: false,
+            heif: false,
+        }
+    }
+
+    /// Returns the `transcode` post-processor configured by the given TOML `config`.
+    fn configured(config: &str) -> Result<Transcode, Error> {
+        let config: PostProcessConfig = Figment::new()
+            .merge(Toml::string(&format!("name = \"transcode\"\n{config}")))
+            .extract()
+            .unwrap();
+
+        Transcode::new(&config)
+    }
+
+    #[test]
+    fn rejects_quality_for_webp() {
+        assert!(configured("format = \"jpeg\"\nquality = 70").is_ok());
+        assert!(configured("format = \"webp\"").is_ok());
+        assert!(matches!(
+            configured("format = \"webp\"\nquality = 70"),
+            Err(Error::UnsupportedQuality)
+        ));
+    }
+
+    #[test]
+    fn requires_heif_convert_for_heif_images_only() {
+        let pngs = configured("mime_types = [\"image/png\"]\nmin_png_size = 0").unwrap();
+        let all = configured("").unwrap();
+
+        assert!(pngs.check().unwrap());
+        assert_eq!(
+            all.check().is_ok(),
+            external::executable_exists("heif-convert")
+        );
+    }
+
+    #[tokio::test]
+    async fn passes_small_pngs_through() {
+        let path = png(32, 32);
+        let before = fs::read(&path).unwrap();
+        let output = transcode(u64::MAX).apply(path).await.unwrap();
+
+        assert!(output.unchanged);
+        assert!(output.mime_type.is_none());
+        assert_eq!(fs::read(&output.path).unwrap(), before);
+    }
+
+    #[tokio::test]
+    async fn converts_large_pngs() {
+        let output = transcode(0).apply(png(32, 16)).await.unwrap();
+        let image = image::open(&output.path).unwrap();
+
+        assert!(!output.unchanged);
+        assert_eq!(output.mime_type, Some("image/jpeg"));
+        assert_eq!(output.extension, Some("jpg"));
+        assert_eq!((image.width(), image.height()), (16, 8));
+        assert!(output.derived.is_empty());
+    }
+}
@@ -0,0 +1,157 @@
+use serde::Serialize;
+
+use crate::{
+    config::{Policy, ScanAction},
+    mime::Detection,
+    store::{AttachmentRecord, Outcome},
+};
+
+/// Whether a post-processor ran successfully on an attachment.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProcessorStatus {
+    /// The post-processor ran successfully.
+    Applied,
+    /// The post-processor failed.
+    Failed,
+}
+
+/// A report of a post-processor run on an attachment.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProcessorReport {
+    /// The name of the post-processor.
+    pub name: String,
+    /// What happens to the attachment if the post-processor fails.
+    pub policy: Policy,
+    /// Whether the post-processor ran successfully.
+    pub status: ProcessorStatus,
+    /// The reason the post-processor failed, if it did.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Notes of the post-processor on what it did.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub notes: Vec<String>,
+}
+
+/// The result of scanning an attachment for malware.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScanStatus {
+    /// No malware was detected.
+    Clean,
+    /// Malware was detected.
+    Infected,
+    /// The attachment could not be scanned.
+    Failed,
+}
+
+/// A report of the malware scan of an attachment.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScanReport {
+    /// The result of the scan.
+    pub status: ScanStatus,
+    /// The name of the detected malware, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+    /// What was done with the attachment, if malware was detected.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub action: Option<ScanAction>,
+    /// The key the attachment was quarantined under, if it was.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quarantine_key: Option<String>,
+    /// The reason the scan failed, if it did.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// A report of the processing of an attachment.
+#[derive(Debug, Clone, Serialize)]
+pub struct AttachmentReport {
+    /// The record of the attachment.
+    #[serde(flatten)]
+    pub record: AttachmentRecord,
+    /// The MIME type sniffed from the contents of the attachment.
+    pub sniffed_mime_type: &'static str,
+    /// The MIME type the mail declared for the attachment, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub declared_mime_type: Option<String>,
+    /// Whether the declared type or the filename extension contradicts the contents.
+    pub type_mismatch: bool,
+    /// The filename of the archive the attachment was extracted from, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub archive: Option<String>,
+    /// Whether the attachment was an inline part of the mail.
+    pub inline: bool,
+    /// Whether the attachment was extracted from a forwarded message.
+    pub forwarded: bool,
+    /// The post-processors that ran on the attachment, in order.
+    pub processors: Vec<ProcessorReport>,
+    /// The malware scan of the attachment, if scanning is enabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scan: Option<ScanReport>,
+    /// The reason the attachment was not stored, if it wasn't.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl AttachmentReport {
+    /// Returns the report of an attachment that has not been stored (yet).
+    pub fn new(filename: Option<&str>, detection: &Detection, size: u64) -> Self {
+        AttachmentReport {
+            record: AttachmentRecord {
+                key: None,
+                filename: filename.map(String::from),
+                mime_type: detection.mime_type.to_string(),
+                size,
+                outcome: Outcome::Failed,
+                thumbnail_key: None,
+            },
+            sniffed_mime_type: detection.sniffed,
+            declared_mime_type: detection.declared.clone(),
+            type_mismatch: detection.mismatch,
+            archive: None,
+            inline: false,
+            forwarded: false,
+            processors: vec![],
+            scan: None,
+            reason: None,
+        }
+    }
+}
+
+/// A report of the processing of an ingested mail.
+#[derive(Debug, Clone, Serialize)]
+pub struct MailReport {
+    /// The Message-ID of the mail, if any.
+    pub message_id: Option<String>,
+    /// The subject of the mail, if any.
+    pub subject: Option<String>,
+    /// The outcome of processing the mail.
+    pub outcome: Outcome,
+    /// The reports of the attachments of the mail.
+    pub attachments: Vec<AttachmentReport>,
+    /// The report of the stored body of the mail, if it was stored.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<AttachmentReport>,
+    /// The URLs extracted from the body of the mail.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub links: Vec<String>,
+    /// The reason the mail could not be processed, if it couldn't.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl MailReport {
+    /// Returns the report of a mail that could not be processed at all.
+    pub fn failed(error: impl Into<String>) -> Self {
+        MailReport {
+            message_id: None,
+            subject: None,
+            outcome: Outcome::Failed,
+            attachments: vec![],
+            body: None,
+            links: vec![],
+            error: Some(error.into()),
+        }
+    }
+}
@@ -0,0 +1,352 @@
+use std::{path::Path, time::Duration};
+
+use tokio::{
+    fs,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+use tracing::debug;
+
+use crate::{
+    config::{Policy, ScanAction, ScanConfig},
+    Error,
+};
+
+/// The size of the chunks files are streamed to clamd in.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The maximum length of a clamd reply.
+const MAX_REPLY_LENGTH: u64 = 4096;
+
+/// The address of a clamd daemon.
+#[derive(Debug, Clone)]
+enum Address {
+    /// A TCP address as `host:port`.
+    Tcp(String),
+    /// The path to a Unix socket.
+    #[cfg(unix)]
+    Unix(String),
+}
+
+impl Address {
+    /// Parses an address given as `tcp://host:port`, `unix:/path` or `host:port`.
+    fn parse(address: &str) -> Result<Self, Error> {
+        if let Some(path) = address.strip_prefix("unix:") {
+            #[cfg(unix)]
+            return Ok(Address::Unix(path.trim_start_matches("//").to_string()));
+            #[cfg(not(unix))]
+            return Err(Error::InvalidScanAddress(path.to_string()));
+        }
+
+        let address = address.strip_prefix("tcp://").unwrap_or(address);
+
+        if address.contains(':') {
+            Ok(Address::Tcp(address.to_string()))
+        } else {
+            Err(Error::InvalidScanAddress(address.to_string()))
+        }
+    }
+}
+
+/// The result of scanning a file.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Verdict {
+    /// No malware was detected.
+    Clean,
+    /// Malware with the given signature name was detected.
+    Infected(String),
+}
+
+/// Scans files for malware with a clamd daemon, using its `INSTREAM` command.
+#[derive(Debug)]
+pub struct Scanner {
+    /// The address of clamd.
+    address: Address,
+    /// The maximum duration of a single scan.
+    timeout: Duration,
+    /// What to do with attachments malware was detected in.
+    pub action: ScanAction,
+    /// What to do with attachments that could not be scanned.
+    pub policy: Policy,
+    /// The private bucket infected attachments are quarantined in, if they are quarantined.
+    pub quarantine_bucket: Option<String>,
+    /// The key prefix infected attachments are quarantined under.
+    pub quarantine_prefix: String,
+}
+
+impl Scanner {
+    /// Returns the scanner described by `config`, or `None` if scanning is disabled.
+    ///
+    /// Quarantining requires a bucket other than the `public_bucket` attachments are uploaded
+    /// to, as object ACLs aren't enforced by every S3-compatible store.
+    pub fn new(config: &ScanConfig, public_bucket: &str) -> Result<Option<Self>, Error> {
+        if !config.enabled {
+            return Ok(None);
+        }
+
+        let quarantine_bucket = match config.action {
+            ScanAction::Quarantine => match config.quarantine_bucket {
+                Some(ref bucket) if bucket != public_bucket => Some(bucket.clone()),
+                _ => return Err(Error::InvalidQuarantineBucket),
+            },
+            ScanAction::Reject | ScanAction::Tag => None,
+        };
+
+        Ok(Some(Scanner {
+            address: Address::parse(&config.address)?,
+            timeout: Duration::from_secs(config.timeout),
+            action: config.action,
+            policy: config.policy,
+            quarantine_bucket,
+            quarantine_prefix: config.quarantine_prefix.clone(),
+        }))
+    }
+
+    /// Scans the file at `path`.
+    pub async fn scan(&self, path: &Path) -> Result<Verdict, Error> {
+        debug!(address = ?self.address, "scanning {path}", path = path.display());
+
+        tokio::time::timeout(self.timeout, self.scan_with_address(path))
+            .await
+            .unwrap_or_else(|_| {
+                Err(Error::ScanFailed(format!(
+                    "timed out after {:?}",
+                    self.timeout
+                )))
+            })
+    }
+
+    async fn scan_with_address(&self, path: &Path) -> Result<Verdict, Error> {
+        let unreachable = |err| Error::ScanFailed(format!("could not connect to clamd: {err}"));
+
+        match self.address {
+            Address::Tcp(ref address) => {
+                let stream = TcpStream::connect(address).await.map_err(unreachable)?;
+
+                instream(stream, path).await
+            }
+            #[cfg(unix)]
+            Address::Unix(ref socket) => {
+                let stream = tokio::net::UnixStream::connect(socket)
+                    .await
+                    .map_err(unreachable)?;
+
+                instream(stream, path).await
+            }
+        }
+    }
+}
+
+/// Streams the file at `path` to clamd over `stream` and returns its verdict.
+async fn instream<S>(mut stream: S, path: &Path) -> Result<Verdict, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut file = fs::File::open(path).await?;
+    let mut chunk = vec![0; CHUNK_SIZE];
+
+    stream.write_all(b"zINSTREAM\0").await?;
+
+    // Each chunk is prefixed with its length, and a zero length ends the stream.
+    loop {
+        let len = file.read(&mut chunk).await?;
+
+        stream.write_all(&(len as u32).to_be_bytes()).await?;
+
+        if len == 0 {
+            break;
+        }
+
+        stream.write_all(&chunk[..len]).await?;
+    }
+
+    stream.flush().await?;
+
+    let mut reply = vec![];
+
+    stream
+        .take(MAX_REPLY_LENGTH)
+        .read_to_end(&mut reply)
+        .await?;
+
+    parse_reply(&String::from_utf8_lossy(&reply))
+}
+
+/// Parses a clamd reply such as `stream: OK` or `stream: Eicar-Signature FOUND`.
+fn parse_reply(reply: &str) -> Result<Verdict, Error> {
+    let reply = reply.trim_end_matches(['\0', '\n']);
+    let result = reply.strip_prefix("stream: ").unwrap_or(reply);
+
+    if result == "OK" {
+        Ok(Verdict::Clean)
+    } else if let Some(signature) = result.strip_suffix(" FOUND") {
+        Ok(Verdict::Infected(signature.to_string()))
+    } else {
+        Err(Error::ScanFailed(result.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tempfile::NamedTempFile;
+    use tokio::{net::TcpListener, task::JoinHandle};
+
+    use super::*;
+
+    /// Starts a stand-in for clamd that answers a single `INSTREAM` command with `reply`, and
+    /// returns its address along with the task yielding the streamed contents.
+    async fn clamd(reply: &'static str) -> (String, JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("tcp://{}", listener.local_addr().unwrap());
+        let task = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut command = [0; 10];
+            let mut contents = vec![];
+
+            stream.read_exact(&mut command).await.unwrap();
+            assert_eq!(&command, b"zINSTREAM\0");
+
+            loop {
+                let len = stream.read_u32().await.unwrap() as usize;
+
+                if len == 0 {
+                    break;
+                }
+
+                let mut chunk = vec![0; len];
+
+                stream.read_exact(&mut chunk).await.unwrap();
+                contents.extend(chunk);
+            }
+
+            stream.write_all(reply.as_bytes()).await.unwrap();
+
+            contents
+        });
+
+        (address, task)
+    }
+
+    fn config(address: &str, action: ScanAction) -> ScanConfig {
+        ScanConfig {
+            enabled: true,
+            address: address.to_string(),
+            action,
+            timeout: 1,
+            ..ScanConfig::default()
+        }
+    }
+
+    /// Returns a temporary file with `len` bytes of contents.
+    fn file(len: usize) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+
+        file.write_all(&vec![b'x'; len]).unwrap();
+        file
+    }
+
+    #[tokio::test]
+    async fn streams_files_in_chunks() {
+        let (address, task) = clamd("stream: OK\0").await;
+        let scanner = Scanner::new(&config(&address, ScanAction::Reject), "public")
+            .unwrap()
+            .unwrap();
+        let file = file(CHUNK_SIZE * 2 + 1);
+
+        assert_eq!(scanner.scan(file.path()).await.unwrap(), Verdict::Clean);
+        assert_eq!(task.await.unwrap(), fs::read(file.path()).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn detects_malware() {
+        let (address, _) = clamd("stream: Eicar-Signature FOUND\0").await;
+        let scanner = Scanner::new(&config(&address, ScanAction::Reject), "public")
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            scanner.scan(file(68).path()).await.unwrap(),
+            Verdict::Infected("Eicar-Signature".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_on_errors() {
+        let (address, _) = clamd("INSTREAM size limit exceeded. ERROR\0").await;
+        let scanner = Scanner::new(&config(&address, ScanAction::Reject), "public")
+            .unwrap()
+            .unwrap();
+
+        assert!(matches!(
+            scanner.scan(file(1).path()).await,
+            Err(Error::ScanFailed(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn times_out() {
+        // Accepts connections, but never replies.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("tcp://{}", listener.local_addr().unwrap());
+        let scanner = Scanner::new(&config(&address, ScanAction::Reject), "public")
+            .unwrap()
+            .unwrap();
+
+        assert!(matches!(
+            scanner.scan(file(1).path()).await,
+            Err(Error::ScanFailed(_))
+        ));
+    }
+
+    #[test]
+    fn requires_private_quarantine_bucket() {
+        let mut config = config("tcp://127.0.0.1:3310", ScanAction::Quarantine);
+
+        assert!(matches!(
+            Scanner::new(&config, "public"),
+            Err(Error::InvalidQuarantineBucket)
+        ));
+
+        config.quarantine_bucket = Some("public".to_string());
+        assert!(matches!(
+            Scanner::new(&config, "public"),
+            Err(Error::InvalidQuarantineBucket)
+        ));
+
+        config.quarantine_bucket = Some("quarantine".to_string());
+        let scanner = Scanner::new(&config, "public").unwrap().unwrap();
+        assert_eq!(scanner.quarantine_bucket.as_deref(), Some("quarantine"));
+    }
+
+    #[test]
+    fn parses_addresses() {
+        assert!(matches!(
+            Address::parse("tcp://localhost:3310"),
+            Ok(Address::Tcp(x)) if x == "localhost:3310"
+        ));
+        assert!(matches!(
+            Address::parse("localhost:3310"),
+            Ok(Address::Tcp(x)) if x == "localhost:3310"
+        ));
+        #[cfg(unix)]
+        assert!(matches!(
+            Address::parse("unix:/run/clamav/clamd.ctl"),
+            Ok(Address::Unix(x)) if x == "/run/clamav/clamd.ctl"
+        ));
+        assert!(Address::parse("localhost").is_err());
+    }
+
+    #[test]
+    fn parses_replies() {
+        assert_eq!(parse_reply("stream: OK\0").unwrap(), Verdict::Clean);
+        assert_eq!(parse_reply("OK\n").unwrap(), Verdict::Clean);
+        assert_eq!(
+            parse_reply("stream: Win.Test.EICAR_HDB-1 FOUND\0").unwrap(),
+            Verdict::Infected("Win.Test.EICAR_HDB-1".to_string())
+        );
+        assert!(parse_reply("stream: Can't allocate memory ERROR\0").is_err());
+        assert!(parse_reply("").is_err());
+    }
+}
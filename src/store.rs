@@ -0,0 +1,929 @@
+use std::{
+    fmt, fs,
+    path::Path,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use rusqlite::{params, types::Type, Connection, OptionalExtension, Row};
+use serde::Serialize;
+use tracing::debug;
+
+use crate::Error;
+
+/// A reference from an ingested mail to a stored attachment.
+#[derive(Debug, Clone, Serialize)]
+pub struct AttachmentReference {
+    /// The key of the stored object.
+    pub key: String,
+    /// The Message-ID of the referencing mail, if any.
+    pub message_id: Option<String>,
+    /// The sender of the referencing mail, if known.
+    pub sender: Option<String>,
+    /// The recipient of the referencing mail, if known.
+    pub recipient: Option<String>,
+    /// The subject of the referencing mail, if any.
+    pub subject: Option<String>,
+    /// The time the reference was recorded, as a unix timestamp.
+    pub created_at: i64,
+}
+
+/// The outcome of processing an ingested mail or one of its attachments.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// The attachment was uploaded.
+    Uploaded,
+    /// The attachment already existed in the bucket.
+    Cached,
+    /// The mail or attachment was skipped.
+    Skipped,
+    /// The mail or attachment was processed.
+    Processed,
+    /// The mail or attachment could not be processed.
+    Failed,
+    /// The attachment was rejected by policy.
+    Rejected,
+    /// The attachment was stored privately as malware was detected in it.
+    Quarantined,
+    /// The attachment was taken down after it was stored.
+    TakenDown,
+}
+
+impl Outcome {
+    /// Returns the string representation of the outcome.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Uploaded => "uploaded",
+            Self::Cached => "cached",
+            Self::Skipped => "skipped",
+            Self::Processed => "processed",
+            Self::Failed => "failed",
+            Self::Rejected => "rejected",
+            Self::Quarantined => "quarantined",
+            Self::TakenDown => "taken_down",
+        }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Outcome {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "uploaded" => Ok(Self::Uploaded),
+            "cached" => Ok(Self::Cached),
+            "skipped" => Ok(Self::Skipped),
+            "processed" => Ok(Self::Processed),
+            "failed" => Ok(Self::Failed),
+            "rejected" => Ok(Self::Rejected),
+            "quarantined" => Ok(Self::Quarantined),
+            "taken_down" => Ok(Self::TakenDown),
+            _ => Err(format!("unknown outcome `{s}'")),
+        }
+    }
+}
+
+/// The status of the delivery of a webhook event to an endpoint.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    /// The event has not been delivered yet.
+    Pending,
+    /// The endpoint accepted the event.
+    Delivered,
+    /// The event could not be delivered and won't be retried.
+    Failed,
+}
+
+impl DeliveryStatus {
+    /// Returns the string representation of the status.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Delivered => "delivered",
+            Self::Failed => "failed",
+        }
+    }
+}
+
+/// A record of an attachment of an ingested mail.
+#[derive(Debug, Clone, Serialize)]
+pub struct AttachmentRecord {
+    /// The key of the stored object, if it was stored.
+    pub key: Option<String>,
+    /// The filename of the attachment, if any.
+    pub filename: Option<String>,
+    /// The detected MIME type of the attachment.
+    pub mime_type: String,
+    /// The size of the attachment, in bytes.
+    pub size: u64,
+    /// The outcome of processing the attachment.
+    pub outcome: Outcome,
+    /// The key of the thumbnail of the stored object, if any.
+    pub thumbnail_key: Option<String>,
+}
+
+/// A record of an ingested mail.
+#[derive(Debug, Clone, Serialize)]
+pub struct MailRecord {
+    /// The id of the record.
+    pub id: i64,
+    /// The Message-ID of the mail, if any.
+    pub message_id: Option<String>,
+    /// The date of the mail as a unix timestamp, if known.
+    pub date: Option<i64>,
+    /// The time the mail was ingested, as a unix timestamp.
+    pub received_at: i64,
+    /// The sender of the mail, if known.
+    pub sender: Option<String>,
+    /// The recipients of the mail.
+    pub recipients: Vec<String>,
+    /// The subject of the mail, if any.
+    pub subject: Option<String>,
+    /// The outcome of processing the mail.
+    pub outcome: Outcome,
+    /// The attachments of the mail.
+    pub attachments: Vec<AttachmentRecord>,
+}
+
+/// Filters for searching ingested mails.
+#[derive(Debug, Clone, Default)]
+pub struct MailFilter {
+    /// Only include mails from this sender.
+    pub sender: Option<String>,
+    /// Only include mails dated at or after this unix timestamp.
+    pub since: Option<i64>,
+    /// Only include mails dated before this unix timestamp.
+    pub until: Option<i64>,
+    /// Only include mails with an attachment of this MIME type.
+    pub mime_type: Option<String>,
+    /// Only include mails whose subject contains this string, ignoring case.
+    pub subject: Option<String>,
+    /// Only include mails with at least one attachment that is still stored.
+    pub stored: bool,
+}
+
+/// The schema migrations of the store, applied in order.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE IF NOT EXISTS attachment_refs (
+        id INTEGER PRIMARY KEY,
+        key TEXT NOT NULL,
+        message_id TEXT,
+        sender TEXT,
+        recipient TEXT,
+        subject TEXT,
+        created_at INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS attachment_refs_key ON attachment_refs (key);
+    CREATE INDEX IF NOT EXISTS attachment_refs_message_id ON attachment_refs (message_id);
+    CREATE INDEX IF NOT EXISTS attachment_refs_sender ON attachment_refs (sender);
+    CREATE TABLE IF NOT EXISTS mails (
+        id INTEGER PRIMARY KEY,
+        message_id TEXT,
+        date INTEGER,
+        received_at INTEGER NOT NULL,
+        sender TEXT,
+        recipients TEXT NOT NULL,
+        subject TEXT,
+        outcome TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS mails_sender ON mails (sender);
+    CREATE INDEX IF NOT EXISTS mails_date ON mails (COALESCE(date, received_at));
+    CREATE TABLE IF NOT EXISTS mail_attachments (
+        id INTEGER PRIMARY KEY,
+        mail_id INTEGER NOT NULL REFERENCES mails (id),
+        key TEXT,
+        mime_type TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        outcome TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS mail_attachments_mail_id ON mail_attachments (mail_id);",
+    "ALTER TABLE mail_attachments ADD COLUMN filename TEXT;",
+    "CREATE TABLE derived_objects (
+        key TEXT NOT NULL,
+        derived_key TEXT NOT NULL PRIMARY KEY
+    );
+    CREATE INDEX derived_objects_key ON derived_objects (key);",
+    "CREATE TABLE webhook_deliveries (
+        id INTEGER PRIMARY KEY,
+        event_id TEXT NOT NULL,
+        event_type TEXT NOT NULL,
+        url TEXT NOT NULL,
+        status TEXT NOT NULL,
+        attempts INTEGER NOT NULL,
+        response_status INTEGER,
+        error TEXT,
+        created_at INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL
+    );
+    CREATE INDEX webhook_deliveries_event_id ON webhook_deliveries (event_id);",
+];
+
+/// Embedded index of ingested mails and the attachments they reference.
+#[derive(Debug)]
+pub struct Store {
+    conn: Connection,
+}
+
+impl Store {
+    /// Opens the store at the given `path`, creating it if it doesn't exist.
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        debug!(path = %path.display(), "opening store");
+
+        if let Some(parent) = path.parent().filter(|x| !x.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+
+        let conn = Connection::open(path)?;
+
+        let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+        for (version, migration) in (1..).zip(MIGRATIONS).skip(version.unsigned_abs() as usize) {
+            debug!(%version, "migrating store");
+
+            conn.execute_batch(migration)?;
+            conn.pragma_update(None, "user_version", version)?;
+        }
+
+        Ok(Store { conn })
+    }
+
+    /// Records that the attachment with the given `key` was included in a mail.
+    pub fn add_reference(
+        &self,
+        key: &str,
+        message_id: Option<&str>,
+        sender: Option<&str>,
+        recipient: Option<&str>,
+        subject: Option<&str>,
+    ) -> Result<(), Error> {
+        self.conn.execute(
+            "INSERT INTO attachment_refs (key, message_id, sender, recipient, subject, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![key, message_id, sender, recipient, subject, now()],
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns whether any mail references the attachment with the given `key`.
+    pub fn is_referenced(&self, key: &str) -> Result<bool, Error> {
+        let found = self
+            .conn
+            .query_row(
+                "SELECT 1 FROM attachment_refs WHERE key = ?1 LIMIT 1",
+                [key],
+                |_| Ok(()),
+            )
+            .optional()?;
+
+        Ok(found.is_some())
+    }
+
+    /// Returns all the references to the attachment with the given `key`, oldest first.
+    pub fn references(&self, key: &str) -> Result<Vec<AttachmentReference>, Error> {
+        let mut stmt = self.conn.prepare(
+            "SELECT key, message_id, sender, recipient, subject, created_at
+             FROM attachment_refs WHERE key = ?1 ORDER BY created_at, id",
+        )?;
+        let rows = stmt.query_map([key], |row| {
+            Ok(AttachmentReference {
+                key: row.get(0)?,
+                message_id: row.get(1)?,
+                sender: row.get(2)?,
+                recipient: row.get(3)?,
+                subject: row.get(4)?,
+                created_at: row.get(5)?,
+            })
+        })?;
+
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Returns the keys of the attachments referenced by mails matching the given `message_id`
+    /// and/or `sender`.
+    pub fn find_keys(
+        &self,
+        message_id: Option<&str>,
+        sender: Option<&str>,
+    ) -> Result<Vec<String>, Error> {
+        let mut stmt = self.conn.prepare(
+            "SELECT DISTINCT key FROM attachment_refs
+             WHERE (?1 IS NULL OR message_id = ?1) AND (?2 IS NULL OR sender = ?2)
+             ORDER BY key",
+        )?;
+        let rows = stmt.query_map(params![message_id, sender], |row| row.get(0))?;
+
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Removes the references to the attachment with the given `key`.
+    ///
+    /// If `message_id` and/or `sender` are given, only the references from the matching mails
+    /// are removed. Returns the number of references that remain.
+    pub fn remove_references(
+        &self,
+        key: &str,
+        message_id: Option<&str>,
+        sender: Option<&str>,
+    ) -> Result<u64, Error> {
+        self.conn.execute(
+            "DELETE FROM attachment_refs
+             WHERE key = ?1 AND (?2 IS NULL OR message_id = ?2) AND (?3 IS NULL OR sender = ?3)",
+            params![key, message_id, sender],
+        )?;
+
+        let remaining: i64 = self.conn.query_row(
+            "SELECT COUNT(*) FROM attachment_refs WHERE key = ?1",
+            [key],
+            |row| row.get(0),
+        )?;
+
+        Ok(remaining.unsigned_abs())
+    }
+
+    /// Records that the object with the `derived_key` was derived from the object with the
+    /// given `key`.
+    pub fn add_derived(&self, key: &str, derived_key: &str) -> Result<(), Error> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO derived_objects (key, derived_key) VALUES (?1, ?2)",
+            params![key, derived_key],
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns the keys of the objects derived from the object with the given `key`.
+    pub fn derived_keys(&self, key: &str) -> Result<Vec<String>, Error> {
+        let mut stmt = self.conn.prepare(
+            "SELECT derived_key FROM derived_objects WHERE key = ?1 ORDER BY derived_key",
+        )?;
+        let rows = stmt.query_map([key], |row| row.get(0))?;
+
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Records that the attachment with the given `key` was taken down.
+    ///
+    /// Forgets the objects derived from it and marks the attachments of the ingested mails that
+    /// were stored under `key` as taken down, so they no longer link to the deleted objects.
+    pub fn remove_attachment(&mut self, key: &str) -> Result<(), Error> {
+        let tx = self.conn.transaction()?;
+
+        tx.execute("DELETE FROM derived_objects WHERE key = ?1", [key])?;
+        tx.execute(
+            "UPDATE mail_attachments SET key = NULL, outcome = ?2 WHERE key = ?1",
+            params![key, Outcome::TakenDown.as_str()],
+        )?;
+
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    /// Records an ingested mail along with its attachments and returns the id of the record.
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_mail<'a>(
+        &mut self,
+        message_id: Option<&str>,
+        date: Option<i64>,
+        sender: Option<&str>,
+        recipients: &[String],
+        subject: Option<&str>,
+        outcome: Outcome,
+        attachments: impl IntoIterator<Item = &'a AttachmentRecord>,
+    ) -> Result<i64, Error> {
+        let recipients = serde_json::to_string(recipients).unwrap_or_else(|_| "[]".to_string());
+        let tx = self.conn.transaction()?;
+
+        tx.execute(
+            "INSERT INTO mails (message_id, date, received_at, sender, recipients, subject, outcome)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                message_id,
+                date,
+                now(),
+                sender,
+                recipients,
+                subject,
+                outcome.as_str()
+            ],
+        )?;
+
+        let mail_id = tx.last_insert_rowid();
+
+        for attachment in attachments {
+            tx.execute(
+                "INSERT INTO mail_attachments (mail_id, key, filename, mime_type, size, outcome)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    mail_id,
+                    attachment.key,
+                    attachment.filename,
+                    attachment.mime_type,
+                    i64::try_from(attachment.size).unwrap_or(i64::MAX),
+                    attachment.outcome.as_str()
+                ],
+            )?;
+        }
+
+        tx.commit()?;
+
+        Ok(mail_id)
+    }
+
+    /// Returns the ingested mails matching the given `filter`, newest first, along with the total
+    /// number of matching mails.
+    pub fn find_mails(
+        &self,
+        filter: &MailFilter,
+        limit: u32,
+        offset: u32,
+    ) -> Result<(Vec<MailRecord>, u64), Error> {
+        const CONDITIONS: &str = "(?1 IS NULL OR m.sender = ?1)
+            AND (?2 IS NULL OR COALESCE(m.date, m.received_at) >= ?2)
+            AND (?3 IS NULL OR COALESCE(m.date, m.received_at) < ?3)
+            AND (?4 IS NULL OR EXISTS (
+                SELECT 1 FROM mail_attachments a WHERE a.mail_id = m.id AND a.mime_type = ?4))
+            AND (?5 IS NULL OR instr(lower(m.subject), lower(?5)) > 0)
+            AND (NOT ?6 OR EXISTS (
+                SELECT 1 FROM mail_attachments a WHERE a.mail_id = m.id AND a.key IS NOT NULL))";
+
+        let total: i64 = self.conn.query_row(
+            &format!("SELECT COUNT(*) FROM mails m WHERE {CONDITIONS}"),
+            params![
+                filter.sender,
+                filter.since,
+                filter.until,
+                filter.mime_type,
+                filter.subject,
+                filter.stored
+            ],
+            |row| row.get(0),
+        )?;
+
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT m.id, m.message_id, m.date, m.received_at, m.sender, m.recipients, m.subject,
+                    m.outcome
+             FROM mails m WHERE {CONDITIONS}
+             ORDER BY COALESCE(m.date, m.received_at) DESC, m.id DESC
+             LIMIT ?7 OFFSET ?8"
+        ))?;
+        let rows = stmt.query_map(
+            params![
+                filter.sender,
+                filter.since,
+                filter.until,
+                filter.mime_type,
+                filter.subject,
+                filter.stored,
+                limit,
+                offset
+            ],
+            |row| {
+                let recipients: String = row.get(5)?;
+
+                Ok(MailRecord {
+                    id: row.get(0)?,
+                    message_id: row.get(1)?,
+                    date: row.get(2)?,
+                    received_at: row.get(3)?,
+                    sender: row.get(4)?,
+                    recipients: serde_json::from_str(&recipients).unwrap_or_default(),
+                    subject: row.get(6)?,
+                    outcome: outcome_column(row, 7)?,
+                    attachments: vec![],
+                })
+            },
+        )?;
+        let mut mails = rows.collect::<Result<Vec<_>, _>>()?;
+
+        let mut stmt = self.conn.prepare(
+            "SELECT a.key, a.filename, a.mime_type, a.size, a.outcome,
+                    (SELECT d.derived_key FROM derived_objects d
+                     WHERE d.key = a.key AND d.derived_key LIKE '%.thumbnail.jpg')
+             FROM mail_attachments a WHERE a.mail_id = ?1 ORDER BY a.id",
+        )?;
+
+        for mail in &mut mails {
+            let rows = stmt.query_map([mail.id], |row| {
+                let size: i64 = row.get(3)?;
+
+                Ok(AttachmentRecord {
+                    key: row.get(0)?,
+                    filename: row.get(1)?,
+                    mime_type: row.get(2)?,
+                    size: size.unsigned_abs(),
+                    outcome: outcome_column(row, 4)?,
+                    thumbnail_key: row.get(5)?,
+                })
+            })?;
+
+            mail.attachments = rows.collect::<Result<_, _>>()?;
+        }
+
+        Ok((mails, total.unsigned_abs()))
+    }
+
+    /// Records a pending delivery of the webhook event with the given `event_id` to `url` and
+    /// returns the id of the record.
+    pub fn add_delivery(&self, event_id: &str, event_type: &str, url: &str) -> Result<i64, Error> {
+        let now = now();
+
+        self.conn.execute(
+            "INSERT INTO webhook_deliveries
+                (event_id, event_type, url, status, attempts, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, 0, ?5, ?5)",
+            params![
+                event_id,
+                event_type,
+                url,
+                DeliveryStatus::Pending.as_str(),
+                now
+            ],
+        )?;
+
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    /// Updates the delivery with the given `id` after an attempt to deliver it.
+    ///
+    /// `response_status` is the HTTP status the endpoint responded with, if it responded.
+    pub fn update_delivery(
+        &self,
+        id: i64,
+        status: DeliveryStatus,
+        attempts: u32,
+        response_status: Option<u16>,
+        error: Option<&str>,
+    ) -> Result<(), Error> {
+        self.conn.execute(
+            "UPDATE webhook_deliveries
+             SET status = ?2, attempts = ?3, response_status = ?4, error = ?5, updated_at = ?6
+             WHERE id = ?1",
+            params![id, status.as_str(), attempts, response_status, error, now()],
+        )?;
+
+        Ok(())
+    }
+
+    /// Marks the pending deliveries as failed with the given `error` and returns their number.
+    ///
+    /// Events are only queued in memory, so this is done at startup for the deliveries that were
+    /// interrupted.
+    pub fn fail_pending_deliveries(&self, error: &str) -> Result<usize, Error> {
+        let count = self.conn.execute(
+            "UPDATE webhook_deliveries SET status = ?1, error = ?2, updated_at = ?3
+             WHERE status = ?4",
+            params![
+                DeliveryStatus::Failed.as_str(),
+                error,
+                now(),
+                DeliveryStatus::Pending.as_str()
+            ],
+        )?;
+
+        Ok(count)
+    }
+}
+
+/// Reads an [`Outcome`] from the column at `idx` of the given `row`.
+fn outcome_column(row: &Row<'_>, idx: usize) -> rusqlite::Result<Outcome> {
+    let value: String = row.get(idx)?;
+
+    value.parse().map_err(|err: String| {
+        rusqlite::Error::FromSqlConversionFailure(idx, Type::Text, err.into())
+    })
+}
+
+/// Returns the current time as a unix timestamp.
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |x| x.as_secs().try_into().unwrap_or(i64::MAX))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn creates_and_reopens_stores() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data/store.db");
+        let store = Store::open(&path).unwrap();
+
+        store.add_reference("a", None, None, None, None).unwrap();
+        drop(store);
+
+        let store = Store::open(&path).unwrap();
+
+        assert!(store.is_referenced("a").unwrap());
+    }
+
+    fn user_version(store: &Store) -> usize {
+        let version: i64 = store
+            .conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+
+        version.try_into().unwrap()
+    }
+
+    #[test]
+    fn migrates_existing_stores() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.db");
+        let conn = Connection::open(&path).unwrap();
+
+        // Stores created before migrations were tracked have the first schema at version 0.
+        conn.execute_batch(MIGRATIONS[0]).unwrap();
+        conn.execute(
+            "INSERT INTO attachment_refs (key, created_at) VALUES ('a', 1)",
+            [],
+        )
+        .unwrap();
+        drop(conn);
+
+        let mut store = Store::open(&path).unwrap();
+
+        assert_eq!(user_version(&store), MIGRATIONS.len());
+        assert!(store.is_referenced("a").unwrap());
+
+        store
+            .add_mail(
+                None,
+                None,
+                None,
+                &[],
+                None,
+                Outcome::Processed,
+                &[attachment(Some("a"), "image/png")],
+            )
+            .unwrap();
+        drop(store);
+
+        let store = Store::open(&path).unwrap();
+        let (mails, _) = store.find_mails(&MailFilter::default(), 1, 0).unwrap();
+
+        assert_eq!(user_version(&store), MIGRATIONS.len());
+        assert_eq!(mails[0].attachments[0].filename.as_deref(), Some("a.bin"));
+    }
+
+    #[test]
+    fn tracks_references() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(&dir.path().join("store.db")).unwrap();
+
+        store
+            .add_reference("a", Some("<1@x>"), Some("eve@x"), None, Some("hi"))
+            .unwrap();
+        store
+            .add_reference("a", Some("<2@x>"), Some("bob@x"), Some("ops@x"), None)
+            .unwrap();
+        store
+            .add_reference("b", Some("<2@x>"), Some("bob@x"), None, None)
+            .unwrap();
+
+        let references = store.references("a").unwrap();
+
+        assert_eq!(references.len(), 2);
+        assert_eq!(references[0].message_id.as_deref(), Some("<1@x>"));
+        assert_eq!(references[1].recipient.as_deref(), Some("ops@x"));
+        assert_eq!(store.find_keys(None, None).unwrap(), ["a", "b"]);
+        assert_eq!(store.find_keys(Some("<2@x>"), None).unwrap(), ["a", "b"]);
+        assert_eq!(store.find_keys(None, Some("eve@x")).unwrap(), ["a"]);
+        assert!(store
+            .find_keys(Some("<1@x>"), Some("bob@x"))
+            .unwrap()
+            .is_empty());
+
+        assert_eq!(
+            store.remove_references("a", None, Some("eve@x")).unwrap(),
+            1
+        );
+        assert_eq!(
+            store.remove_references("a", Some("<3@x>"), None).unwrap(),
+            1
+        );
+        assert_eq!(store.remove_references("a", None, None).unwrap(), 0);
+        assert!(!store.is_referenced("a").unwrap());
+        assert!(store.is_referenced("b").unwrap());
+    }
+
+    fn attachment(key: Option<&str>, mime_type: &str) -> AttachmentRecord {
+        AttachmentRecord {
+            key: key.map(String::from),
+            filename: Some("a.bin".to_string()),
+            mime_type: mime_type.to_string(),
+            size: 42,
+            outcome: if key.is_some() {
+                Outcome::Uploaded
+            } else {
+                Outcome::Skipped
+            },
+            thumbnail_key: None,
+        }
+    }
+
+    #[test]
+    fn records_and_finds_mails() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = Store::open(&dir.path().join("store.db")).unwrap();
+        let recipients = ["ops@x".to_string()];
+        let attachments = [
+            attachment(Some("a.png"), "image/png"),
+            attachment(None, "application/x-msdownload"),
+        ];
+
+        store.add_derived("a.png", "a.png.thumbnail.jpg").unwrap();
+        store
+            .add_mail(
+                Some("<1@x>"),
+                Some(100),
+                Some("eve@x"),
+                &recipients,
+                Some("Holiday Photos"),
+                Outcome::Processed,
+                &attachments,
+            )
+            .unwrap();
+        store
+            .add_mail(
+                Some("<2@x>"),
+                Some(200),
+                Some("bob@x"),
+                &[],
+                None,
+                Outcome::Skipped,
+                &[],
+            )
+            .unwrap();
+        store
+            .add_mail(
+                None,
+                Some(300),
+                Some("eve@x"),
+                &[],
+                Some("Re: photos"),
+                Outcome::Failed,
+                &[],
+            )
+            .unwrap();
+
+        let find = |filter: MailFilter| {
+            let (mails, total) = store.find_mails(&filter, 10, 0).unwrap();
+            let ids: Vec<_> = mails.into_iter().filter_map(|x| x.message_id).collect();
+
+            (ids, total)
+        };
+
+        let (mails, total) = store.find_mails(&MailFilter::default(), 2, 0).unwrap();
+
+        assert_eq!(total, 3);
+        assert_eq!(mails.len(), 2);
+        assert_eq!(mails[0].date, Some(300));
+        assert_eq!(mails[1].message_id.as_deref(), Some("<2@x>"));
+
+        let (mails, _) = store.find_mails(&MailFilter::default(), 2, 2).unwrap();
+        let mail = &mails[0];
+
+        assert_eq!(mail.recipients, recipients);
+        assert_eq!(mail.outcome, Outcome::Processed);
+        assert_eq!(mail.attachments.len(), 2);
+        assert_eq!(mail.attachments[0].key.as_deref(), Some("a.png"));
+        assert_eq!(
+            mail.attachments[0].thumbnail_key.as_deref(),
+            Some("a.png.thumbnail.jpg")
+        );
+        assert_eq!(mail.attachments[1].outcome, Outcome::Skipped);
+
+        let sender = MailFilter {
+            sender: Some("bob@x".to_string()),
+            ..MailFilter::default()
+        };
+        let dates = MailFilter {
+            since: Some(100),
+            until: Some(300),
+            ..MailFilter::default()
+        };
+        let mime_type = MailFilter {
+            mime_type: Some("image/png".to_string()),
+            ..MailFilter::default()
+        };
+        let subject = MailFilter {
+            subject: Some("PHOTOS".to_string()),
+            ..MailFilter::default()
+        };
+        let stored = MailFilter {
+            stored: true,
+            ..MailFilter::default()
+        };
+
+        assert_eq!(find(sender), (vec!["<2@x>".to_string()], 1));
+        assert_eq!(find(dates).1, 2);
+        assert_eq!(find(mime_type), (vec!["<1@x>".to_string()], 1));
+        assert_eq!(find(subject).1, 2);
+        assert_eq!(find(stored), (vec!["<1@x>".to_string()], 1));
+    }
+
+    #[test]
+    fn tracks_derived_objects() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = Store::open(&dir.path().join("store.db")).unwrap();
+
+        store.add_derived("a", "a.thumbnail.jpg").unwrap();
+        store.add_derived("a", "a.thumbnail.jpg").unwrap();
+        store.add_derived("a", "a.poster.jpg").unwrap();
+
+        assert_eq!(
+            store.derived_keys("a").unwrap(),
+            ["a.poster.jpg", "a.thumbnail.jpg"]
+        );
+
+        store.remove_attachment("a").unwrap();
+
+        assert!(store.derived_keys("a").unwrap().is_empty());
+    }
+
+    #[test]
+    fn marks_taken_down_attachments() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = Store::open(&dir.path().join("store.db")).unwrap();
+        let attachments = [
+            attachment(Some("a.png"), "image/png"),
+            attachment(Some("b.png"), "image/png"),
+        ];
+
+        store.add_derived("a.png", "a.png.thumbnail.jpg").unwrap();
+        store
+            .add_mail(
+                None,
+                None,
+                None,
+                &[],
+                None,
+                Outcome::Processed,
+                &attachments,
+            )
+            .unwrap();
+        store.remove_attachment("a.png").unwrap();
+
+        let (mails, _) = store.find_mails(&MailFilter::default(), 1, 0).unwrap();
+        let attachments = &mails[0].attachments;
+
+        assert_eq!(attachments[0].key, None);
+        assert_eq!(attachments[0].thumbnail_key, None);
+        assert_eq!(attachments[0].outcome, Outcome::TakenDown);
+        assert_eq!(attachments[1].key.as_deref(), Some("b.png"));
+        assert_eq!(attachments[1].outcome, Outcome::Uploaded);
+    }
+
+    fn delivery_status(store: &Store, id: i64) -> (String, u32, Option<String>) {
+        store
+            .conn
+            .query_row(
+                "SELECT status, attempts, error FROM webhook_deliveries WHERE id = ?1",
+                [id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn records_deliveries() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(&dir.path().join("store.db")).unwrap();
+        let delivered = store
+            .add_delivery("a", "attachment.ingested", "http://a/")
+            .unwrap();
+        let pending = store
+            .add_delivery("b", "attachment.ingested", "http://a/")
+            .unwrap();
+
+        store
+            .update_delivery(delivered, DeliveryStatus::Delivered, 1, Some(200), None)
+            .unwrap();
+        store
+            .update_delivery(pending, DeliveryStatus::Pending, 1, Some(503), Some("busy"))
+            .unwrap();
+
+        assert_eq!(store.fail_pending_deliveries("interrupted").unwrap(), 1);
+        assert_eq!(
+            delivery_status(&store, delivered),
+            ("delivered".to_string(), 1, None)
+        );
+        assert_eq!(
+            delivery_status(&store, pending),
+            ("failed".to_string(), 1, Some("interrupted".to_string()))
+        );
+        assert_eq!(store.fail_pending_deliveries("interrupted").unwrap(), 0);
+    }
+}
@@ -0,0 +1,428 @@
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, Mac};
+use reqwest::header::CONTENT_TYPE;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{debug, error, instrument, warn};
+
+use crate::{
+    config::{AwsS3Config, WebhookConfig},
+    handler::MailInfo,
+    http_client::HttpClient,
+    report::AttachmentReport,
+    store::{self, DeliveryStatus, Store},
+    Error,
+};
+
+/// The version of the event schema, which is bumped on incompatible changes.
+pub const EVENT_VERSION: u32 = 1;
+
+/// The header the signature of a payload is sent in, as `sha256=<hex>`.
+pub const SIGNATURE_HEADER: &str = "X-Meta-Signature-256";
+
+/// The header the type of an event is sent in.
+pub const EVENT_HEADER: &str = "X-Meta-Event";
+
+/// The header the id of an event is sent in.
+pub const DELIVERY_HEADER: &str = "X-Meta-Delivery";
+
+/// The delay before the first retry of a failed delivery, which doubles with every retry.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The maximum delay between two attempts to deliver an event.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// The maximum number of events queued for delivery to an endpoint, beyond which new events
+/// are dropped.
+const QUEUE_CAPACITY: usize = 1024;
+
+/// The mail an event is about.
+#[derive(Debug, Clone, Serialize)]
+pub struct EventMail {
+    /// The Message-ID of the mail, if any.
+    pub message_id: Option<String>,
+    /// The sender of the mail, if known.
+    pub sender: Option<String>,
+    /// The intended recipient of the mail, if known.
+    pub recipient: Option<String>,
+    /// The subject of the mail, if any.
+    pub subject: Option<String>,
+}
+
+/// An event posted to the configured webhooks.
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    /// The version of the event schema.
+    pub version: u32,
+    /// The unique id of the event, for deduplication by receivers.
+    pub id: String,
+    /// The type of the event.
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    /// The time the event was created, as a unix timestamp.
+    pub created_at: i64,
+    /// The mail the event is about.
+    pub mail: EventMail,
+    /// The report of the attachment the event is about.
+    pub attachment: AttachmentReport,
+    /// The public URL of the attachment, if it was stored.
+    pub url: Option<String>,
+    /// The public URL of the thumbnail of the attachment, if any.
+    pub thumbnail_url: Option<String>,
+}
+
+impl Event {
+    /// Returns the event for an ingested attachment, whatever the outcome of its processing.
+    pub fn attachment(
+        report: &AttachmentReport,
+        info: &MailInfo<'_>,
+        s3_config: &AwsS3Config,
+    ) -> Self {
+        let object_url = |key: &Option<String>| {
+            key.as_deref()
+                .and_then(|x| s3_config.object_url(x))
+                .map(String::from)
+        };
+
+        Event {
+            version: EVENT_VERSION,
+            id: event_id(info.message_id, report.record.key.as_deref()),
+            kind: "attachment.ingested",
+            created_at: store::now(),
+            mail: EventMail {
+                message_id: info.message_id.map(String::from),
+                sender: info.sender.map(String::from),
+                recipient: info.recipient.map(String::from),
+                subject: info.subject.map(String::from),
+            },
+            attachment: report.clone(),
+            url: object_url(&report.record.key),
+            thumbnail_url: object_url(&report.record.thumbnail_key),
+        }
+    }
+}
+
+/// Delivers events to the configured webhooks in the background.
+///
+/// Each endpoint has its own bounded queue, so a slow or failing endpoint doesn't hold up the
+/// others or the ingestion of mails. The queues are kept in memory only.
+#[derive(Debug, Clone, Default)]
+pub struct Webhooks {
+    queues: Vec<mpsc::Sender<Arc<Event>>>,
+}
+
+impl Webhooks {
+    /// Starts a delivery task for each of the webhooks in `configs`, which send their requests
+    /// with `http` and record the status of their deliveries in the store at `store_path`.
+    ///
+    /// The deliveries left pending by a previous run are marked as failed, as their events
+    /// were lost with its queues.
+    pub fn start(
+        configs: &[WebhookConfig],
+        store_path: &Path,
+        http: &HttpClient,
+    ) -> Result<Self, Error> {
+        let interrupted =
+            Store::open(store_path)?.fail_pending_deliveries("interrupted by a restart")?;
+
+        if interrupted > 0 {
+            warn!(%interrupted, "marked interrupted webhook deliveries as failed");
+        }
+
+        let mut queues = vec![];
+
+        for config in configs {
+            let endpoint = Endpoint {
+                config: config.clone(),
+                http: http.clone(),
+                store: Store::open(store_path)?,
+            };
+            let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+
+            tokio::spawn(endpoint.run(receiver));
+            queues.push(sender);
+        }
+
+        Ok(Webhooks { queues })
+    }
+
+    /// Queues the `event` for delivery to every webhook.
+    pub fn send(&self, event: Event) {
+        let event = Arc::new(event);
+
+        for queue in &self.queues {
+            match queue.try_send(Arc::clone(&event)) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    error!(id = %event.id, "dropping webhook event as its delivery queue is full");
+                }
+                Err(TrySendError::Closed(_)) => {
+                    error!(id = %event.id, "could not queue webhook event as its delivery task stopped");
+                }
+            }
+        }
+    }
+}
+
+/// A webhook endpoint with its delivery state.
+struct Endpoint {
+    config: WebhookConfig,
+    http: HttpClient,
+    store: Store,
+}
+
+impl Endpoint {
+    /// Delivers the events received through `receiver` one after another.
+    async fn run(mut self, mut receiver: mpsc::Receiver<Arc<Event>>) {
+        while let Some(event) = receiver.recv().await {
+            self.deliver(&event).await;
+        }
+    }
+
+    /// Posts the `event` to the endpoint, retrying with exponential backoff on network errors,
+    /// server errors and rate limiting, and records the status of the delivery.
+    #[instrument(skip_all, fields(url = %self.config.url, id = %event.id))]
+    async fn deliver(&mut self, event: &Event) {
+        let body = match serde_json::to_vec(event) {
+            Ok(body) => body,
+            Err(err) => {
+                error!(%err, "could not serialize webhook event");
+
+                return;
+            }
+        };
+        let signature = sign(&self.config.secret, &body);
+        let delivery = self
+            .store
+            .add_delivery(&event.id, event.kind, self.config.url.as_str())
+            .inspect_err(|err| error!(%err, "could not record webhook delivery"))
+            .ok();
+        let mut backoff = INITIAL_BACKOFF;
+
+        for attempt in 1..=self.config.max_attempts.max(1) {
+            // Failures are retried here rather than by the client, so every attempt is recorded.
+            let result = self
+                .http
+                .client()
+                .post(self.config.url.clone())
+                .timeout(Duration::from_secs(self.config.timeout))
+                .header(CONTENT_TYPE, "application/json")
+                .header(EVENT_HEADER, event.kind)
+                .header(DELIVERY_HEADER, &event.id)
+                .header(SIGNATURE_HEADER, &signature)
+                .body(body.clone())
+                .send()
+                .await;
+
+            let (response_status, err, retry) = match result {
+                Ok(res) if res.status().is_success() => {
+                    debug!(status = %res.status(), %attempt, "delivered webhook event");
+
+                    self.record(
+                        delivery,
+                        DeliveryStatus::Delivered,
+                        attempt,
+                        Some(res.status().as_u16()),
+                        None,
+                    );
+
+                    return;
+                }
+                Ok(res) => {
+                    let status = res.status();
+
+                    (
+                        Some(status.as_u16()),
+                        format!("the endpoint responded with {status}"),
+                        status.is_server_error() || status.as_u16() == 429,
+                    )
+                }
+                Err(err) => (None, err.to_string(), true),
+            };
+
+            if !retry || attempt >= self.config.max_attempts {
+                error!(%err, %attempt, "could not deliver webhook event");
+
+                self.record(
+                    delivery,
+                    DeliveryStatus::Failed,
+                    attempt,
+                    response_status,
+                    Some(&err),
+                );
+
+                return;
+            }
+
+            warn!(%err, %attempt, ?backoff, "could not deliver webhook event, retrying");
+
+            self.record(
+                delivery,
+                DeliveryStatus::Pending,
+                attempt,
+                response_status,
+                Some(&err),
+            );
+
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    /// Updates the record of the `delivery`, if it was recorded.
+    fn record(
+        &self,
+        delivery: Option<i64>,
+        status: DeliveryStatus,
+        attempts: u32,
+        response_status: Option<u16>,
+        err: Option<&str>,
+    ) {
+        let Some(id) = delivery else {
+            return;
+        };
+
+        if let Err(err) = self
+            .store
+            .update_delivery(id, status, attempts, response_status, err)
+        {
+            error!(%err, "could not record webhook delivery");
+        }
+    }
+}
+
+/// Returns the signature of the payload `body` with the given `secret`, as `sha256=<hex>`.
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+
+    mac.update(body);
+
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Returns a unique id for an event about the attachment with the given `key` of the mail with
+/// the given `message_id`.
+fn event_id(message_id: Option<&str>, key: Option<&str>) -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |x| x.as_nanos());
+    let mut hasher = Sha256::new();
+
+    hasher.update(nanos.to_le_bytes());
+    hasher.update(COUNTER.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+    hasher.update(message_id.unwrap_or_default());
+    hasher.update([0]);
+    hasher.update(key.unwrap_or_default());
+
+    hex::encode(&hasher.finalize()[..16])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use axum::{body::Bytes, http::HeaderMap, http::StatusCode, routing::post, Router};
+    use reqwest::Url;
+    use tokio::net::TcpListener;
+
+    use crate::{config::HttpConfig, mime::Detection};
+
+    use super::*;
+
+    /// Starts an endpoint that fails the first `failures` requests, and returns its URL along
+    /// with the headers and bodies of the requests it receives.
+    async fn endpoint(failures: usize) -> (Url, mpsc::UnboundedReceiver<(HeaderMap, Bytes)>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let app = Router::new().route(
+            "/hook",
+            post(move |headers: HeaderMap, body: Bytes| async move {
+                sender.send((headers, body)).unwrap();
+
+                if requests.fetch_add(1, Ordering::Relaxed) < failures {
+                    StatusCode::INTERNAL_SERVER_ERROR
+                } else {
+                    StatusCode::NO_CONTENT
+                }
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        (url.parse().unwrap(), receiver)
+    }
+
+    fn event() -> Event {
+        let detection = Detection {
+            mime_type: "text/plain",
+            sniffed: "text/plain",
+            declared: None,
+            mismatch: false,
+        };
+        let report = AttachmentReport::new(Some("a.txt"), &detection, 1);
+        let s3_config = AwsS3Config {
+            bucket_name: "bucket".to_string(),
+            public_url: None,
+        };
+
+        Event::attachment(&report, &MailInfo::default(), &s3_config)
+    }
+
+    #[test]
+    fn signs_payloads() {
+        // RFC 4231, test case 2.
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn generates_unique_event_ids() {
+        let first = event_id(Some("<a@example.org>"), Some("key"));
+        let second = event_id(Some("<a@example.org>"), Some("key"));
+
+        assert_eq!(first.len(), 32);
+        assert_ne!(first, second);
+    }
+
+    #[tokio::test]
+    async fn delivers_signed_events_and_retries_server_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let (url, mut requests) = endpoint(1).await;
+        let config = WebhookConfig {
+            url,
+            secret: "secret".to_string(),
+            max_attempts: 2,
+            timeout: 5,
+        };
+        let http = HttpClient::new(&HttpConfig::default()).unwrap();
+        let webhooks = Webhooks::start(&[config], &dir.path().join("store.db"), &http).unwrap();
+        let event = event();
+        let id = event.id.clone();
+
+        webhooks.send(event);
+
+        for _ in 0..2 {
+            let (headers, body) = requests.recv().await.unwrap();
+
+            assert_eq!(headers[DELIVERY_HEADER], id.as_str());
+            assert_eq!(headers[EVENT_HEADER], "attachment.ingested");
+            assert_eq!(headers[SIGNATURE_HEADER], sign("secret", &body).as_str());
+        }
+    }
+}